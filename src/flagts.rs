@@ -2,29 +2,742 @@
 // Licensed under the MIT License.
 
 //! Generate a time series of flagging percentages for a data set.
+//!
+//! By default, all of the samples in each timeslot are lumped together. The
+//! `--by` option breaks the statistics down by antenna, baseline, spectral
+//! window, polarization, field, and/or scan, in which case we print the
+//! flagged fraction of each group in its own column. The `--spectrum` option
+//! instead collapses along the time axis, yielding the flagging statistics of
+//! each channel of each spectral window.
+//!
+//! Samples in rows flagged with FLAG_ROW are counted as flagged. With
+//! `--weighted`, we also report the flagged fraction of the total weight, so
+//! that one can see how much sensitivity has been lost.
+//!
+//! With `--waterfall`, we write out time-frequency images of the flagged
+//! fraction for each baseline or antenna, which are the best way to diagnose
+//! intermittent RFI.
+//!
+//! With `--compare`, we instead report how the flags differ from those of a
+//! reference data set, such as a copy made before running an automated
+//! flagger.
+//!
+//! The `--flag-version` and `--all-flag-versions` options analyze the flags
+//! saved by CASA's `flagmanager` in `<ms>.flagversions` rather than the main
+//! FLAG column, so that the history of flagging decisions can be audited.
+//! When several versions are analyzed, the results for each are written to a
+//! separate file.
+//!
+//! With `--fail-above`, the tool can act as a QA gate: if the flagged fraction
+//! of the data set, or of any group, exceeds the threshold, the offending
+//! groups are reported and the exit code is 2.
+//!
+//! The time series is computed by reading only the needed columns, in chunks
+//! of rows that are reduced by several worker threads (see `--threads`). Each
+//! worker handles its own subset of timeslots, so the results don't depend on
+//! the number of threads. The array cells themselves are read one row at a
+//! time by the main thread: rubbl_casatables has no bulk reader for array
+//! columns, and casacore shares one open table among all of the handles to
+//! it in a process, so the workers can't safely read rows of their own.
+//!
+//! Results are printed as whitespace-separated text by default, but the
+//! `--format` option can select CSV, JSON, or NPY output. JSON output includes
+//! metadata describing the columns; for CSV and NPY files, the metadata are
+//! written to a JSON file alongside them.
 
-use clap::{value_parser, Arg, ArgMatches, Command};
+use crate::{
+    msutil::{
+        flag_versions_dir, list_flag_versions, load_data_desc_info, open_table, time_reference,
+        DataDescInfo,
+    },
+    npy::ndarray_to_npy_stream,
+    output::{Column, OutputFormat, ResultTable, Value},
+    spwglue::VisRecordIdentity,
+    MiscellaneousError,
+};
+use clap::{value_parser, Arg, ArgAction, ArgMatches, Command};
 use itertools::Itertools;
-use ndarray::Ix2;
-use pbr;
-use rubbl_casatables::{Table, TableOpenMode};
+use ndarray::{ArrayView, Axis, Dimension, Ix1, Ix2, Zip};
+use rubbl_casatables::Table;
 use rubbl_core::{
     anyhow::{self, Result},
     ctry,
     notify::NotificationBackend,
-    Array,
+    rn_note, rn_severe, rn_warning, Array,
 };
 use std::{
     self,
-    collections::HashMap,
-    f64, io, mem,
+    collections::{BTreeSet, HashMap, HashSet},
+    f64,
+    fmt::{self, Display},
+    io::{self, Write},
+    ops::AddAssign,
     path::{Path, PathBuf},
+    str::FromStr,
+    sync::mpsc,
+    thread,
 };
 
+/// Which axes of the data we break the flagging statistics down by.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+struct Grouping {
+    antenna: bool,
+    baseline: bool,
+    spw: bool,
+    pol: bool,
+    field: bool,
+    scan: bool,
+}
+
+impl Grouping {
+    fn from_names<'a, I: IntoIterator<Item = &'a String>>(names: I) -> Result<Self> {
+        let mut g = Grouping::default();
+
+        for name in names {
+            match name.as_str() {
+                "antenna" => g.antenna = true,
+                "baseline" => g.baseline = true,
+                "spw" => g.spw = true,
+                "pol" => g.pol = true,
+                "field" => g.field = true,
+                "scan" => g.scan = true,
+                other => {
+                    return err_msg!("unrecognized grouping \"{}\"", other);
+                }
+            }
+        }
+
+        Ok(g)
+    }
+
+    fn is_grouped(&self) -> bool {
+        *self != Grouping::default()
+    }
+}
+
+/// The identity of one group of samples. Fields that aren't part of the
+/// active grouping are left as `None`.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, Ord, PartialEq, PartialOrd)]
+struct GroupKey {
+    antenna: Option<i32>,
+    baseline: Option<(i32, i32)>,
+    spw: Option<i32>,
+    pol: Option<i32>,
+    field: Option<i32>,
+    scan: Option<i32>,
+}
+
+impl Display for GroupKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut pieces = Vec::new();
+
+        if let Some(a) = self.antenna {
+            pieces.push(format!("ant={}", a));
+        }
+
+        if let Some((a1, a2)) = self.baseline {
+            pieces.push(format!("bl={}-{}", a1, a2));
+        }
+
+        if let Some(s) = self.spw {
+            pieces.push(format!("spw={}", s));
+        }
+
+        if let Some(p) = self.pol {
+            pieces.push(format!("pol={}", stokes_name(p)));
+        }
+
+        if let Some(fi) = self.field {
+            pieces.push(format!("field={}", fi));
+        }
+
+        if let Some(s) = self.scan {
+            pieces.push(format!("scan={}", s));
+        }
+
+        if pieces.is_empty() {
+            write!(f, "all")
+        } else {
+            write!(f, "{}", pieces.join(","))
+        }
+    }
+}
+
+/// Get a name for a CASA `Stokes::StokesTypes` code, as found in the
+/// POLARIZATION:CORR_TYPE column.
+fn stokes_name(code: i32) -> String {
+    match code {
+        1 => "I".to_owned(),
+        2 => "Q".to_owned(),
+        3 => "U".to_owned(),
+        4 => "V".to_owned(),
+        5 => "RR".to_owned(),
+        6 => "RL".to_owned(),
+        7 => "LR".to_owned(),
+        8 => "LL".to_owned(),
+        9 => "XX".to_owned(),
+        10 => "XY".to_owned(),
+        11 => "YX".to_owned(),
+        12 => "YY".to_owned(),
+        other => format!("corr{}", other),
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+struct FlagCounts {
+    n_total: usize,
+    n_flagged: usize,
+    w_total: f64,
+    w_flagged: f64,
+}
+
+impl FlagCounts {
+    fn accumulate<D: Dimension>(
+        &mut self,
+        flags: ArrayView<bool, D>,
+        weights: Option<ArrayView<f32, D>>,
+    ) {
+        self.n_total += flags.len();
+        self.n_flagged += flags.iter().filter(|f| **f).count();
+
+        if let Some(weights) = weights {
+            Zip::from(&flags).and(&weights).for_each(|f, w| {
+                self.w_total += *w as f64;
+
+                if *f {
+                    self.w_flagged += *w as f64;
+                }
+            });
+        }
+    }
+
+    fn fraction(&self, weighted: bool) -> f64 {
+        if weighted {
+            if self.w_total == 0. {
+                f64::NAN
+            } else {
+                self.w_flagged / self.w_total
+            }
+        } else if self.n_total == 0 {
+            f64::NAN
+        } else {
+            self.n_flagged as f64 / self.n_total as f64
+        }
+    }
+}
+
+impl AddAssign<&FlagCounts> for FlagCounts {
+    fn add_assign(&mut self, other: &FlagCounts) {
+        self.n_total += other.n_total;
+        self.n_flagged += other.n_flagged;
+        self.w_total += other.w_total;
+        self.w_flagged += other.w_flagged;
+    }
+}
+
+/// The exit code returned if `--fail-above` is used and a flagged fraction
+/// exceeds the threshold.
+const FAIL_ABOVE_EXIT_CODE: i32 = 2;
+
+/// How we get the weights associated with each sample.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum WeightSource {
+    /// Use WEIGHT_SPECTRUM, falling back to WEIGHT for rows where it isn't
+    /// defined.
+    Spectrum,
+
+    /// Use WEIGHT, broadcast across all channels.
+    Broadcast,
+}
+
+type MaybeWeights = Option<Array<f32, Ix2>>;
+
+/// Helper for reading the flags, and possibly weights, of each row.
+struct SampleReader {
+    /// The FLAG_ROW column, if the table has one. Scalar columns are cheap
+    /// to read in bulk, so we do that up front.
+    flag_row: Option<Vec<bool>>,
+
+    weights: Option<WeightSource>,
+
+    /// If set, flags are read from this CASA flag version table rather than
+    /// the main table.
+    version: Option<FlagVersion>,
+}
+
+/// A table of flags saved by CASA's `flagmanager`. These live in
+/// `<ms>.flagversions/flags.<name>` and have FLAG and FLAG_ROW columns whose
+/// rows line up with those of the main table.
+struct FlagVersion {
+    table: Table,
+    flag_row: Option<Vec<bool>>,
+}
+
+/// The undecoded array cells of one row, as read from the table by
+/// `SampleReader::read_raw`. In rubbl_casatables 0.9, `Table::get_cell`
+/// rejects array cells because it checks the requested type against the
+/// cell's element type, and `Table::get_cell_as_vec` discards their shapes,
+/// so these are flattened; `decode` reconstructs the shapes from the
+/// DATA_DESCRIPTION metadata.
+struct RawSample {
+    flag: Vec<bool>,
+    flag_row: bool,
+
+    /// If weighted, the WEIGHT_SPECTRUM and WEIGHT cells, if defined.
+    weights: Option<CellWeights<Vec<f32>, Vec<f32>>>,
+}
+
+/// The WEIGHT_SPECTRUM and WEIGHT cells of a row, either of which may be
+/// undefined.
+type CellWeights<S, W> = (Option<S>, Option<W>);
+
+impl RawSample {
+    /// Decode the sample, given the (n_chan, n_pol) shape implied by the
+    /// row's DATA_DESC_ID. The shapes of the cells are checked, so a
+    /// mismatch with the metadata is an error rather than garbage.
+    fn decode(self, shape: (usize, usize)) -> Result<(Array<bool, Ix2>, MaybeWeights)> {
+        let n_expected = shape.0 * shape.1;
+
+        if self.flag.len() != n_expected {
+            return err_msg!(
+                "FLAG cell has {} elements, but its DATA_DESC_ID implies shape {:?}",
+                self.flag.len(),
+                shape
+            );
+        }
+
+        let flag = Array::from_shape_vec(shape, self.flag).unwrap();
+        let weights = self.weights.map(|(spec, w)| {
+            (
+                spec.filter(|s| s.len() == n_expected)
+                    .map(|s| Array::from_shape_vec(shape, s).unwrap()),
+                w.map(Array::from),
+            )
+        });
+
+        Ok(finish_sample(flag, self.flag_row, weights)?)
+    }
+}
+
+/// Apply FLAG_ROW to the flags of a row, and, if weighting, work out the
+/// weight of each sample, either from WEIGHT_SPECTRUM (if it's defined and
+/// has the right shape) or by broadcasting WEIGHT.
+fn finish_sample(
+    mut flag: Array<bool, Ix2>,
+    flag_row: bool,
+    weights: Option<CellWeights<Array<f32, Ix2>, Array<f32, Ix1>>>,
+) -> Result<(Array<bool, Ix2>, MaybeWeights), MiscellaneousError> {
+    if flag_row {
+        flag.fill(true);
+    }
+
+    let weights = match weights {
+        None => None,
+        Some((Some(s), _)) if s.shape() == flag.shape() => Some(s),
+
+        Some((_, Some(w))) => match w.broadcast(flag.raw_dim()) {
+            Some(b) => Some(b.to_owned()),
+            None => {
+                return err_msg!(
+                    "WEIGHT has {} elements but FLAG has shape {:?}",
+                    w.len(),
+                    flag.shape()
+                );
+            }
+        },
+
+        Some((_, None)) => {
+            return err_msg!("row has neither a usable WEIGHT_SPECTRUM nor a WEIGHT");
+        }
+    };
+
+    Ok((flag, weights))
+}
+
+/// Read the FLAG_ROW column of a table, if it has one.
+fn load_flag_row(table: &mut Table, col_names: &[String]) -> Result<Option<Vec<bool>>> {
+    if col_names.iter().any(|n| n == "FLAG_ROW") {
+        Ok(Some(table.get_col_as_vec::<bool>("FLAG_ROW")?))
+    } else {
+        Ok(None)
+    }
+}
+
+impl SampleReader {
+    fn new(table: &mut Table, path: &Path, weighted: bool) -> Result<Self> {
+        let col_names = ctry!(
+            table.column_names();
+            "failed to get names of columns in \"{}\"", path.display()
+        );
+        let have_col = |name: &str| col_names.iter().any(|n| n == name);
+
+        let weights = if !weighted {
+            None
+        } else if have_col("WEIGHT_SPECTRUM") {
+            Some(WeightSource::Spectrum)
+        } else if have_col("WEIGHT") {
+            Some(WeightSource::Broadcast)
+        } else {
+            return err_msg!(
+                "weighted statistics requested, but \"{}\" has neither \
+                 WEIGHT_SPECTRUM nor WEIGHT columns",
+                path.display()
+            );
+        };
+
+        let flag_row = ctry!(
+            load_flag_row(table, &col_names);
+            "failed to read the FLAG_ROW column of \"{}\"", path.display()
+        );
+
+        Ok(SampleReader {
+            flag_row,
+            weights,
+            version: None,
+        })
+    }
+
+    /// Read flags from the named CASA flag version of the data set at
+    /// `inpath` rather than its main FLAG column.
+    fn use_flag_version(&mut self, inpath: &Path, n_rows: u64, name: &str) -> Result<()> {
+        let (path, mut table) =
+            open_table(&flag_versions_dir(inpath), &format!("flags.{}", name), true)?;
+
+        if table.n_rows() != n_rows {
+            return err_msg!(
+                "flag version table \"{}\" has {} rows, but the main table has {}",
+                path.display(),
+                table.n_rows(),
+                n_rows
+            );
+        }
+
+        let col_names = ctry!(
+            table.column_names();
+            "failed to get names of columns in \"{}\"", path.display()
+        );
+        let flag_row = ctry!(
+            load_flag_row(&mut table, &col_names);
+            "failed to read the FLAG_ROW column of \"{}\"", path.display()
+        );
+
+        self.version = Some(FlagVersion { table, flag_row });
+        Ok(())
+    }
+
+    /// Whether the row is flagged with FLAG_ROW, in the main table or the
+    /// flag version, as appropriate.
+    fn is_row_flagged(&self, row_num: u64) -> bool {
+        let flag_row = match self.version {
+            None => &self.flag_row,
+            Some(ref v) => &v.flag_row,
+        };
+
+        flag_row.as_ref().is_some_and(|f| f[row_num as usize])
+    }
+
+    /// Read the undecoded cells of a row directly from the table, without
+    /// touching any of its other columns, which can be a big win for large
+    /// data sets.
+    fn read_raw(&mut self, table: &mut Table, row_num: u64) -> Result<RawSample> {
+        let flag = match self.version {
+            None => table.get_cell_as_vec::<bool>("FLAG", row_num)?,
+            Some(ref mut v) => v.table.get_cell_as_vec::<bool>("FLAG", row_num)?,
+        };
+
+        // WEIGHT_SPECTRUM is often present but without any contents, in
+        // which case reading it fails and we fall back to WEIGHT.
+
+        let weights = match self.weights {
+            None => None,
+            Some(WeightSource::Spectrum) => Some((
+                table.get_cell_as_vec("WEIGHT_SPECTRUM", row_num).ok(),
+                table.get_cell_as_vec("WEIGHT", row_num).ok(),
+            )),
+            Some(WeightSource::Broadcast) => {
+                Some((None, Some(table.get_cell_as_vec("WEIGHT", row_num)?)))
+            }
+        };
+
+        Ok(RawSample {
+            flag,
+            flag_row: self.is_row_flagged(row_num),
+            weights,
+        })
+    }
+}
+
+/// How to express the times in the time series output.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum TimeFormat {
+    /// Seconds since MJD 0.0, as stored in CASA data sets.
+    Casa,
+
+    /// Modified Julian Date, in days.
+    Mjd,
+
+    /// ISO 8601 date-time text, with millisecond precision.
+    Iso,
+
+    /// Seconds since 1970 January 1.
+    Unix,
+
+    /// Seconds since the first timeslot.
+    Relative,
+}
+
+/// The MJD of the Unix epoch.
+const UNIX_EPOCH_MJD: f64 = 40587.;
+
+const SECONDS_PER_DAY: f64 = 86400.;
+
+impl FromStr for TimeFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "casa" => Ok(TimeFormat::Casa),
+            "mjd" => Ok(TimeFormat::Mjd),
+            "iso" => Ok(TimeFormat::Iso),
+            "unix" => Ok(TimeFormat::Unix),
+            "relative" => Ok(TimeFormat::Relative),
+            other => err_msg!("unrecognized time format \"{}\"", other),
+        }
+    }
+}
+
+impl TimeFormat {
+    /// Express `time`, in CASA seconds, in this format. `t0` is the time of
+    /// the first timeslot.
+    fn format(&self, time: f64, t0: f64) -> Value {
+        match self {
+            TimeFormat::Casa => Value::Float(time),
+            TimeFormat::Mjd => Value::Float(time / SECONDS_PER_DAY),
+            TimeFormat::Iso => Value::Text(casa_time_to_iso(time)),
+            TimeFormat::Unix => Value::Float(time - UNIX_EPOCH_MJD * SECONDS_PER_DAY),
+            TimeFormat::Relative => Value::Float(time - t0),
+        }
+    }
+
+    fn description(&self, time_reference: &str) -> String {
+        match self {
+            TimeFormat::Casa => format!("timeslot, in {}", time_reference),
+            TimeFormat::Mjd => "timeslot, as MJD".to_owned(),
+            TimeFormat::Iso => "timeslot, as ISO 8601 text".to_owned(),
+            TimeFormat::Unix => "timeslot, in seconds since 1970-01-01T00:00:00".to_owned(),
+            TimeFormat::Relative => {
+                "timeslot, in seconds relative to the first timeslot".to_owned()
+            }
+        }
+    }
+}
+
+/// Convert a CASA time (seconds since MJD 0.0) to ISO 8601 format. This
+/// ignores leap seconds, just as the MJD representation does.
+fn casa_time_to_iso(time: f64) -> String {
+    let total_ms = (time * 1000.).round() as i64;
+    let ms_per_day = SECONDS_PER_DAY as i64 * 1000;
+    let days = total_ms.div_euclid(ms_per_day) - UNIX_EPOCH_MJD as i64;
+    let ms_of_day = total_ms.rem_euclid(ms_per_day);
+
+    // Civil-from-days algorithm of H. Hinnant; `days` is relative to
+    // 1970-01-01.
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}",
+        year,
+        month,
+        day,
+        ms_of_day / 3_600_000,
+        (ms_of_day / 60_000) % 60,
+        (ms_of_day / 1000) % 60,
+        ms_of_day % 1000
+    )
+}
+
 pub fn make_command() -> Command {
     Command::new("flagts")
         .bin_name("rubbl rxpackage flagts")
         .about("Print a time series of flagging fractions")
+        .arg(
+            Arg::new("by")
+                .long("by")
+                .long_help(
+                    "Break down the flagging statistics by the specified axis. \
+                     May be repeated, or given as a comma-separated list, to \
+                     group by several axes at once. Each group is printed as \
+                     its own column of flagged fractions.",
+                )
+                .value_name("GROUPING")
+                .value_parser(["antenna", "baseline", "spw", "pol", "field", "scan"])
+                .value_delimiter(',')
+                .action(ArgAction::Append),
+        )
+        .arg(
+            Arg::new("spectrum")
+                .long("spectrum")
+                .action(ArgAction::SetTrue)
+                .conflicts_with("by")
+                .help(
+                    "Instead of a time series, print the flagged fraction as a \
+                     function of channel in each spectral window",
+                ),
+        )
+        .arg(
+            Arg::new("bin")
+                .long("bin")
+                .help("Aggregate timeslots into bins of this width, in seconds")
+                .value_name("SECONDS")
+                .value_parser(value_parser!(f64)),
+        )
+        .arg(
+            Arg::new("time_format")
+                .long("time-format")
+                .help("How to express the times in the time series")
+                .long_help(
+                    "How to express the times in the first column of the time \
+                     series. \"casa\" is seconds since MJD 0.0, as stored in the \
+                     data set. The second column always gives times relative \
+                     to the first timeslot, in seconds. \"iso\" cannot be used \
+                     with `--waterfall`.",
+                )
+                .value_name("FORMAT")
+                .value_parser(["casa", "mjd", "iso", "unix", "relative"])
+                .default_value("casa"),
+        )
+        .arg(
+            Arg::new("weighted")
+                .long("weighted")
+                .action(ArgAction::SetTrue)
+                .help(
+                    "Also compute the flagged fraction of the total weight, using \
+                     WEIGHT_SPECTRUM or WEIGHT",
+                ),
+        )
+        .arg(
+            Arg::new("fail_above")
+                .long("fail-above")
+                .help("Exit with an error code if the flagged fraction exceeds this value")
+                .long_help(
+                    "Exit with code 2 if the flagged fraction of the whole data \
+                     set exceeds this value. If `--by` is used, each group's \
+                     flagged fraction over the whole data set is also checked, \
+                     and the offending groups are reported.",
+                )
+                .value_name("FRACTION")
+                .value_parser(value_parser!(f64))
+                .conflicts_with("spectrum"),
+        )
+        .arg(
+            Arg::new("compare")
+                .long("compare")
+                .help("Compare the flags to those of this reference data set")
+                .long_help(
+                    "Compare the flags to those of this reference data set, \
+                     such as a copy made before running an automated flagger. \
+                     Rows are matched on their time, antennas, DATA_DESC_ID, \
+                     and other identifying fields, and for each timeslot we \
+                     report the number of samples that are flagged in the input \
+                     but not the reference (newly flagged), the reverse \
+                     (newly unflagged), and those that are unchanged.",
+                )
+                .value_name("REF-TABLE")
+                .value_parser(value_parser!(PathBuf))
+                .conflicts_with_all(["by", "spectrum", "weighted", "fail_above"]),
+        )
+        .arg(
+            Arg::new("flag_version")
+                .long("flag-version")
+                .help("Analyze this CASA flag version rather than the main flags")
+                .long_help(
+                    "Analyze the flags saved in this CASA flag version, stored in \
+                     the table `<IN-TABLE>.flagversions/flags.<NAME>`, rather \
+                     than the main FLAG column. May be repeated to analyze \
+                     several versions, in which case `--output` is required \
+                     and the version name is inserted before its extension.",
+                )
+                .value_name("NAME")
+                .action(ArgAction::Append)
+                .conflicts_with("compare"),
+        )
+        .arg(
+            Arg::new("all_flag_versions")
+                .long("all-flag-versions")
+                .action(ArgAction::SetTrue)
+                .conflicts_with_all(["flag_version", "compare"])
+                .help("Analyze every CASA flag version listed in FLAG_VERSION_LIST")
+                .long_help(
+                    "Analyze every CASA flag version listed in the file \
+                     `<IN-TABLE>.flagversions/FLAG_VERSION_LIST`, producing \
+                     a set of results for each. If there are several, \
+                     `--output` is required and the version name is inserted \
+                     before its extension.",
+                ),
+        )
+        .arg(
+            Arg::new("waterfall")
+                .long("waterfall")
+                .help("Write time-frequency images of the flagged fraction into this directory")
+                .long_help(
+                    "Write 2D images of the flagged fraction, as a function of \
+                     time and channel, into this directory. An image is made \
+                     for each baseline, or each antenna if `--by antenna` is \
+                     given, and each spectral window. The images are NPY files \
+                     named like `bl1-2_spw0.npy` or `ant3_spw0.npy`, with shape \
+                     (n_time, n_chan); the time and frequency axes are written \
+                     to `times.npy` and `freqs_spw<N>.npy`. Consider using \
+                     `--bin` for long observations, since all of the images are \
+                     accumulated in memory.",
+                )
+                .value_name("DIR")
+                .value_parser(value_parser!(PathBuf))
+                .conflicts_with_all(["spectrum", "compare", "fail_above"]),
+        )
+        .arg(
+            Arg::new("format")
+                .long("format")
+                .help("The output format")
+                .value_name("FORMAT")
+                .value_parser(["text", "csv", "json", "npy"])
+                .default_value("text"),
+        )
+        .arg(
+            Arg::new("output")
+                .short('o')
+                .long("output")
+                .help("Write output to this file rather than standard output")
+                .long_help(
+                    "Write output to this file rather than standard output. \
+                     This is required for the NPY format. For the CSV and NPY \
+                     formats, the column metadata are written to a JSON file \
+                     alongside it.",
+                )
+                .value_name("PATH")
+                .value_parser(value_parser!(PathBuf)),
+        )
+        .arg(
+            Arg::new("threads")
+                .long("threads")
+                .short('j')
+                .help("The number of threads used to reduce the data [default: all CPUs]")
+                .value_name("N")
+                .value_parser(value_parser!(usize)),
+        )
+        .arg(
+            Arg::new("no_progress")
+                .long("no-progress")
+                .action(ArgAction::SetTrue)
+                .help("Don't print a progress bar"),
+        )
         .arg(
             Arg::new("IN-TABLE")
                 .help("The path of the input data set")
@@ -34,106 +747,1223 @@ pub fn make_command() -> Command {
         )
 }
 
-pub fn do_cli(matches: &ArgMatches, _nbe: &mut dyn NotificationBackend) -> Result<i32> {
+pub fn do_cli(matches: &ArgMatches, nbe: &mut dyn NotificationBackend) -> Result<i32> {
     // Deal with args.
 
     let inpath = matches.get_one::<PathBuf>("IN-TABLE").unwrap();
+    let spectrum = *matches.get_one::<bool>("spectrum").unwrap();
+    let format: OutputFormat = matches.get_one::<String>("format").unwrap().parse()?;
+    let outpath = matches.get_one::<PathBuf>("output").map(|p| p.as_path());
+    let refpath = matches.get_one::<PathBuf>("compare");
+    let waterfall_dir = matches.get_one::<PathBuf>("waterfall");
+    let settings = Settings {
+        grouping: Grouping::from_names(matches.get_many::<String>("by").unwrap_or_default())?,
+        time_format: matches.get_one::<String>("time_format").unwrap().parse()?,
+        bin_width: matches.get_one::<f64>("bin").copied(),
+        weighted: *matches.get_one::<bool>("weighted").unwrap(),
+        fail_above: matches.get_one::<f64>("fail_above").copied(),
+        n_threads: match matches.get_one::<usize>("threads") {
+            Some(n) => *n,
+            None => thread::available_parallelism().map_or(1, |n| n.get()),
+        },
+        progress: !*matches.get_one::<bool>("no_progress").unwrap(),
+    };
+
+    if let Some(w) = settings.bin_width {
+        if !w.is_finite() || w <= 0. {
+            return err_msg!("time bin width must be positive; got {}", w);
+        }
+    }
+
+    if waterfall_dir.is_some() {
+        let g = settings.grouping;
+
+        if g.spw || g.pol || g.field || g.scan || (g.antenna && g.baseline) {
+            return err_msg!("waterfalls can only be grouped by antenna or by baseline");
+        }
+
+        // The time axis is written as an NPY file, which can only hold
+        // numbers.
+        if settings.time_format == TimeFormat::Iso {
+            return err_msg!("`--time-format iso` cannot be used with `--waterfall`");
+        }
+    }
+
+    if settings.n_threads == 0 {
+        return err_msg!("the number of threads must be at least 1");
+    }
+
+    if let Some(f) = settings.fail_above {
+        if !(0. ..=1.).contains(&f) {
+            return err_msg!("flagging threshold must be between 0 and 1; got {}", f);
+        }
+    }
+
+    // Figure out which set(s) of flags we're analyzing. `None` denotes the
+    // flags in the main table.
+
+    let versions: Vec<Option<String>> = if *matches.get_one::<bool>("all_flag_versions").unwrap() {
+        list_flag_versions(inpath)?.into_iter().map(Some).collect()
+    } else {
+        match matches.get_many::<String>("flag_version") {
+            Some(names) => names.map(|n| Some(n.to_owned())).collect(),
+            None => vec![None],
+        }
+    };
+
+    let split_outputs = versions.len() > 1;
+
+    // Each version gets its own output file, since concatenating several
+    // tables on standard output would yield something that CSV or JSON
+    // readers can't parse.
+
+    if split_outputs && outpath.is_none() && waterfall_dir.is_none() {
+        return err_msg!(
+            "an output path must be specified with `--output` when analyzing multiple flag versions"
+        );
+    }
+
+    // Open up the input table and do some prep work.
+
+    let (_, mut in_main_table) = open_table(inpath, "", true)?;
+    let n_rows = in_main_table.n_rows();
+
+    // We need to decode the DATA_DESC_ID values to group by spw or
+    // polarization, and also to know the shapes of the data when reading
+    // them column-by-column.
+
+    let ddinfo = load_data_desc_info(inpath)?;
+
+    if let Some(refpath) = refpath {
+        let reader = SampleReader::new(&mut in_main_table, inpath, false)?;
+        let results = do_compare(
+            inpath,
+            &mut in_main_table,
+            &ddinfo,
+            refpath,
+            reader,
+            &settings,
+            nbe,
+        )?;
+        results.emit(format, outpath)?;
+        return Ok(0);
+    }
+
+    // Let's do it.
+
+    let mut exit_code = 0;
+
+    for version in &versions {
+        let mut reader = SampleReader::new(&mut in_main_table, inpath, settings.weighted)?;
+
+        if let Some(name) = version {
+            reader.use_flag_version(inpath, n_rows, name)?;
+
+            if split_outputs {
+                rn_note!(nbe, "analyzing flag version \"{}\"", name);
+            }
+        }
+
+        if let Some(dir) = waterfall_dir {
+            let dir = match version {
+                Some(name) if split_outputs => dir.join(name),
+                _ => dir.to_owned(),
+            };
+
+            do_waterfall(inpath, &mut in_main_table, &ddinfo, reader, &settings, &dir)?;
+            continue;
+        }
+
+        let (mut results, code) = if spectrum {
+            (
+                do_spectrum(inpath, &mut in_main_table, &ddinfo, reader, &settings)?,
+                0,
+            )
+        } else {
+            do_timeseries(inpath, &mut in_main_table, &ddinfo, reader, &settings, nbe)?
+        };
+
+        results.flag_version = version.clone();
+
+        let dest = match (outpath, version) {
+            (Some(p), Some(name)) if split_outputs => Some(versioned_output_path(p, name)),
+            _ => outpath.map(|p| p.to_owned()),
+        };
+
+        results.emit(format, dest.as_deref())?;
+        exit_code = exit_code.max(code);
+    }
+
+    Ok(exit_code)
+}
+
+/// Settings of the analysis, as parsed from the command line.
+#[derive(Clone, Copy)]
+struct Settings {
+    grouping: Grouping,
+    time_format: TimeFormat,
+    bin_width: Option<f64>,
+    weighted: bool,
+    fail_above: Option<f64>,
+    n_threads: usize,
+    progress: bool,
+}
+
+/// A progress bar that can be disabled.
+struct Progress(Option<pbr::ProgressBar<io::StderrLock<'static>>>);
+
+impl Progress {
+    fn new(n_rows: u64, enabled: bool) -> Self {
+        Progress(enabled.then(|| {
+            let mut pb = pbr::ProgressBar::on(io::stderr().lock(), n_rows);
+            pb.set_max_refresh_rate(Some(std::time::Duration::from_millis(500)));
+            pb
+        }))
+    }
+
+    fn inc(&mut self) {
+        if let Some(pb) = self.0.as_mut() {
+            pb.inc();
+        }
+    }
+
+    fn add(&mut self, n: u64) {
+        if let Some(pb) = self.0.as_mut() {
+            pb.add(n);
+        }
+    }
+
+    fn finish(&mut self, message: &str) {
+        if let Some(pb) = self.0.as_mut() {
+            pb.finish_println(message);
+        }
+    }
+}
+
+/// Get the path to which the results for one of several flag versions are
+/// written: "out.csv" becomes "out.<version>.csv".
+fn versioned_output_path(base: &Path, version: &str) -> PathBuf {
+    let mut name = base.file_stem().unwrap_or_default().to_owned();
+    name.push(".");
+    name.push(version);
+
+    if let Some(ext) = base.extension() {
+        name.push(".");
+        name.push(ext);
+    }
+
+    base.with_file_name(name)
+}
+
+/// The number of rows read at once when computing the time series.
+const CHUNK_ROWS: u64 = 1024;
+
+/// The information about one row needed to accumulate the time series.
+struct RowSample<'a> {
+    /// The bit pattern of the (possibly binned) timestamp.
+    recast_time: u64,
+
+    /// The group(s) that the row contributes to.
+    keys: Vec<GroupKey>,
+
+    /// If grouping by polarization, the CORR_TYPE codes of the row's data.
+    corr_types: Option<&'a [i32]>,
+
+    flag: Array<bool, Ix2>,
+    weights: MaybeWeights,
+}
 
-    // Open up the input table and do some prep work. We do this up here
-    // so that we can validate some of the program configuration before
-    // creating the output tables.
+/// The scalar columns needed to compute the time series, read in bulk.
+/// Columns that the requested grouping doesn't need aren't read at all.
+struct RowColumns {
+    /// The bit patterns of the (possibly binned) timestamps.
+    recast_time: Vec<u64>,
+    ddid: Vec<i32>,
+    antennas: Option<(Vec<i32>, Vec<i32>)>,
+    field_id: Option<Vec<i32>>,
+    scan: Option<Vec<i32>>,
+}
 
-    fn open_table(base: &Path, extension: &str, is_input: bool) -> Result<(PathBuf, Table)> {
-        let mut p = base.to_owned();
+impl RowColumns {
+    fn load(table: &mut Table, settings: &Settings) -> Result<Self> {
+        let grouping = settings.grouping;
+        let mut time = table.get_col_as_vec::<f64>("TIME")?;
 
-        if extension.len() > 0 {
-            p.push(extension);
+        // If binning, we label each bin by its center. Bins are aligned with
+        // MJD 0 so that their boundaries don't depend on the data.
+        if let Some(w) = settings.bin_width {
+            for t in &mut time {
+                *t = ((*t / w).floor() + 0.5) * w;
+            }
         }
 
-        let mode = if is_input {
-            TableOpenMode::Read
+        let antennas = if grouping.antenna || grouping.baseline {
+            Some((
+                table.get_col_as_vec("ANTENNA1")?,
+                table.get_col_as_vec("ANTENNA2")?,
+            ))
+        } else {
+            None
+        };
+
+        let field_id = if grouping.field {
+            Some(table.get_col_as_vec("FIELD_ID")?)
+        } else {
+            None
+        };
+
+        let scan = if grouping.scan {
+            Some(table.get_col_as_vec("SCAN_NUMBER")?)
         } else {
-            TableOpenMode::ReadWrite
+            None
         };
 
-        let t = ctry!(Table::open(&p, mode);
-                      "failed to open {} {}table \"{}\"",
-                      if is_input { "input" } else { "output" },
-                      if extension.len() > 0 { "sub-" } else { "" },
-                      p.display()
+        Ok(RowColumns {
+            recast_time: time.into_iter().map(f64::to_bits).collect(),
+            ddid: table.get_col_as_vec("DATA_DESC_ID")?,
+            antennas,
+            field_id,
+            scan,
+        })
+    }
+
+    /// Decode a row read with `SampleReader::read_raw`, working out which
+    /// group(s) it contributes to.
+    fn decode<'a>(
+        &self,
+        ddinfo: &'a [DataDescInfo],
+        grouping: Grouping,
+        row_num: u64,
+        raw: RawSample,
+    ) -> Result<RowSample<'a>> {
+        let i = row_num as usize;
+        let ddid = self.ddid[i];
+
+        let dd = match ddinfo.get(ddid as usize) {
+            Some(d) => d,
+            None => {
+                return err_msg!(
+                    "row #{} refers to nonexistent DATA_DESC_ID {}",
+                    row_num,
+                    ddid
+                );
+            }
+        };
+
+        let (flag, weights) = ctry!(
+            raw.decode((dd.num_chan, dd.corr_types.len()));
+            "failed to read row #{}", row_num
         );
 
-        Ok((p, t))
+        // Figure out which group(s) this row belongs to. When grouping by
+        // antenna, each cross-correlation contributes to two groups.
+
+        let mut key = GroupKey::default();
+        let ants = self.antennas.as_ref().map(|(a1, a2)| (a1[i], a2[i]));
+
+        if grouping.baseline {
+            key.baseline = ants;
+        }
+
+        if grouping.spw {
+            key.spw = Some(dd.spw_id);
+        }
+
+        key.field = self.field_id.as_ref().map(|f| f[i]);
+        key.scan = self.scan.as_ref().map(|s| s[i]);
+
+        let mut keys = Vec::with_capacity(2);
+
+        match ants {
+            Some((ant1, ant2)) if grouping.antenna => {
+                keys.push(GroupKey {
+                    antenna: Some(ant1),
+                    ..key
+                });
+
+                if ant2 != ant1 {
+                    keys.push(GroupKey {
+                        antenna: Some(ant2),
+                        ..key
+                    });
+                }
+            }
+
+            _ => {
+                keys.push(key);
+            }
+        }
+
+        Ok(RowSample {
+            recast_time: self.recast_time[i],
+            keys,
+            corr_types: Some(&dd.corr_types[..]).filter(|_| grouping.pol),
+            flag,
+            weights,
+        })
     }
+}
 
-    let (_, mut in_main_table) = open_table(&inpath, "", true)?;
+/// The flagging statistics accumulated by one worker thread.
+#[derive(Default)]
+struct Accumulator {
+    records: HashMap<(u64, GroupKey), FlagCounts>,
+    group_keys: BTreeSet<GroupKey>,
 
-    // Let's do it.
+    /// The ungrouped statistics of each timeslot, if needed.
+    overall: HashMap<u64, FlagCounts>,
+}
+
+impl Accumulator {
+    fn add(&mut self, sample: &RowSample, want_overall: bool) {
+        let flag = &sample.flag;
+        let weights = sample.weights.as_ref();
+
+        if want_overall {
+            self.overall
+                .entry(sample.recast_time)
+                .or_default()
+                .accumulate(flag.view(), weights.map(|w| w.view()));
+        }
 
-    struct TimeslotInfo {
-        n_total: usize,
-        n_flagged: usize,
+        for rk in &sample.keys {
+            if let Some(corr_types) = sample.corr_types {
+                // The FLAG array has shape (n_chan, n_pol).
+                for (i_pol, pol_flags) in flag.axis_iter(Axis(1)).enumerate() {
+                    let pol_weights = weights.map(|w| w.index_axis(Axis(1), i_pol));
+                    let pol_key = GroupKey {
+                        pol: corr_types.get(i_pol).copied(),
+                        ..*rk
+                    };
+
+                    self.group_keys.insert(pol_key);
+                    self.records
+                        .entry((sample.recast_time, pol_key))
+                        .or_default()
+                        .accumulate(pol_flags, pol_weights);
+                }
+            } else {
+                self.group_keys.insert(*rk);
+                self.records
+                    .entry((sample.recast_time, *rk))
+                    .or_default()
+                    .accumulate(flag.view(), weights.map(|w| w.view()));
+            }
+        }
     }
+}
+
+/// Decide which worker thread is responsible for a given timeslot.
+fn timeslot_partition(recast_time: u64, n_threads: usize) -> usize {
+    // Consecutive timestamps differ mainly in their middle bits, so mix them
+    // up before reducing.
+    ((recast_time.wrapping_mul(0x9E37_79B9_7F4A_7C15) >> 32) % n_threads as u64) as usize
+}
 
-    let mut records = HashMap::new();
-    let mut in_row_num = 0usize;
-    let stderr = io::stderr();
-    let mut pb = pbr::ProgressBar::on(stderr.lock(), in_main_table.n_rows());
-    pb.set_max_refresh_rate(Some(std::time::Duration::from_millis(500)));
+/// Compute the time series of flagging statistics. Besides the results, this
+/// returns the exit code implied by the `--fail-above` check, if any.
+fn do_timeseries(
+    inpath: &Path,
+    in_main_table: &mut Table,
+    ddinfo: &[DataDescInfo],
+    mut reader: SampleReader,
+    settings: &Settings,
+    nbe: &mut dyn NotificationBackend,
+) -> Result<(ResultTable, i32)> {
+    let grouping = settings.grouping;
+    let time_format = settings.time_format;
+    let bin_width = settings.bin_width;
+    let weighted = settings.weighted;
 
-    in_main_table.for_each_row(|in_row| {
-        let time: f64 = in_row.get_cell("TIME")?;
-        let recast_time: u64 = unsafe { mem::transmute(time) };
+    // The scalar columns are read in bulk up front. The main thread then
+    // reads the array cells in chunks of rows, handing each row to the
+    // worker thread responsible for its timeslot, which decodes it and
+    // accumulates the statistics. Each worker sees its rows in their
+    // original order, so that floating-point sums are computed in exactly
+    // the same order regardless of the number of threads.
 
-        if !records.contains_key(&recast_time) {
-            records.insert(
-                recast_time,
-                TimeslotInfo {
-                    n_total: 0,
-                    n_flagged: 0,
-                },
-            );
+    let n_rows = in_main_table.n_rows();
+    let n_threads = settings.n_threads;
+    let want_overall = settings.fail_above.is_some();
+    let cols = RowColumns::load(in_main_table, settings)?;
+    let mut progress = Progress::new(n_rows, settings.progress);
+
+    let accumulators = thread::scope(|scope| {
+        let mut senders = Vec::with_capacity(n_threads);
+        let mut workers = Vec::with_capacity(n_threads);
+
+        for _ in 0..n_threads {
+            let (tx, rx) = mpsc::sync_channel::<Vec<(u64, RawSample)>>(2);
+            let cols = &cols;
+            senders.push(tx);
+
+            workers.push(scope.spawn(move || -> Result<Accumulator> {
+                let mut acc = Accumulator::default();
+
+                for batch in rx {
+                    for (row_num, raw) in batch {
+                        let sample = cols.decode(ddinfo, grouping, row_num, raw)?;
+                        acc.add(&sample, want_overall);
+                    }
+                }
+
+                Ok(acc)
+            }));
         }
 
-        let state = records.get_mut(&recast_time).unwrap();
-        let flag = in_row.get_cell::<Array<bool, Ix2>>("FLAG")?;
-        state.n_total += flag.len();
-        state.n_flagged += flag.fold(0usize, |a, f| if *f { a + 1 } else { a });
+        // Only this thread touches the tables; see the module docs.
+        let mut read_result = Ok(());
+        let mut start = 0;
 
-        in_row_num += 1;
-        pb.inc();
-        Ok(())
+        'chunks: while start < n_rows {
+            let n = (n_rows - start).min(CHUNK_ROWS);
+            let mut batches = (0..n_threads).map(|_| Vec::new()).collect::<Vec<_>>();
+
+            for row_num in start..start + n {
+                let i_thread = timeslot_partition(cols.recast_time[row_num as usize], n_threads);
+
+                match reader.read_raw(in_main_table, row_num) {
+                    Ok(raw) => batches[i_thread].push((row_num, raw)),
+                    Err(e) => {
+                        read_result = Err(e);
+                        break 'chunks;
+                    }
+                }
+            }
+
+            for (tx, batch) in senders.iter().zip(batches) {
+                // If sending fails, the worker has hit an error, which is
+                // reported when we join it below.
+                if !batch.is_empty() && tx.send(batch).is_err() {
+                    break 'chunks;
+                }
+            }
+
+            start += n;
+            progress.add(n);
+        }
+
+        // Hang up on the workers so that they finish.
+        drop(senders);
+
+        let accs = workers
+            .into_iter()
+            .map(|w| w.join().expect("flagts worker thread panicked"))
+            .collect::<Result<Vec<_>>>();
+        read_result.and(accs)
     })?;
 
-    let iter = records.keys().sorted_by(|rc1, rc2| {
-        // explicit type annotations to avoid accidentally transmuting &u64
-        let t1: f64 = unsafe { mem::transmute::<u64, f64>(**rc1) };
-        let t2: f64 = unsafe { mem::transmute::<u64, f64>(**rc2) };
-        t1.partial_cmp(&t2).unwrap()
-    });
+    // The workers' timeslots are disjoint, so merging is simple.
+
+    let mut records: HashMap<(u64, GroupKey), FlagCounts> = HashMap::new();
+    let mut group_keys = BTreeSet::new();
+    let mut overall_by_time = HashMap::new();
+
+    for acc in accumulators {
+        records.extend(acc.records);
+        group_keys.extend(acc.group_keys);
+        overall_by_time.extend(acc.overall);
+    }
+
+    let times = records
+        .keys()
+        .map(|(rt, _)| *rt)
+        .unique()
+        .sorted_by(|rc1, rc2| {
+            // explicit type annotations to avoid accidentally mis-casting &u64
+            let t1: f64 = f64::from_bits(*rc1);
+            let t2: f64 = f64::from_bits(*rc2);
+            t1.partial_cmp(&t2).unwrap()
+        })
+        .collect::<Vec<_>>();
+
+    let mut results = ResultTable::new(inpath, "timeseries", time_reference(in_main_table));
+    let mut time_description = time_format.description(&results.time_reference);
+
+    if let Some(w) = bin_width {
+        time_description.push_str(&format!("; center of {} s bin", w));
+    }
+
+    results.columns.push(Column::new("time", time_description));
+    results.columns.push(Column::new(
+        "reltime",
+        "timeslot, in seconds relative to the first timeslot",
+    ));
+
+    if grouping.is_grouped() {
+        let what = if weighted { "weight" } else { "samples" };
+
+        for key in &group_keys {
+            results.columns.push(Column::new(
+                key,
+                format!("flagged fraction of {} in group {}", what, key),
+            ));
+        }
+    } else {
+        results
+            .columns
+            .push(Column::new("n_total", "total number of samples"));
+        results
+            .columns
+            .push(Column::new("n_flagged", "number of flagged samples"));
+        results.text_header = false;
+
+        if weighted {
+            results.columns.push(weight_total_column());
+            results.columns.push(weight_flagged_column());
+        }
+    }
+
+    // Sum the overall statistics in time order, so that the result doesn't
+    // depend on how the timeslots were divided among the workers.
+
+    let mut overall = FlagCounts::default();
+
+    for recast_time in &times {
+        if let Some(counts) = overall_by_time.get(recast_time) {
+            overall += counts;
+        }
+    }
 
     let mut t0 = f64::NAN;
 
-    for recast_time in iter {
-        let time: f64 = unsafe { mem::transmute::<u64, f64>(*recast_time) };
-        let state = records.get(recast_time).unwrap();
+    for recast_time in &times {
+        let time: f64 = f64::from_bits(*recast_time);
 
         if t0.is_nan() {
             t0 = time;
         }
 
-        println!(
-            "{:.16e} {:.16e} {} {}",
-            time,
-            time - t0,
-            state.n_total,
-            state.n_flagged
-        );
+        let mut row = vec![time_format.format(time, t0), Value::Float(time - t0)];
+
+        if grouping.is_grouped() {
+            for key in &group_keys {
+                let frac = records
+                    .get(&(*recast_time, *key))
+                    .map(|c| c.fraction(weighted))
+                    .unwrap_or(f64::NAN);
+                row.push(Value::Float(frac));
+            }
+        } else {
+            let state = records.get(&(*recast_time, GroupKey::default())).unwrap();
+            row.push(Value::Int(state.n_total as i64));
+            row.push(Value::Int(state.n_flagged as i64));
+
+            if weighted {
+                row.push(Value::Float(state.w_total));
+                row.push(Value::Float(state.w_flagged));
+            }
+        }
+
+        results.rows.push(row);
     }
 
-    pb.finish_println(&format!(
+    progress.finish(&format!(
         "Computed flag stats for {} timeslots",
-        records.len()
+        times.len()
+    ));
+
+    // If we're acting as a QA gate, check the results.
+
+    let threshold = match settings.fail_above {
+        Some(t) => t,
+        None => return Ok((results, 0)),
+    };
+
+    let what = if weighted { "weight" } else { "samples" };
+    let overall_frac = overall.fraction(weighted);
+    let mut n_failed = 0;
+
+    rn_note!(
+        nbe,
+        "overall flagged fraction of {}: {:.4} ({} of {} samples flagged)",
+        what,
+        overall_frac,
+        overall.n_flagged,
+        overall.n_total
+    );
+
+    if overall_frac > threshold {
+        rn_severe!(
+            nbe,
+            "overall flagged fraction {:.4} exceeds threshold {}",
+            overall_frac,
+            threshold
+        );
+        n_failed += 1;
+    }
+
+    if grouping.is_grouped() {
+        let mut group_totals: HashMap<GroupKey, FlagCounts> = HashMap::new();
+
+        for ((_, key), counts) in &records {
+            *group_totals.entry(*key).or_default() += counts;
+        }
+
+        for key in &group_keys {
+            let frac = group_totals[key].fraction(weighted);
+
+            if frac > threshold {
+                rn_severe!(
+                    nbe,
+                    "group {} has flagged fraction {:.4}, exceeding threshold {}",
+                    key,
+                    frac,
+                    threshold
+                );
+                n_failed += 1;
+            }
+        }
+    }
+
+    if n_failed > 0 {
+        rn_note!(nbe, "{} flagging threshold check(s) failed", n_failed);
+        return Ok((results, FAIL_ABOVE_EXIT_CODE));
+    }
+
+    Ok((results, 0))
+}
+
+fn weight_total_column() -> Column {
+    Column::new("w_total", "total weight of all samples")
+}
+
+fn weight_flagged_column() -> Column {
+    Column::new("w_flagged", "total weight of flagged samples")
+}
+
+/// Accumulate the flags along the time axis rather than the frequency axis,
+/// tabulating the flagging statistics of each channel of each spectral window.
+fn do_spectrum(
+    inpath: &Path,
+    in_main_table: &mut Table,
+    ddinfo: &[DataDescInfo],
+    mut reader: SampleReader,
+    settings: &Settings,
+) -> Result<ResultTable> {
+    let mut spectra: HashMap<i32, Vec<FlagCounts>> = HashMap::new();
+    let n_rows = in_main_table.n_rows();
+    let mut progress = Progress::new(n_rows, settings.progress);
+    let ddids = in_main_table.get_col_as_vec::<i32>("DATA_DESC_ID")?;
+
+    for row_num in 0..n_rows {
+        let ddid = ddids[row_num as usize];
+        let dd = match ddinfo.get(ddid as usize) {
+            Some(d) => d,
+            None => {
+                return err_msg!(
+                    "row #{} refers to nonexistent DATA_DESC_ID {}",
+                    row_num,
+                    ddid
+                );
+            }
+        };
+
+        // The FLAG array has shape (n_chan, n_pol).
+        let raw = reader.read_raw(in_main_table, row_num)?;
+        let (flag, weights) = ctry!(
+            raw.decode((dd.num_chan, dd.corr_types.len()));
+            "failed to read row #{}", row_num
+        );
+        let spectrum = spectra.entry(dd.spw_id).or_default();
+
+        if spectrum.len() < flag.shape()[0] {
+            spectrum.resize(flag.shape()[0], FlagCounts::default());
+        }
+
+        for (i_chan, (counts, chan_flags)) in
+            spectrum.iter_mut().zip(flag.axis_iter(Axis(0))).enumerate()
+        {
+            counts.accumulate(
+                chan_flags,
+                weights.as_ref().map(|w| w.index_axis(Axis(0), i_chan)),
+            );
+        }
+
+        progress.inc();
+    }
+
+    // Now get the frequencies of the channels and tabulate everything.
+
+    let (in_spw_path, mut in_spw_table) = open_table(inpath, "SPECTRAL_WINDOW", true)?;
+    let mut results = ResultTable::new(inpath, "spectrum", time_reference(in_main_table));
+    results
+        .columns
+        .push(Column::new("spw", "spectral window ID"));
+    results.columns.push(Column::new(
+        "chan",
+        "channel number within the spectral window",
+    ));
+    results
+        .columns
+        .push(Column::new("freq", "channel frequency, in Hz"));
+    results
+        .columns
+        .push(Column::new("n_total", "total number of samples"));
+    results
+        .columns
+        .push(Column::new("n_flagged", "number of flagged samples"));
+
+    if reader.weights.is_some() {
+        results.columns.push(weight_total_column());
+        results.columns.push(weight_flagged_column());
+    }
+
+    for (spw_id, spectrum) in spectra.iter().sorted_by_key(|(spw_id, _)| **spw_id) {
+        let freqs = ctry!(
+            in_spw_table.get_cell_as_vec::<f64>("CHAN_FREQ", *spw_id as u64);
+            "failed to read CHAN_FREQ of spectral window {} from \"{}\"", spw_id, in_spw_path.display()
+        );
+
+        if freqs.len() != spectrum.len() {
+            return err_msg!(
+                "spectral window {} has {} channels in \"{}\", but its FLAG data have {}",
+                spw_id,
+                freqs.len(),
+                in_spw_path.display(),
+                spectrum.len()
+            );
+        }
+
+        for (i_chan, (freq, counts)) in freqs.iter().zip(spectrum.iter()).enumerate() {
+            let mut row = vec![
+                Value::Int(*spw_id as i64),
+                Value::Int(i_chan as i64),
+                Value::Float(*freq),
+                Value::Int(counts.n_total as i64),
+                Value::Int(counts.n_flagged as i64),
+            ];
+
+            if reader.weights.is_some() {
+                row.push(Value::Float(counts.w_total));
+                row.push(Value::Float(counts.w_flagged));
+            }
+
+            results.rows.push(row);
+        }
+    }
+
+    progress.finish(&format!(
+        "Computed flag spectra for {} spectral windows",
+        spectra.len()
     ));
-    Ok(0)
+    Ok(results)
+}
+
+/// Counts of how the flags of matched samples differ between two data sets.
+#[derive(Clone, Copy, Debug, Default)]
+struct FlagChangeCounts {
+    n_newly_flagged: usize,
+    n_newly_unflagged: usize,
+    n_unchanged: usize,
+}
+
+/// Compare the flags of the input data set with those of a reference data
+/// set, matching rows by their identities, and tabulate the changes as a
+/// function of time.
+fn do_compare(
+    inpath: &Path,
+    in_main_table: &mut Table,
+    ddinfo: &[DataDescInfo],
+    refpath: &Path,
+    mut reader: SampleReader,
+    settings: &Settings,
+    nbe: &mut dyn NotificationBackend,
+) -> Result<ResultTable> {
+    let bin_width = settings.bin_width;
+    let time_format = settings.time_format;
+    let (_, mut ref_table) = open_table(refpath, "", true)?;
+    let mut ref_reader = SampleReader::new(&mut ref_table, refpath, false)?;
+
+    // First, index the reference data set. Only the scalar columns that
+    // identify each row are read, in bulk; the flags are read cell-by-cell
+    // for the matched rows below.
+
+    let ref_idents = ctry!(
+        VisRecordIdentity::load_all(&mut ref_table);
+        "failed to index the rows of \"{}\"", refpath.display()
+    );
+    let n_ref_rows = ref_idents.len();
+    let mut ref_rows = HashMap::with_capacity(n_ref_rows);
+
+    for (ref_row_num, ident) in ref_idents.into_iter().enumerate() {
+        ref_rows.insert(ident, ref_row_num as u64);
+    }
+
+    if ref_rows.len() < n_ref_rows {
+        return err_msg!(
+            "{} rows of \"{}\" have duplicate identities, so rows can't be matched reliably",
+            n_ref_rows - ref_rows.len(),
+            refpath.display()
+        );
+    }
+
+    // Now walk the input data set.
+
+    let in_idents = ctry!(
+        VisRecordIdentity::load_all(in_main_table);
+        "failed to read the rows of \"{}\"", inpath.display()
+    );
+    let mut records: HashMap<u64, FlagChangeCounts> = HashMap::new();
+    let mut n_unmatched = 0usize;
+    let mut n_matched = 0usize;
+    let mut progress = Progress::new(in_main_table.n_rows(), settings.progress);
+
+    for (row_num, ident) in in_idents.iter().enumerate() {
+        let row_num = row_num as u64;
+        progress.inc();
+
+        let ref_row_num = match ref_rows.get(ident) {
+            Some(n) => *n,
+            None => {
+                n_unmatched += 1;
+                continue;
+            }
+        };
+
+        let mut time = ident.time();
+
+        if let Some(w) = bin_width {
+            time = ((time / w).floor() + 0.5) * w;
+        }
+
+        let ddid = *ident.discriminant();
+        let dd = match ddinfo.get(ddid as usize) {
+            Some(d) => d,
+            None => {
+                return err_msg!(
+                    "row #{} refers to nonexistent DATA_DESC_ID {}",
+                    row_num,
+                    ddid
+                );
+            }
+        };
+
+        let raw = reader.read_raw(in_main_table, row_num)?;
+        let ref_raw = ref_reader.read_raw(&mut ref_table, ref_row_num)?;
+
+        if raw.flag.len() != ref_raw.flag.len() {
+            return err_msg!(
+                "FLAG cells of matched rows differ in size ({} vs. {} elements) at time {}",
+                raw.flag.len(),
+                ref_raw.flag.len(),
+                time
+            );
+        }
+
+        let shape = (dd.num_chan, dd.corr_types.len());
+        let (flag, _) = ctry!(
+            raw.decode(shape);
+            "failed to read row #{} of \"{}\"", row_num, inpath.display()
+        );
+        let (ref_flag, _) = ctry!(
+            ref_raw.decode(shape);
+            "failed to read row #{} of \"{}\"", ref_row_num, refpath.display()
+        );
+        let state = records.entry(time.to_bits()).or_default();
+
+        Zip::from(&flag)
+            .and(&ref_flag)
+            .for_each(|f, rf| match (*f, *rf) {
+                (true, false) => state.n_newly_flagged += 1,
+                (false, true) => state.n_newly_unflagged += 1,
+                _ => state.n_unchanged += 1,
+            });
+
+        n_matched += 1;
+    }
+
+    progress.finish(&format!("Compared flags for {} timeslots", records.len()));
+
+    if n_unmatched > 0 {
+        rn_warning!(
+            nbe,
+            "{} rows of \"{}\" had no counterpart in \"{}\" and were ignored",
+            n_unmatched,
+            inpath.display(),
+            refpath.display()
+        );
+    }
+
+    if n_matched < n_ref_rows {
+        rn_warning!(
+            nbe,
+            "{} rows of \"{}\" had no counterpart in \"{}\" and were ignored",
+            n_ref_rows - n_matched,
+            refpath.display(),
+            inpath.display()
+        );
+    }
+
+    // Tabulate.
+
+    let mut results = ResultTable::new(inpath, "compare", time_reference(in_main_table));
+    let mut time_description = time_format.description(&results.time_reference);
+
+    if let Some(w) = bin_width {
+        time_description.push_str(&format!("; center of {} s bin", w));
+    }
+
+    results.columns.push(Column::new("time", time_description));
+    results.columns.push(Column::new(
+        "reltime",
+        "timeslot, in seconds relative to the first timeslot",
+    ));
+    results.columns.push(Column::new(
+        "n_newly_flagged",
+        format!(
+            "number of samples flagged here but not in {}",
+            refpath.display()
+        ),
+    ));
+    results.columns.push(Column::new(
+        "n_newly_unflagged",
+        format!(
+            "number of samples flagged in {} but not here",
+            refpath.display()
+        ),
+    ));
+    results.columns.push(Column::new(
+        "n_unchanged",
+        "number of samples whose flags are unchanged",
+    ));
+
+    let mut t0 = f64::NAN;
+
+    for (recast_time, state) in records.iter().sorted_by(|(rc1, _), (rc2, _)| {
+        f64::from_bits(**rc1)
+            .partial_cmp(&f64::from_bits(**rc2))
+            .unwrap()
+    }) {
+        let time: f64 = f64::from_bits(*recast_time);
+
+        if t0.is_nan() {
+            t0 = time;
+        }
+
+        results.rows.push(vec![
+            time_format.format(time, t0),
+            Value::Float(time - t0),
+            Value::Int(state.n_newly_flagged as i64),
+            Value::Int(state.n_newly_unflagged as i64),
+            Value::Int(state.n_unchanged as i64),
+        ]);
+    }
+
+    Ok(results)
+}
+
+/// Accumulate per-channel flagging statistics for each timeslot and each
+/// baseline or antenna, and write them out as time-frequency images.
+fn do_waterfall(
+    inpath: &Path,
+    in_main_table: &mut Table,
+    ddinfo: &[DataDescInfo],
+    mut reader: SampleReader,
+    settings: &Settings,
+    dir: &Path,
+) -> Result<()> {
+    // Default to per-baseline images, and always split by spectral window,
+    // since different windows have different channelizations.
+
+    let mut settings = *settings;
+
+    if !settings.grouping.antenna {
+        settings.grouping.baseline = true;
+    }
+
+    settings.grouping.spw = true;
+
+    let n_rows = in_main_table.n_rows();
+    let mut progress = Progress::new(n_rows, settings.progress);
+    let mut images: HashMap<GroupKey, HashMap<u64, Vec<FlagCounts>>> = HashMap::new();
+    let mut recast_times = HashSet::new();
+    let cols = RowColumns::load(in_main_table, &settings)?;
+    let mut start = 0;
+
+    while start < n_rows {
+        let n = (n_rows - start).min(CHUNK_ROWS);
+
+        for row_num in start..start + n {
+            let raw = reader.read_raw(in_main_table, row_num)?;
+            let sample = cols.decode(ddinfo, settings.grouping, row_num, raw)?;
+            recast_times.insert(sample.recast_time);
+
+            for key in &sample.keys {
+                let spectrum = images
+                    .entry(*key)
+                    .or_default()
+                    .entry(sample.recast_time)
+                    .or_default();
+
+                if spectrum.len() < sample.flag.shape()[0] {
+                    spectrum.resize(sample.flag.shape()[0], FlagCounts::default());
+                }
+
+                // The FLAG array has shape (n_chan, n_pol).
+                for (i_chan, (counts, chan_flags)) in spectrum
+                    .iter_mut()
+                    .zip(sample.flag.axis_iter(Axis(0)))
+                    .enumerate()
+                {
+                    counts.accumulate(
+                        chan_flags,
+                        sample
+                            .weights
+                            .as_ref()
+                            .map(|w| w.index_axis(Axis(0), i_chan)),
+                    );
+                }
+            }
+        }
+
+        start += n;
+        progress.add(n);
+    }
+
+    ctry!(
+        std::fs::create_dir_all(dir);
+        "failed to create output directory \"{}\"", dir.display()
+    );
+
+    // The time axis, which is shared by all of the images.
+
+    let times = recast_times
+        .into_iter()
+        .map(f64::from_bits)
+        .sorted_by(|t1, t2| t1.partial_cmp(t2).unwrap())
+        .collect::<Vec<_>>();
+
+    let time_reference = time_reference(in_main_table);
+    let mut time_axis = ResultTable::new(inpath, "waterfall", time_reference.clone());
+    let mut time_description = settings.time_format.description(&time_reference);
+
+    if let Some(w) = settings.bin_width {
+        time_description.push_str(&format!("; center of {} s bin", w));
+    }
+
+    time_axis
+        .columns
+        .push(Column::new("time", time_description));
+    time_axis.columns.push(Column::new(
+        "reltime",
+        "timeslot, in seconds relative to the first timeslot",
+    ));
+
+    let t0 = times.first().copied().unwrap_or(f64::NAN);
+
+    for t in &times {
+        time_axis.rows.push(vec![
+            settings.time_format.format(*t, t0),
+            Value::Float(t - t0),
+        ]);
+    }
+
+    time_axis.emit(OutputFormat::Npy, Some(&dir.join("times.npy")))?;
+
+    // The frequency axes of the spectral windows.
+
+    let (in_spw_path, mut in_spw_table) = open_table(inpath, "SPECTRAL_WINDOW", true)?;
+    let spw_ids = images.keys().filter_map(|k| k.spw).collect::<BTreeSet<_>>();
+
+    for spw_id in &spw_ids {
+        let freqs = ctry!(
+            in_spw_table.get_cell_as_vec::<f64>("CHAN_FREQ", *spw_id as u64);
+            "failed to read CHAN_FREQ of spectral window {} from \"{}\"", spw_id, in_spw_path.display()
+        );
+
+        let mut freq_axis = ResultTable::new(inpath, "waterfall", time_reference.clone());
+        freq_axis.columns.push(Column::new(
+            "chan",
+            "channel number within the spectral window",
+        ));
+        freq_axis
+            .columns
+            .push(Column::new("freq", "channel frequency, in Hz"));
+
+        for (i_chan, freq) in freqs.iter().enumerate() {
+            freq_axis
+                .rows
+                .push(vec![Value::Int(i_chan as i64), Value::Float(*freq)]);
+        }
+
+        freq_axis.emit(
+            OutputFormat::Npy,
+            Some(&dir.join(format!("freqs_spw{}.npy", spw_id))),
+        )?;
+    }
+
+    // Finally, the images themselves.
+
+    for (key, by_time) in &images {
+        let n_chan = by_time.values().map(|s| s.len()).max().unwrap_or(0);
+        let mut image = Array::<f64, Ix2>::from_elem((times.len(), n_chan), f64::NAN);
+
+        for (mut image_row, t) in image.outer_iter_mut().zip(&times) {
+            if let Some(spectrum) = by_time.get(&t.to_bits()) {
+                for (pixel, counts) in image_row.iter_mut().zip(spectrum) {
+                    *pixel = counts.fraction(settings.weighted);
+                }
+            }
+        }
+
+        let name = match (key.antenna, key.baseline) {
+            (Some(ant), _) => format!("ant{}", ant),
+            (None, Some((ant1, ant2))) => format!("bl{}-{}", ant1, ant2),
+            (None, None) => {
+                return err_msg!("waterfall images must be grouped by antenna or baseline");
+            }
+        };
+
+        let path = dir.join(format!("{}_spw{}.npy", name, key.spw.unwrap_or(0)));
+        let mut f = io::BufWriter::new(ctry!(
+            std::fs::File::create(&path);
+            "failed to create output file \"{}\"", path.display()
+        ));
+        ctry!(
+            ndarray_to_npy_stream(&image, &mut f);
+            "failed to write output file \"{}\"", path.display()
+        );
+        ctry!(
+            f.flush();
+            "failed to write output file \"{}\"", path.display()
+        );
+    }
+
+    progress.finish(&format!(
+        "Wrote {} waterfall images to \"{}\"",
+        images.len(),
+        dir.display()
+    ));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn iso_times() {
+        assert_eq!(
+            casa_time_to_iso(51544.5 * 86400.),
+            "2000-01-01T12:00:00.000"
+        );
+        assert_eq!(
+            casa_time_to_iso(60369. * 86400. + 3723.456),
+            "2024-02-29T01:02:03.456"
+        );
+        assert_eq!(casa_time_to_iso(0.), "1858-11-17T00:00:00.000");
+    }
+
+    #[test]
+    fn iso_times_round_to_milliseconds() {
+        assert_eq!(
+            casa_time_to_iso(40587. * 86400. - 0.0004),
+            "1970-01-01T00:00:00.000"
+        );
+        assert_eq!(
+            casa_time_to_iso(59000. * 86400. + 59.9996),
+            "2020-05-31T00:01:00.000"
+        );
+    }
 }
@@ -32,7 +32,11 @@ impl From<MiscellaneousError> for rubbl_casatables::TableError {
 }
 
 mod flagts;
+mod msutil;
+mod npy;
+mod output;
 mod peel;
+mod predict;
 mod spwglue;
 
 fn main() {
@@ -44,10 +48,11 @@ fn main() {
             match matches.subcommand() {
                 Some(("flagts", m)) => flagts::do_cli(m, nbe),
                 Some(("peel", m)) => peel::do_cli(m, nbe),
+                Some(("predict", m)) => predict::do_cli(m, nbe),
                 Some(("show", m)) => do_show_cli(m, nbe),
                 Some(("spwglue", m)) => spwglue::do_cli(m, nbe),
                 Some((unknown, _)) => {
-                    return err_msg!("unrecognized sub-command \"{}\"", unknown);
+                    err_msg!("unrecognized sub-command \"{}\"", unknown)
                 }
                 None => {
                     // No sub-command provided.
@@ -67,6 +72,7 @@ fn make_command() -> Command {
         .rubbl_notify_args()
         .subcommand(flagts::make_command())
         .subcommand(peel::make_command())
+        .subcommand(predict::make_command())
         .subcommand(spwglue::make_command())
         .subcommand(make_show_command())
 }
@@ -120,7 +126,7 @@ pub fn do_show_cli(matches: &ArgMatches, nbe: &mut dyn NotificationBackend) -> R
         }
 
         Some((unknown, _)) => {
-            return err_msg!("unrecognized sub-command \"{}\"", unknown);
+            err_msg!("unrecognized sub-command \"{}\"", unknown)
         }
 
         None => {
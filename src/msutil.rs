@@ -0,0 +1,233 @@
+// Copyright 2017-2022 Peter Williams <peter@newton.cx> and collaborators
+// Licensed under the MIT License.
+
+//! Helpers for working with the structure of CASA Measurement Sets that are
+//! shared between the various subcommands: their DATA_DESCRIPTION and
+//! POLARIZATION metadata, the TIME reference frame, and the flag versions
+//! maintained by CASA's `flagmanager`.
+
+use rubbl_casatables::{
+    GlueDataType, Table, TableCreateMode, TableDesc, TableDescCreateMode, TableOpenMode,
+    TableRecord,
+};
+use rubbl_core::{
+    anyhow::{self, Result},
+    ctry,
+};
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+/// The spectral window, number of channels, and correlation types
+/// associated with each DATA_DESC_ID.
+pub struct DataDescInfo {
+    pub spw_id: i32,
+    pub num_chan: usize,
+    pub corr_types: Vec<i32>,
+}
+
+/// Get a description of the reference frame of the TIME column of the main
+/// table, for the benefit of downstream consumers.
+pub fn time_reference(table: &mut Table) -> String {
+    let frame = table
+        .get_column_keyword_record("TIME")
+        .ok()
+        .and_then(|mut kws| kws.get_field::<TableRecord>("MEASINFO").ok())
+        .and_then(|mut mi| mi.get_field::<String>("Ref").ok())
+        .unwrap_or_else(|| "UTC".to_owned());
+
+    format!("seconds since MJD 0.0 ({})", frame)
+}
+
+/// Open a table, or one of its sub-tables if `extension` is nonempty.
+pub fn open_table(base: &Path, extension: &str, is_input: bool) -> Result<(PathBuf, Table)> {
+    let mut p = base.to_owned();
+
+    if !extension.is_empty() {
+        p.push(extension);
+    }
+
+    let mode = if is_input {
+        TableOpenMode::Read
+    } else {
+        TableOpenMode::ReadWrite
+    };
+
+    let t = ctry!(Table::open(&p, mode);
+                  "failed to open {} {}table \"{}\"",
+                  if is_input { "input" } else { "output" },
+                  if !extension.is_empty() { "sub-" } else { "" },
+                  p.display()
+    );
+
+    Ok((p, t))
+}
+
+/// Get the path of the directory in which CASA stores the flag versions of
+/// the data set at `inpath`.
+pub fn flag_versions_dir(inpath: &Path) -> PathBuf {
+    let mut name = inpath.file_name().unwrap_or_default().to_owned();
+    name.push(".flagversions");
+    inpath.with_file_name(name)
+}
+
+/// List the names of the flag versions of the data set at `inpath`, as
+/// recorded in the FLAG_VERSION_LIST file maintained by CASA. Each line of
+/// the file has the form "<name> : <comment>".
+pub fn list_flag_versions(inpath: &Path) -> Result<Vec<String>> {
+    let list_path = flag_versions_dir(inpath).join("FLAG_VERSION_LIST");
+    let text = ctry!(
+        std::fs::read_to_string(&list_path);
+        "failed to read flag version list \"{}\"", list_path.display()
+    );
+
+    let names = text
+        .lines()
+        .filter_map(|line| line.split(" : ").next())
+        .map(|name| name.trim())
+        .filter(|name| !name.is_empty())
+        .map(|name| name.to_owned())
+        .collect::<Vec<_>>();
+
+    if names.is_empty() {
+        return err_msg!("no flag versions are listed in \"{}\"", list_path.display());
+    }
+
+    Ok(names)
+}
+
+/// Create a new flag version of the data set at `inpath`, in the style of
+/// CASA's `flagmanager`, and register it in FLAG_VERSION_LIST. The new table
+/// has FLAG and FLAG_ROW columns and as many rows as the main table, but its
+/// cells are left for the caller to fill in. Its FLAG column is modeled on
+/// the main column `flag_column`.
+pub fn create_flag_version(
+    inpath: &Path,
+    main_table: &mut Table,
+    flag_column: &str,
+    name: &str,
+    comment: &str,
+) -> Result<(PathBuf, Table)> {
+    let dir = flag_versions_dir(inpath);
+    let path = dir.join(format!("flags.{}", name));
+
+    if path.exists() {
+        return err_msg!("flag version \"{}\" already exists", path.display());
+    }
+
+    ctry!(
+        std::fs::create_dir_all(&dir);
+        "failed to create directory \"{}\"", dir.display()
+    );
+
+    let flag_desc = ctry!(
+        main_table.get_col_desc(flag_column);
+        "failed to get the description of the {} column of \"{}\"", flag_column, inpath.display()
+    );
+
+    let mut td = TableDesc::new("", TableDescCreateMode::TDM_SCRATCH)?;
+    td.add_array_column(
+        GlueDataType::TpBool,
+        "FLAG",
+        None,
+        flag_desc.shape(),
+        false,
+        false,
+    )?;
+    td.add_scalar_column(GlueDataType::TpBool, "FLAG_ROW", None, false, false)?;
+
+    let table = ctry!(
+        Table::new(&path, td, main_table.n_rows() as usize, TableCreateMode::New);
+        "failed to create flag version table \"{}\"", path.display()
+    );
+
+    let list_path = dir.join("FLAG_VERSION_LIST");
+    let mut list = ctry!(
+        std::fs::OpenOptions::new().create(true).append(true).open(&list_path);
+        "failed to open flag version list \"{}\"", list_path.display()
+    );
+    ctry!(
+        writeln!(list, "{} : {}", name, comment);
+        "failed to write flag version list \"{}\"", list_path.display()
+    );
+
+    Ok((path, table))
+}
+
+/// Load the DATA_DESCRIPTION information for the data set at `inpath`,
+/// indexed by DATA_DESC_ID.
+pub fn load_data_desc_info(inpath: &Path) -> Result<Vec<DataDescInfo>> {
+    let (_, mut in_pol_table) = open_table(inpath, "POLARIZATION", true)?;
+    let mut corr_types = Vec::new();
+
+    for i in 0..in_pol_table.n_rows() {
+        corr_types.push(in_pol_table.get_cell_as_vec::<i32>("CORR_TYPE", i)?);
+    }
+
+    let (_, mut in_spw_table) = open_table(inpath, "SPECTRAL_WINDOW", true)?;
+    let num_chans = in_spw_table.get_col_as_vec::<i32>("NUM_CHAN")?;
+
+    let (in_dd_path, mut in_dd_table) = open_table(inpath, "DATA_DESCRIPTION", true)?;
+    let spw_ids = in_dd_table.get_col_as_vec::<i32>("SPECTRAL_WINDOW_ID")?;
+    let pol_ids = in_dd_table.get_col_as_vec::<i32>("POLARIZATION_ID")?;
+    let mut ddinfo = Vec::with_capacity(spw_ids.len());
+
+    for (spw_id, pol_id) in spw_ids.into_iter().zip(pol_ids) {
+        let corr_types = match corr_types.get(pol_id as usize) {
+            Some(c) => c.clone(),
+            None => {
+                return err_msg!(
+                    "sub-table \"{}\" refers to nonexistent POLARIZATION_ID {}",
+                    in_dd_path.display(),
+                    pol_id
+                );
+            }
+        };
+
+        let num_chan = match num_chans.get(spw_id as usize) {
+            Some(n) => *n as usize,
+            None => {
+                return err_msg!(
+                    "sub-table \"{}\" refers to nonexistent SPECTRAL_WINDOW_ID {}",
+                    in_dd_path.display(),
+                    spw_id
+                );
+            }
+        };
+
+        ddinfo.push(DataDescInfo {
+            spw_id,
+            num_chan,
+            corr_types,
+        });
+    }
+
+    Ok(ddinfo)
+}
+
+/// Load the phase centers of the fields of the data set at `inpath`, as
+/// (longitude, latitude) pairs in radians, indexed by FIELD_ID.
+pub fn load_field_directions(inpath: &Path) -> Result<Vec<(f64, f64)>> {
+    let (field_path, mut field_table) = open_table(inpath, "FIELD", true)?;
+    let mut dirs = Vec::with_capacity(field_table.n_rows() as usize);
+
+    for i in 0..field_table.n_rows() {
+        let phase_dir = ctry!(
+            field_table.get_cell_as_vec::<f64>("PHASE_DIR", i);
+            "failed to read PHASE_DIR of field {} from \"{}\"", i, field_path.display()
+        );
+
+        if phase_dir.len() < 2 {
+            return err_msg!(
+                "PHASE_DIR of field {} in \"{}\" does not contain a direction",
+                i,
+                field_path.display()
+            );
+        }
+
+        dirs.push((phase_dir[0], phase_dir[1]));
+    }
+
+    Ok(dirs)
+}
@@ -0,0 +1,353 @@
+// Copyright 2017-2022 Peter Williams <peter@newton.cx> and collaborators
+// Licensed under the MIT License.
+
+//! Quick .npy file reading and writing, stealing work from the `npy` crate
+//! version 0.3.2.
+
+use byteorder::{ByteOrder, LittleEndian, ReadBytesExt, WriteBytesExt};
+use ndarray::{Array, Dimension};
+use nom::{
+    branch::alt,
+    bytes::complete::tag,
+    character::complete::{char, i64, multispace0, satisfy},
+    combinator::{map, opt},
+    multi::{many1, separated_list0},
+    sequence::{delimited, separated_pair},
+    IResult, Parser,
+};
+use rubbl_core::{anyhow::Result, num::DimFromShapeSlice};
+use std::collections::HashMap;
+use std::io::{Read, Write};
+
+#[derive(PartialEq, Eq, Debug)]
+enum LimitedPyLiteral {
+    String(String),
+    Integer(i64),
+    Bool(bool),
+    List(Vec<LimitedPyLiteral>),
+    Map(HashMap<String, LimitedPyLiteral>),
+}
+
+pub fn npy_stream_to_ndarray<R: Read, D: Dimension + DimFromShapeSlice<u64>>(
+    stream: &mut R,
+) -> Result<Array<f64, D>> {
+    let mut preamble = [0u8; 10];
+
+    stream.read_exact(&mut preamble)?;
+
+    if &preamble[..8] != b"\x93NUMPY\x01\x00" {
+        return err_msg!("stream does not appear to be NPY-format save data");
+    }
+
+    // Because header_len is only a u16, it can't be absurdly large even
+    // in a maliciously-constructed file.
+    //
+    // The Python header is padded such that the data start at a multiple
+    // of 16 bytes into the file. If the un-padded header length is a
+    // total of X bytes, we can calculate the padded length as ((X + 15) /
+    // 16) * 16 with standard truncating integer division. We've already
+    // read 10 bytes though, so we need to account for those in the total
+    // length as well as for the length that remains to be read.
+    let header_len = LittleEndian::read_u16(&preamble[8..]);
+    let aligned_len = (header_len as usize + 10).div_ceil(16) * 16 - 10;
+
+    let mut header = vec![0; aligned_len];
+    stream.read_exact(&mut header[..])?;
+
+    let endpos = header
+        .iter()
+        .position(|&c| c == b'\0')
+        .unwrap_or(aligned_len);
+
+    let header = match std::str::from_utf8(&header[..endpos]) {
+        Ok(h) => h,
+
+        Err(e) => {
+            return err_msg!("failed to convert NPY Python header into text: {}", e);
+        }
+    };
+
+    let pyinfo = match limited_py_literal(header) {
+        Ok((_, info)) => info,
+
+        Err(e) => {
+            return err_msg!("failed to parse NPY Python header: {}", e);
+        }
+    };
+
+    let pyinfo = match pyinfo {
+        LimitedPyLiteral::Map(m) => m,
+        other => {
+            return err_msg!(
+                "bad NPY Python header: expected toplevel map but got {:?}",
+                other
+            );
+        }
+    };
+
+    let descr = match pyinfo.get("descr") {
+        Some(LimitedPyLiteral::String(s)) => s,
+        other => {
+            return err_msg!(
+                "bad NPY Python header: expected string item \"descr\" but got {:?}",
+                other
+            );
+        }
+    };
+
+    let fortran_order = match pyinfo.get("fortran_order") {
+        Some(&LimitedPyLiteral::Bool(b)) => b,
+        other => {
+            return err_msg!(
+                "bad NPY Python header: expected bool item \"fortran_order\" but got {:?}",
+                other
+            );
+        }
+    };
+
+    let py_shape = match pyinfo.get("shape") {
+        Some(LimitedPyLiteral::List(ell)) => ell,
+        other => {
+            return err_msg!(
+                "bad NPY Python header: expected list item \"shape\" but got {:?}",
+                other
+            );
+        }
+    };
+
+    // We could support more choices here ...
+    if descr != "<f8" {
+        return err_msg!(
+            "unsupported NPY file: data type must be little-endian \
+             f64 (\"<f8\") but got \"{}\"",
+            descr
+        );
+    }
+
+    // ... and here.
+    if fortran_order {
+        return err_msg!("unsupported NPY file: data ordering must be C, but got Fortran");
+    }
+
+    let mut shape = Vec::new();
+
+    for py_shape_item in py_shape {
+        match py_shape_item {
+            &LimitedPyLiteral::Integer(i) => {
+                shape.push(i as u64);
+            }
+            other => {
+                return err_msg!(
+                    "bad NPY Python header: expected \"shape\" to be all integers but got {:?}",
+                    other
+                );
+            }
+        }
+    }
+
+    let mut arr = Array::uninit(D::from_shape_slice(&shape)?);
+
+    // Note: we "should" probably use a BufReader here, but the
+    // performance of this bit is totally insignificant in the grand
+    // scheme of things.
+
+    for item in arr.iter_mut() {
+        *item = std::mem::MaybeUninit::new(stream.read_f64::<LittleEndian>()?);
+    }
+
+    Ok(unsafe { arr.assume_init() })
+}
+
+/// Write an array to a stream in NPY format.
+///
+/// As with reading, we only support little-endian f64 data in C order. We
+/// write version 1.0 files, padding the header so that the data start at a
+/// multiple of 16 bytes into the file.
+pub fn ndarray_to_npy_stream<W: Write, D: Dimension>(
+    arr: &Array<f64, D>,
+    stream: &mut W,
+) -> Result<()> {
+    let shape = match arr.shape() {
+        [n] => format!("({},)", n),
+        dims => format!(
+            "({})",
+            dims.iter()
+                .map(|d| d.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    };
+
+    let mut header = format!(
+        "{{'descr': '<f8', 'fortran_order': False, 'shape': {}, }}",
+        shape
+    );
+
+    // The header is terminated with a newline, and the total length of the
+    // preamble plus header must be a multiple of 16.
+    let unpadded_len = 10 + header.len() + 1;
+    let padded_len = unpadded_len.div_ceil(16) * 16;
+
+    for _ in unpadded_len..padded_len {
+        header.push(' ');
+    }
+
+    header.push('\n');
+
+    if header.len() > u16::MAX as usize {
+        return err_msg!("NPY header too long for format version 1.0");
+    }
+
+    stream.write_all(b"\x93NUMPY\x01\x00")?;
+    stream.write_u16::<LittleEndian>(header.len() as u16)?;
+    stream.write_all(header.as_bytes())?;
+
+    for item in arr.iter() {
+        stream.write_f64::<LittleEndian>(*item)?;
+    }
+
+    Ok(())
+}
+
+fn limited_py_literal(input: &str) -> IResult<&str, LimitedPyLiteral> {
+    alt((
+        integer_literal,
+        boolean_literal,
+        braindead_string_literal,
+        listlike_literal,
+        braindead_map_literal,
+    ))
+    .parse(input)
+}
+
+fn integer_literal(input: &str) -> IResult<&str, LimitedPyLiteral> {
+    map(delimited(multispace0, i64, multispace0), |x: i64| {
+        LimitedPyLiteral::Integer(x)
+    })
+    .parse(input)
+}
+
+fn boolean_literal(input: &str) -> IResult<&str, LimitedPyLiteral> {
+    delimited(
+        multispace0,
+        alt((
+            map(tag("True"), |_| LimitedPyLiteral::Bool(true)),
+            map(tag("False"), |_| LimitedPyLiteral::Bool(false)),
+        )),
+        multispace0,
+    )
+    .parse(input)
+}
+
+/// This is "braindead" because we don't handle escapes at all, nor many
+/// other facets of real Python string syntax. This is all we need for .npy
+/// files, though.
+fn braindead_string_text(input: &str) -> IResult<&str, String> {
+    // This is wildly inefficient since we're buffering up invididual
+    // characters in Vec rather than just using the input slice, but for
+    // these purposes I can't be bothered to do better.
+    map(
+        delimited(
+            multispace0,
+            alt((
+                delimited(char('\"'), many1(satisfy(|c| c != '\"')), char('\"')),
+                delimited(char('\''), many1(satisfy(|c| c != '\'')), char('\'')),
+            )),
+            multispace0,
+        ),
+        |chars| chars.iter().collect(),
+    )
+    .parse(input)
+}
+
+fn braindead_string_literal(input: &str) -> IResult<&str, LimitedPyLiteral> {
+    map(braindead_string_text, LimitedPyLiteral::String).parse(input)
+}
+
+/// Note that we do not distinguish between tuples and lists.
+fn listlike_literal(input: &str) -> IResult<&str, LimitedPyLiteral> {
+    map(
+        delimited(
+            multispace0,
+            alt((
+                delimited(
+                    char('['),
+                    separated_list0(char(','), limited_py_literal),
+                    (opt(char(',')), multispace0, char(']')),
+                ),
+                delimited(
+                    char('('),
+                    separated_list0(char(','), limited_py_literal),
+                    (opt(char(',')), multispace0, char(')')),
+                ),
+            )),
+            multispace0,
+        ),
+        LimitedPyLiteral::List,
+    )
+    .parse(input)
+}
+
+/// Note that we only allow string keys.
+fn braindead_map_literal(input: &str) -> IResult<&str, LimitedPyLiteral> {
+    map(
+        delimited(
+            multispace0,
+            delimited(
+                char('{'),
+                separated_list0(
+                    char(','),
+                    separated_pair(braindead_string_text, char(':'), limited_py_literal),
+                ),
+                (opt(char(',')), multispace0, char('}')),
+            ),
+            multispace0,
+        ),
+        |items| LimitedPyLiteral::Map(items.into_iter().collect()),
+    )
+    .parse(input)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::{Ix1, Ix2};
+
+    #[test]
+    fn write_header() {
+        let arr = Array::from_vec(vec![1.5, -2.]);
+        let mut buf = Vec::new();
+        ndarray_to_npy_stream(&arr, &mut buf).unwrap();
+
+        assert_eq!(&buf[..8], b"\x93NUMPY\x01\x00");
+        let header_len = LittleEndian::read_u16(&buf[8..10]) as usize;
+        let data_start = 10 + header_len;
+        assert_eq!(data_start % 16, 0);
+        assert_eq!(buf.len(), data_start + 16);
+
+        let header = std::str::from_utf8(&buf[10..data_start]).unwrap();
+        assert!(header.starts_with("{'descr': '<f8', 'fortran_order': False, 'shape': (2,), }"));
+        assert!(header.ends_with('\n'));
+        assert_eq!(LittleEndian::read_f64(&buf[data_start..]), 1.5);
+    }
+
+    #[test]
+    fn round_trip_1d() {
+        let arr = Array::from_vec(vec![0., 1., f64::INFINITY, -3.25]);
+        let mut buf = Vec::new();
+        ndarray_to_npy_stream(&arr, &mut buf).unwrap();
+
+        let back: Array<f64, Ix1> = npy_stream_to_ndarray(&mut &buf[..]).unwrap();
+        assert_eq!(back, arr);
+    }
+
+    #[test]
+    fn round_trip_2d() {
+        let arr = Array::from_shape_fn((3, 5), |(i, j)| (10 * i + j) as f64);
+        let mut buf = Vec::new();
+        ndarray_to_npy_stream(&arr, &mut buf).unwrap();
+
+        let back: Array<f64, Ix2> = npy_stream_to_ndarray(&mut &buf[..]).unwrap();
+        assert_eq!(back, arr);
+    }
+}
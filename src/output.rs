@@ -0,0 +1,357 @@
+// Copyright 2017-2022 Peter Williams <peter@newton.cx> and collaborators
+// Licensed under the MIT License.
+
+//! Code for writing out tables of results in various formats. This is used
+//! by the subcommands that produce tabular diagnostics, such as `flagts` and
+//! the `peel --report` option.
+
+use itertools::Itertools;
+use ndarray::Ix2;
+use rubbl_core::{
+    anyhow::{self, Result},
+    ctry, Array,
+};
+use std::{
+    fs::File,
+    io::{self, Write},
+    path::Path,
+    str::FromStr,
+};
+
+use crate::npy::ndarray_to_npy_stream;
+
+/// The supported output formats.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum OutputFormat {
+    /// Whitespace-separated columns, as traditionally emitted by this
+    /// tool.
+    Text,
+
+    /// Comma-separated values with a header row. When written to a file,
+    /// the metadata go in a JSON sidecar file.
+    Csv,
+
+    /// A JSON document including metadata.
+    Json,
+
+    /// A 2D NPY array, with metadata in a JSON sidecar file.
+    Npy,
+}
+
+impl FromStr for OutputFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "text" => Ok(OutputFormat::Text),
+            "csv" => Ok(OutputFormat::Csv),
+            "json" => Ok(OutputFormat::Json),
+            "npy" => Ok(OutputFormat::Npy),
+            other => err_msg!("unrecognized output format \"{}\"", other),
+        }
+    }
+}
+
+/// One value in a table of results.
+#[derive(Clone, Debug)]
+pub enum Value {
+    Int(i64),
+    Float(f64),
+    Text(String),
+}
+
+impl Value {
+    fn as_f64(&self) -> Option<f64> {
+        match *self {
+            Value::Int(i) => Some(i as f64),
+            Value::Float(f) => Some(f),
+            Value::Text(_) => None,
+        }
+    }
+
+    fn to_text(&self) -> String {
+        match self {
+            Value::Int(i) => i.to_string(),
+            Value::Float(f) => format!("{:.16e}", f),
+            Value::Text(t) => t.clone(),
+        }
+    }
+
+    fn to_json(&self) -> String {
+        match self {
+            Value::Int(i) => i.to_string(),
+            Value::Float(f) if f.is_finite() => format!("{:.16e}", f),
+            Value::Float(_) => "null".to_owned(),
+            Value::Text(t) => json_string(t),
+        }
+    }
+}
+
+/// A column in a table of results.
+#[derive(Clone, Debug)]
+pub struct Column {
+    pub name: String,
+    pub description: String,
+}
+
+impl Column {
+    pub fn new<S1: ToString, S2: ToString>(name: S1, description: S2) -> Self {
+        Column {
+            name: name.to_string(),
+            description: description.to_string(),
+        }
+    }
+}
+
+/// A table of results, along with the metadata needed to interpret it.
+#[derive(Clone, Debug)]
+pub struct ResultTable {
+    /// The path of the data set that was analyzed.
+    pub dataset: String,
+
+    /// What kind of analysis produced the table.
+    pub mode: &'static str,
+
+    /// The reference frame of the TIME column in the data set.
+    pub time_reference: String,
+
+    /// The CASA flag version that was analyzed, if not the main flags.
+    pub flag_version: Option<String>,
+
+    pub columns: Vec<Column>,
+    pub rows: Vec<Vec<Value>>,
+
+    /// Whether to print the column names as a comment line in the text
+    /// format. For historical compatibility, the basic time series
+    /// doesn't.
+    pub text_header: bool,
+}
+
+impl ResultTable {
+    pub fn new(dataset: &Path, mode: &'static str, time_reference: String) -> Self {
+        ResultTable {
+            dataset: dataset.display().to_string(),
+            mode,
+            time_reference,
+            flag_version: None,
+            columns: Vec::new(),
+            rows: Vec::new(),
+            text_header: true,
+        }
+    }
+
+    /// Emit the table in the specified format. If `dest` is None, the
+    /// output goes to standard output.
+    pub fn emit(&self, format: OutputFormat, dest: Option<&Path>) -> Result<()> {
+        // CSV and NPY files have no standard way to carry metadata, so they
+        // go alongside them.
+
+        let sidecar = match (format, dest) {
+            (OutputFormat::Csv, Some(d)) | (OutputFormat::Npy, Some(d)) => {
+                let meta_path = d.with_extension("json");
+
+                if meta_path == d {
+                    return err_msg!(
+                        "the metadata of \"{}\" would overwrite it; use a different file extension",
+                        d.display()
+                    );
+                }
+
+                Some(meta_path)
+            }
+
+            _ => None,
+        };
+
+        if format == OutputFormat::Npy {
+            let dest = match dest {
+                Some(d) => d,
+                None => {
+                    return err_msg!("an output path must be specified for NPY output");
+                }
+            };
+
+            let mut arr = Array::<f64, Ix2>::zeros((self.rows.len(), self.columns.len()));
+
+            for (mut arr_row, row) in arr.outer_iter_mut().zip(self.rows.iter()) {
+                for ((a, v), col) in arr_row.iter_mut().zip(row.iter()).zip(&self.columns) {
+                    *a = match v.as_f64() {
+                        Some(f) => f,
+                        None => {
+                            return err_msg!(
+                                "column \"{}\" contains text, which can't be written in NPY format",
+                                col.name
+                            );
+                        }
+                    };
+                }
+            }
+
+            let mut f = io::BufWriter::new(ctry!(
+                File::create(dest);
+                "failed to create output file \"{}\"", dest.display()
+            ));
+            ctry!(
+                ndarray_to_npy_stream(&arr, &mut f);
+                "failed to write output file \"{}\"", dest.display()
+            );
+            ctry!(
+                f.flush();
+                "failed to write output file \"{}\"", dest.display()
+            );
+
+            return self.write_sidecar(sidecar.as_deref());
+        }
+
+        let mut stream: Box<dyn Write> = match dest {
+            Some(d) => Box::new(io::BufWriter::new(ctry!(
+                File::create(d);
+                "failed to create output file \"{}\"", d.display()
+            ))),
+            None => Box::new(io::BufWriter::new(io::stdout())),
+        };
+
+        match format {
+            OutputFormat::Text => self.write_text(&mut stream)?,
+            OutputFormat::Csv => self.write_csv(&mut stream)?,
+            OutputFormat::Json => self.write_json(&mut stream, true)?,
+            OutputFormat::Npy => unreachable!(),
+        }
+
+        stream.flush()?;
+        self.write_sidecar(sidecar.as_deref())
+    }
+
+    /// Write the metadata of the table, without its data, to a JSON file, if
+    /// one is given.
+    fn write_sidecar(&self, meta_path: Option<&Path>) -> Result<()> {
+        let meta_path = match meta_path {
+            Some(p) => p,
+            None => return Ok(()),
+        };
+
+        let mut f = io::BufWriter::new(ctry!(
+            File::create(meta_path);
+            "failed to create output file \"{}\"", meta_path.display()
+        ));
+        ctry!(
+            self.write_json(&mut f, false);
+            "failed to write output file \"{}\"", meta_path.display()
+        );
+        ctry!(
+            f.flush();
+            "failed to write output file \"{}\"", meta_path.display()
+        );
+        Ok(())
+    }
+
+    fn write_text<W: Write>(&self, stream: &mut W) -> Result<()> {
+        if let Some(v) = &self.flag_version {
+            writeln!(stream, "# flag_version: {}", v)?;
+        }
+
+        if self.text_header {
+            writeln!(
+                stream,
+                "# {}",
+                self.columns.iter().map(|c| &c.name).join(" ")
+            )?;
+        }
+
+        for row in &self.rows {
+            writeln!(stream, "{}", row.iter().map(|v| v.to_text()).join(" "))?;
+        }
+
+        Ok(())
+    }
+
+    fn write_csv<W: Write>(&self, stream: &mut W) -> Result<()> {
+        writeln!(
+            stream,
+            "{}",
+            self.columns.iter().map(|c| csv_field(&c.name)).join(",")
+        )?;
+
+        for row in &self.rows {
+            writeln!(stream, "{}", row.iter().map(|v| v.to_text()).join(","))?;
+        }
+
+        Ok(())
+    }
+
+    fn write_json<W: Write>(&self, stream: &mut W, with_data: bool) -> Result<()> {
+        writeln!(stream, "{{")?;
+        writeln!(stream, "  \"dataset\": {},", json_string(&self.dataset))?;
+        writeln!(stream, "  \"mode\": {},", json_string(self.mode))?;
+        writeln!(
+            stream,
+            "  \"time_reference\": {},",
+            json_string(&self.time_reference)
+        )?;
+
+        if let Some(v) = &self.flag_version {
+            writeln!(stream, "  \"flag_version\": {},", json_string(v))?;
+        }
+
+        writeln!(stream, "  \"columns\": [")?;
+
+        for (i, col) in self.columns.iter().enumerate() {
+            writeln!(
+                stream,
+                "    {{\"name\": {}, \"description\": {}}}{}",
+                json_string(&col.name),
+                json_string(&col.description),
+                if i + 1 < self.columns.len() { "," } else { "" }
+            )?;
+        }
+
+        if !with_data {
+            writeln!(stream, "  ]")?;
+        } else {
+            writeln!(stream, "  ],")?;
+            writeln!(stream, "  \"data\": [")?;
+
+            for (i, row) in self.rows.iter().enumerate() {
+                writeln!(
+                    stream,
+                    "    [{}]{}",
+                    row.iter().map(|v| v.to_json()).join(", "),
+                    if i + 1 < self.rows.len() { "," } else { "" }
+                )?;
+            }
+
+            writeln!(stream, "  ]")?;
+        }
+
+        writeln!(stream, "}}")?;
+        Ok(())
+    }
+}
+
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_owned()
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut r = String::with_capacity(s.len() + 2);
+    r.push('"');
+
+    for c in s.chars() {
+        match c {
+            '"' => r.push_str("\\\""),
+            '\\' => r.push_str("\\\\"),
+            '\n' => r.push_str("\\n"),
+            '\r' => r.push_str("\\r"),
+            '\t' => r.push_str("\\t"),
+            c if (c as u32) < 0x20 => r.push_str(&format!("\\u{:04x}", c as u32)),
+            c => r.push(c),
+        }
+    }
+
+    r.push('"');
+    r
+}
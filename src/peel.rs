@@ -7,7 +7,8 @@
 //! outline is this:
 //!
 //! 1. Some first-cut calibrated data are stored in "main.ms" in the (main)
-//!    DATA column.
+//!    DATA column. Single-dish data sets store theirs in a real-valued
+//!    FLOAT_DATA column, which is used instead if DATA is missing.
 //! 2. You image the data, yielding a CLEAN component model. There is a source
 //!    that you want to peel from the data, referred to as "source A". It is
 //!    probably off-axis, since if it were on-axis the standard calibration
@@ -24,19 +25,30 @@
 //!    subtracts MODEL from CORRECTED, so to do this incrementally, you have
 //!    to make sure to delete CORRECTED or reset it to DATA first.)
 //! 6. You use `split` to create work.ms:DATA, equal to main.ms:CORRECTED =
-//!    A_P + N.
+//!    A_P + N. Several sources can be peeled in one pass by splitting out one
+//!    work data set for each of them. The split may select or reorder the
+//!    polarization products, which are matched up by their CORR_TYPE, and
+//!    may drop the autocorrelations if `--autocorrelations` is used.
 //! 7. You use fill work.ms:MODEL with an idealized model of source A. This
 //!    could be done by using CASA's component-list routines and then the `ft`
-//!    task again. Write work.ms:MODEL = A_I, the idealized signal from source
-//!    A.
+//!    task again, or, if the source can be described by point and Gaussian
+//!    components, by the `predict` command of this tool. Write work.ms:MODEL =
+//!    A_I, the idealized signal from source A.
 //! 8. You use `gaincal` to solve for calibration gains to correct the
-//!    direction-dependent effects associated with source A.
+//!    direction-dependent effects associated with source A. This is much
+//!    faster if the work data set is averaged in time and frequency, in which
+//!    case you should use `--interpolate` and load a full-resolution model of
+//!    source A into a column of main.ms given by `--model-column`.
 //! 9. Use `applycal` to fill in work.ms:CORRECTED with the best approximation
 //!    of work.ms:MODEL that can be obtained through the calibration fit,
 //!    which we assume is multiplicative:
 //!    work.ms:CORRECTED = G*work.ms:DATA = G(A_P + N) ~= A_I,
 //!    where the final equality is in some least-squares-type sense. Therefore
 //!    A_P ~= A_I / G ~= work.ms:MODEL * work.ms:DATA / work.ms:CORRECTED.
+//!    This step can be skipped by passing the calibration table(s) from step
+//!    8 to this tool with `--caltable`, along with a `--model-column` of
+//!    main.ms holding A_I. CASA's gains g_i multiply the true visibilities,
+//!    so G = 1/(g_i g_j*), and A_P = A_I g_i g_j*.
 //! 10. Finally, this tool comes into play. We'll want to subtract A_P out of
 //!     the main.ms data or otherwise model it. We can do this by inserting
 //!     the correct DD-perturbed model and then running uvsub:
@@ -44,23 +56,388 @@
 //!     main.ms:MODEL = work.ms:MODEL * work.ms:DATA / work.ms:CORRECTED
 //!
 //!     Or we can add to main.ms:MODEL to allow incremental change. This tool
-//!     iterates over the two datasets and performs this operation.
+//!     iterates over the two datasets and performs this operation. Rows are
+//!     matched up by their identifying metadata (time, antennas,
+//!     DATA_DESC_ID, field, scan, etc.), so the work data set need not
+//!     contain exactly the same rows in the same order. With several work
+//!     data sets, their models are summed and their flags are combined.
+//!
+//!     With `--subtract`, this tool also takes care of the final `uvsub`.
+//!     Options such as `--output-column` change the columns that play each
+//!     role.
+//!
+//!     Samples whose implied gain ratios are wild (see `--min-gain-amp`,
+//!     `--max-gain-amp`, and `--max-gain-sigma`) are flagged, or clipped with
+//!     `--clip-gains`, and main.ms:FLAG is updated unless `--keep-flags` is
+//!     given. With `--update-weights`, the variance of each peeled sample,
+//!     |A_I|^2 / (w |work.ms:CORRECTED|^2) where w is its work weight, is
+//!     added to those implied by main.ms:WEIGHT, WEIGHT_SPECTRUM, and SIGMA.
+//!
+//!     Use `--report` to check the gain ratios without imaging, and
+//!     `--dry-run` to see what would change. `--backup NAME` saves what is
+//!     overwritten so that `--restore NAME` can roll it back. The main table
+//!     is written in journaled chunks, so an interrupted peel can be
+//!     continued with `--resume`.
 
-use clap::{value_parser, Arg, ArgAction, ArgMatches, Command};
-use ndarray::{Ix2, Zip};
-use pbr;
-use rubbl_casatables::{CasaDataType, Table, TableOpenMode, TableRow};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use clap::{parser::ValueSource, value_parser, Arg, ArgAction, ArgMatches, Command};
+use itertools::Itertools;
+use ndarray::{Dimension, Ix1, Ix2, IxDyn, Zip};
+use rubbl_casatables::{CasaDataType, GlueDataType, Table, TableOpenMode, TableRow};
 use rubbl_core::{
     anyhow::{self, Result},
     ctry,
     notify::NotificationBackend,
-    rn_fatal, Array, Complex,
+    rn_fatal, rn_note, rn_warning, Array, Complex,
 };
 use std::{
     self,
+    collections::{hash_map::Entry, BTreeSet, HashMap},
+    f32::consts::PI,
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Write},
     path::{Path, PathBuf},
 };
 
+use crate::{
+    msutil::{
+        create_flag_version, flag_versions_dir, load_data_desc_info, load_field_directions,
+        open_table, time_reference, DataDescInfo,
+    },
+    output::{Column, OutputFormat, ResultTable, Value},
+    spwglue::VisRecordIdentity,
+};
+
+/// Visibility-like data and their flags, each with shape (n_chan, n_pol).
+type VisAndFlags = (Array<Complex<f32>, Ix2>, Array<bool, Ix2>);
+
+/// A gain ratio and its flags, along with its variance if it's known.
+type GainRatio = (VisAndFlags, Option<Array<f32, Ix2>>);
+
+/// The median and robust standard deviation of some gain ratio amplitudes.
+type AmpStats = (f32, f32);
+
+/// The statistics of the gain ratio amplitudes of each polarization product
+/// of a work data set, keyed by (ANTENNA1, ANTENNA2, DATA_DESC_ID). Products
+/// without any unflagged samples have None.
+type GainStats = HashMap<(i32, i32, i32), Vec<Option<AmpStats>>>;
+
+/// Where the direction-dependent gains for a main row come from.
+#[derive(Clone, Copy, Debug)]
+enum GainSource {
+    /// The work row with exactly the same identity.
+    Exact(u64),
+
+    /// Interpolation in time between two work rows, where `frac` is
+    /// the weight of the later one. The two may be the same if the main row
+    /// falls outside of the times covered by the work data set. Main rows
+    /// later than `until` are interpolated from different work rows.
+    Interpolated {
+        before: u64,
+        after: u64,
+        frac: f64,
+        until: f64,
+    },
+
+    /// There is no work data set; the gains come from calibration tables.
+    CalTables,
+}
+
+impl GainSource {
+    /// Find the entries in a time-sorted list of (time, work row number)
+    /// that bracket the specified time.
+    fn bracketing(series: &[(f64, u64)], time: f64) -> Self {
+        let idx = series.partition_point(|(t, _)| *t <= time);
+
+        if idx == 0 {
+            let (t, r) = series[0];
+            GainSource::Interpolated {
+                before: r,
+                after: r,
+                frac: 0.,
+                until: t,
+            }
+        } else if idx == series.len() {
+            let r = series[idx - 1].1;
+            GainSource::Interpolated {
+                before: r,
+                after: r,
+                frac: 0.,
+                until: f64::INFINITY,
+            }
+        } else {
+            let (t0, before) = series[idx - 1];
+            let (t1, after) = series[idx];
+            GainSource::Interpolated {
+                before,
+                after,
+                frac: (time - t0) / (t1 - t0),
+                until: t1,
+            }
+        }
+    }
+}
+
+/// For each correlation product of the main data, the index of the same
+/// product in a work data set, if it has it.
+type PolMap = Vec<Option<usize>>;
+
+/// Work out how to map the correlation products of a work data set onto
+/// those of the main one, given their CORR_TYPE codes. Returns None if they
+/// are the same.
+fn pol_map(main_corr_types: &[i32], work_corr_types: &[i32]) -> Option<PolMap> {
+    if main_corr_types == work_corr_types {
+        return None;
+    }
+
+    Some(
+        main_corr_types
+            .iter()
+            .map(|c| work_corr_types.iter().position(|w| w == c))
+            .collect(),
+    )
+}
+
+/// Interpolate between two complex gains, treating their amplitudes and
+/// phases separately, as CASA's applycal does. `frac` is the weight of `g1`.
+/// The phase is interpolated the short way around the circle.
+fn interpolate_gain(g0: Complex<f32>, g1: Complex<f32>, frac: f32) -> Complex<f32> {
+    let (a0, p0) = g0.to_polar();
+    let (a1, p1) = g1.to_polar();
+    let mut dp = p1 - p0;
+
+    if dp > PI {
+        dp -= 2. * PI;
+    } else if dp < -PI {
+        dp += 2. * PI;
+    }
+
+    Complex::from_polar(a0 + (a1 - a0) * frac, p0 + dp * frac)
+}
+
+/// Rearrange the polarization axis of an array from a work data set to match
+/// the main data, filling in products that the work data lack with `fill`.
+fn map_pols<T: Clone>(a: &Array<T, Ix2>, map: &PolMap, fill: T) -> Array<T, Ix2> {
+    Array::from_shape_fn((a.dim().0, map.len()), |(i_chan, i_pol)| match map[i_pol] {
+        Some(j) => a[(i_chan, j)].clone(),
+        None => fill.clone(),
+    })
+}
+
+/// Check whether a column holds real values, like FLOAT_DATA, rather than
+/// complex ones.
+fn is_real_column(table: &mut Table, path: &Path, col_name: &str) -> Result<bool> {
+    let desc = ctry!(
+        table.get_col_desc(col_name);
+        "failed to get the description of column \"{}\" in \"{}\"", col_name, path.display()
+    );
+    Ok(desc.data_type() == GlueDataType::TpFloat)
+}
+
+fn check_cols(table: &mut Table, path: &Path, wanted_col_names: &[&str]) -> Result<()> {
+    let observed_col_names = ctry!(
+        table.column_names();
+        "failed to get names of columns in \"{}\"", path.display()
+    );
+
+    // Not highly efficient, but N is small here ...
+
+    for wanted_col in wanted_col_names {
+        let mut seen_col = false;
+
+        for n in &observed_col_names {
+            if n == wanted_col {
+                seen_col = true;
+                break;
+            }
+        }
+
+        if !seen_col {
+            return err_msg!(
+                "{} column in table in \"{}\" \
+                is required but missing",
+                wanted_col,
+                path.display()
+            );
+        }
+    }
+
+    Ok(())
+}
+
+fn has_column(table: &mut Table, path: &Path, col_name: &str) -> Result<bool> {
+    let col_names = ctry!(
+        table.column_names();
+        "failed to get names of columns in \"{}\"", path.display()
+    );
+    Ok(col_names.iter().any(|n| n == col_name))
+}
+
+/// Make sure that a table has a column, creating it with the same
+/// description as another if needed. Returns whether the column was
+/// created. If `dry_run` is true, the column is not actually created.
+fn ensure_column(
+    table: &mut Table,
+    path: &Path,
+    col_name: &str,
+    template_col_name: &str,
+    dry_run: bool,
+) -> Result<bool> {
+    if has_column(table, path, col_name)? {
+        return Ok(false);
+    }
+
+    if dry_run {
+        return Ok(true);
+    }
+
+    let desc = ctry!(
+        table.get_col_desc(template_col_name);
+        "failed to get the description of column \"{}\" in \"{}\"", template_col_name, path.display()
+    );
+
+    ctry!(
+        table.add_array_column(
+            desc.data_type(),
+            col_name,
+            None,
+            desc.shape(),
+            desc.is_fixed_shape(),
+            false
+        );
+        "failed to create column \"{}\" in \"{}\"", col_name, path.display()
+    );
+
+    Ok(true)
+}
+
+/// Single-dish data sets have FLOAT_DATA rather than DATA, so use that if
+/// the data column wasn't specified and DATA is missing.
+fn find_data_column<'a>(
+    table: &mut Table,
+    path: &Path,
+    col_name: &'a str,
+    is_default: bool,
+) -> Result<&'a str> {
+    if is_default && !has_column(table, path, col_name)? && has_column(table, path, "FLOAT_DATA")? {
+        Ok("FLOAT_DATA")
+    } else {
+        Ok(col_name)
+    }
+}
+
+/// A column of visibility-like data. Single-dish data sets store real values
+/// in FLOAT_DATA, so the column may be real, in which case its values are
+/// read with zero imaginary parts and only their real parts are written.
+#[derive(Clone, Copy, Debug)]
+struct VisColumn<'a> {
+    name: &'a str,
+    is_real: bool,
+}
+
+impl<'a> VisColumn<'a> {
+    /// A column that doesn't exist (yet) is taken to be complex.
+    fn new(table: &mut Table, path: &Path, name: &'a str) -> Result<Self> {
+        let col_names = ctry!(
+            table.column_names();
+            "failed to get names of columns in \"{}\"", path.display()
+        );
+
+        let is_real = if col_names.iter().any(|n| n == name) {
+            is_real_column(table, path, name)?
+        } else {
+            false
+        };
+
+        Ok(VisColumn { name, is_real })
+    }
+
+    /// Get the value of the column in a row that has been read into `row`.
+    fn get(
+        &self,
+        row: &mut TableRow,
+        rownum: u64,
+        path: &Path,
+    ) -> Result<Array<Complex<f32>, Ix2>> {
+        if self.is_real {
+            let value: Array<f32, Ix2> = ctry!(
+                row.get_cell(self.name);
+                "failed to read column \"{}\" of row #{} of file \"{}\"", self.name, rownum, path.display()
+            );
+            Ok(value.mapv(Complex::from))
+        } else {
+            Ok(ctry!(
+                row.get_cell(self.name);
+                "failed to read column \"{}\" of row #{} of file \"{}\"", self.name, rownum, path.display()
+            ))
+        }
+    }
+
+    fn put(
+        &self,
+        table: &mut Table,
+        rownum: u64,
+        value: &Array<Complex<f32>, Ix2>,
+        path: &Path,
+    ) -> Result<()> {
+        if self.is_real {
+            ctry!(
+                table.put_cell(self.name, rownum, &value.mapv(|c| c.re));
+                "failed to write column \"{}\" of row #{} of file \"{}\"", self.name, rownum, path.display()
+            );
+        } else {
+            ctry!(
+                table.put_cell(self.name, rownum, value);
+                "failed to write column \"{}\" of row #{} of file \"{}\"", self.name, rownum, path.display()
+            );
+        }
+
+        Ok(())
+    }
+}
+
+/// A work data set, open for reading.
+struct WorkSet<'a> {
+    table: Table,
+    row: TableRow,
+    path: &'a Path,
+    data: VisColumn<'a>,
+    model: VisColumn<'a>,
+    corrected: VisColumn<'a>,
+    flag: &'a str,
+
+    /// The column of weights used to compute the variances of the gain
+    /// ratios, if they're needed.
+    weight_column: Option<&'static str>,
+
+    /// For each main DATA_DESC_ID, how the correlation products of the work
+    /// data map onto the main ones, or None if they're the same.
+    pol_maps: Vec<Option<PolMap>>,
+
+    /// The statistics of the gain ratio amplitudes, if `--max-gain-sigma`
+    /// needs them.
+    gain_stats: GainStats,
+}
+
+impl<'a> WorkSet<'a> {
+    /// Get the gain ratio statistics of a baseline and DATA_DESC_ID for each
+    /// main polarization product, or nothing if there are none.
+    fn gain_stats(&self, ant1: i32, ant2: i32, ddid: i32) -> Vec<Option<AmpStats>> {
+        let stats = match self.gain_stats.get(&(ant1, ant2, ddid)) {
+            Some(s) => s,
+            None => return Vec::new(),
+        };
+
+        match self.pol_maps.get(ddid as usize).and_then(|m| m.as_ref()) {
+            Some(map) => map
+                .iter()
+                .map(|j| j.and_then(|j| stats.get(j).copied().flatten()))
+                .collect(),
+            None => stats.clone(),
+        }
+    }
+}
+
 // Let's get this show on the road.
 
 pub fn make_command() -> Command {
@@ -71,7 +448,314 @@ pub fn make_command() -> Command {
             Arg::new("incremental")
                 .long("incremental")
                 .action(ArgAction::SetTrue)
-                .help("If specified, add the model to main:MODEL_DATA, rather than overwriting"),
+                .help("If specified, add the model to main:MODEL_DATA, rather than overwriting")
+                .long_help(
+                    "If specified, add the model to main:MODEL_DATA (or the column \
+                     given by `--output-column`), rather than overwriting it. \
+                     This has no effect if the column must be created.",
+                ),
+        )
+        .arg(
+            Arg::new("subtract")
+                .long("subtract")
+                .action(ArgAction::SetTrue)
+                .help("Also write the residuals into main:CORRECTED_DATA, creating it if needed")
+                .long_help(
+                    "Also write the residuals into main:CORRECTED_DATA, creating \
+                     the column if it doesn't exist. Normally, CORRECTED_DATA is \
+                     set to DATA - MODEL_DATA. With `--incremental`, the newly \
+                     computed model is subtracted from the existing \
+                     CORRECTED_DATA instead, unless the column had to be created. \
+                     The columns involved can be changed with `--residual-column`, \
+                     `--data-column`, and `--output-column`.",
+                ),
+        )
+        .arg(
+            Arg::new("skip_unmatched")
+                .long("skip-unmatched")
+                .action(ArgAction::SetTrue)
+                .help(
+                    "Leave main rows that have no counterpart in a work data \
+                     set untouched, rather than aborting",
+                )
+                .long_help(
+                    "Leave main rows that have no counterpart in a work data \
+                     set untouched, rather than aborting. With `--subtract` but \
+                     not `--incremental`, they are instead given a zero peeled \
+                     model, so that their residuals are their data rather than \
+                     whatever was left by a previous run.",
+                ),
+        )
+        .arg(
+            Arg::new("autocorrelations")
+                .long("autocorrelations")
+                .value_name("MODE")
+                .value_parser(["peel", "skip", "pass"])
+                .default_value("peel")
+                .help("How to treat the autocorrelation rows of MAIN-TABLE")
+                .long_help(
+                    "How to treat the autocorrelation rows of MAIN-TABLE: \
+                     \"peel\" them like any other row; \"skip\" them, leaving \
+                     them untouched; or \"pass\" them through with a zero peeled \
+                     model, so that their residuals are their data. With \
+                     \"skip\" or \"pass\", the work data sets need not contain \
+                     autocorrelations.",
+                ),
+        )
+        .arg(
+            Arg::new("interpolate")
+                .long("interpolate")
+                .action(ArgAction::SetTrue)
+                .requires("model_column")
+                .help("The work data set is averaged in time and/or frequency")
+                .long_help(
+                    "The work data set is averaged in time and/or frequency \
+                     relative to the main one. The implied gain ratio, \
+                     work:DATA / work:CORRECTED_DATA, is interpolated linearly \
+                     in time, in amplitude and phase separately as CASA does, \
+                     and broadcast in frequency onto the main rows, \
+                     then multiplied by the full-resolution source model taken \
+                     from the main column given by `--model-column`.",
+                ),
+        )
+        .arg(
+            Arg::new("model_column")
+                .long("model-column")
+                .value_name("COLUMN")
+                .action(ArgAction::Append)
+                .help("The column of MAIN-TABLE holding the full-resolution source model")
+                .long_help(
+                    "The column of MAIN-TABLE holding the full-resolution source \
+                     model. When interpolating with multiple work data sets, this \
+                     must be given once for each of them, in the same order.",
+                ),
+        )
+        .arg(
+            Arg::new("min_gain_amp")
+                .long("min-gain-amp")
+                .value_name("AMP")
+                .value_parser(value_parser!(f32))
+                .help("Flag samples where the amplitude of the implied gain ratio is below this"),
+        )
+        .arg(
+            Arg::new("max_gain_amp")
+                .long("max-gain-amp")
+                .value_name("AMP")
+                .value_parser(value_parser!(f32))
+                .help("Flag samples where the amplitude of the implied gain ratio is above this"),
+        )
+        .arg(
+            Arg::new("max_gain_sigma")
+                .long("max-gain-sigma")
+                .value_name("N")
+                .value_parser(value_parser!(f32))
+                .conflicts_with("caltable")
+                .help("Flag samples where the gain ratio amplitude deviates from its baseline's median by N sigma")
+                .long_help(
+                    "Flag samples where the amplitude of the implied gain ratio \
+                     deviates from the median for its baseline, spectral \
+                     window, and polarization product by more than N times the \
+                     robust standard deviation (derived from the median \
+                     absolute deviation). The statistics are gathered while \
+                     the work data sets are matched up with the main one, \
+                     which then requires reading all of their data.",
+                ),
+        )
+        .arg(
+            Arg::new("clip_gains")
+                .long("clip-gains")
+                .action(ArgAction::SetTrue)
+                .help("Clip gain ratio amplitudes to the limits, rather than flagging")
+                .long_help(
+                    "Clip the amplitudes of out-of-range gain ratios to the \
+                     limits, keeping their phases, rather than flagging them. \
+                     Ratios of exactly zero have no phase, so they are still \
+                     flagged if they fall below the minimum.",
+                ),
+        )
+        .arg(
+            Arg::new("keep_flags")
+                .long("keep-flags")
+                .action(ArgAction::SetTrue)
+                .help("Do not modify main:FLAG; flagged samples just get a zero model"),
+        )
+        .arg(
+            Arg::new("update_weights")
+                .long("update-weights")
+                .action(ArgAction::SetTrue)
+                .conflicts_with("caltable")
+                .help("Update main:WEIGHT, WEIGHT_SPECTRUM, and SIGMA to include the noise of the peeled model"),
+        )
+        .arg(
+            Arg::new("dry_run")
+                .long("dry-run")
+                .action(ArgAction::SetTrue)
+                .help("Check the inputs and report what would change, without modifying anything"),
+        )
+        .arg(
+            Arg::new("backup")
+                .long("backup")
+                .value_name("NAME")
+                .conflicts_with("dry_run")
+                .help("Save the data that will be overwritten in a backup with this name")
+                .long_help(
+                    "Save the contents of the column(s) that will be \
+                     overwritten into scratch columns named like \
+                     `MODEL_DATA_BACKUP_<NAME>`, and the flags into a CASA \
+                     flag version named NAME. Use `--restore NAME` to undo \
+                     the peel, which also removes the scratch columns unless \
+                     `--keep-backup` is given.",
+                ),
+        )
+        .arg(
+            Arg::new("restore")
+                .long("restore")
+                .value_name("NAME")
+                .conflicts_with_all([
+                    "WORK-TABLE",
+                    "caltable",
+                    "backup",
+                    "dry_run",
+                    "resume",
+                    "update_weights",
+                    "incremental",
+                    "subtract",
+                    "report",
+                ])
+                .help("Restore MAIN-TABLE from the backup with this name, rather than peeling"),
+        )
+        .arg(
+            Arg::new("keep_backup")
+                .long("keep-backup")
+                .action(ArgAction::SetTrue)
+                .requires("restore")
+                .help("Keep the backup columns after restoring from them, rather than removing them"),
+        )
+        .arg(
+            Arg::new("resume")
+                .long("resume")
+                .action(ArgAction::SetTrue)
+                .conflicts_with("dry_run")
+                .help("Continue a peel that was interrupted, using its checkpoint"),
+        )
+        .arg(
+            Arg::new("chunk_rows")
+                .long("chunk-rows")
+                .value_name("N")
+                .value_parser(value_parser!(usize))
+                .default_value("1000")
+                .help("The number of rows to write between checkpoints"),
+        )
+        .arg(
+            Arg::new("report")
+                .long("report")
+                .value_name("PATH")
+                .value_parser(value_parser!(PathBuf))
+                .help("Write statistics of the implied gain ratios to this file")
+                .long_help(
+                    "Write statistics of the implied direction-dependent gain \
+                     ratios to this file: for each source, timeslot, antenna, \
+                     and spectral window, the median and robust scatter of \
+                     their amplitudes and phases, and the fraction of samples \
+                     flagged. Each antenna's statistics include all of its \
+                     baselines. The metadata of a CSV report are written to \
+                     a JSON file alongside it.",
+                ),
+        )
+        .arg(
+            Arg::new("report_format")
+                .long("report-format")
+                .value_name("FORMAT")
+                .value_parser(["csv", "json"])
+                .default_value("csv")
+                .requires("report")
+                .help("The format of the file written by `--report`"),
+        )
+        .arg(
+            Arg::new("data_column")
+                .long("data-column")
+                .value_name("COLUMN")
+                .default_value("DATA")
+                .help("The column of MAIN-TABLE holding the observed data")
+                .long_help(
+                    "The column of MAIN-TABLE holding the observed data. It is \
+                     only required with `--subtract`, but if it exists, it is \
+                     used as the template for any columns that must be created.",
+                ),
+        )
+        .arg(
+            Arg::new("flag_column")
+                .long("flag-column")
+                .value_name("COLUMN")
+                .default_value("FLAG")
+                .help("The column of MAIN-TABLE holding the flags")
+                .long_help(
+                    "The column of MAIN-TABLE holding the flags, which are read \
+                     and updated by the peel. Use the same value with \
+                     `--restore` to restore flags saved by `--backup`.",
+                ),
+        )
+        .arg(
+            Arg::new("output_column")
+                .long("output-column")
+                .value_name("COLUMN")
+                .default_value("MODEL_DATA")
+                .help("The column of MAIN-TABLE into which to write the peeled model"),
+        )
+        .arg(
+            Arg::new("residual_column")
+                .long("residual-column")
+                .value_name("COLUMN")
+                .default_value("CORRECTED_DATA")
+                .help("The column of MAIN-TABLE into which to write residuals with `--subtract`"),
+        )
+        .arg(
+            Arg::new("work_data_column")
+                .long("work-data-column")
+                .value_name("COLUMN")
+                .default_value("DATA")
+                .help("The column of the work data sets holding the uncalibrated data"),
+        )
+        .arg(
+            Arg::new("work_model_column")
+                .long("work-model-column")
+                .value_name("COLUMN")
+                .default_value("MODEL_DATA")
+                .help("The column of the work data sets holding the idealized source model"),
+        )
+        .arg(
+            Arg::new("work_corrected_column")
+                .long("work-corrected-column")
+                .value_name("COLUMN")
+                .default_value("CORRECTED_DATA")
+                .help("The column of the work data sets holding the calibrated data"),
+        )
+        .arg(
+            Arg::new("work_flag_column")
+                .long("work-flag-column")
+                .value_name("COLUMN")
+                .default_value("FLAG")
+                .help("The column of the work data sets holding the flags"),
+        )
+        .arg(
+            Arg::new("caltable")
+                .long("caltable")
+                .value_name("TABLE")
+                .value_parser(value_parser!(PathBuf))
+                .action(ArgAction::Append)
+                .conflicts_with_all(["WORK-TABLE", "interpolate", "skip_unmatched"])
+                .requires("model_column")
+                .help("Apply the gains in this CASA calibration table, rather than using a work data set")
+                .long_help(
+                    "Take the direction-dependent gains from this CASA calibration \
+                     table, as produced by `gaincal` or `bandpass`, rather than \
+                     from a work data set processed with `applycal`. May be \
+                     repeated, in which case the gains of the tables are \
+                     multiplied. Solutions are applied to rows of the same \
+                     field; fields without solutions use those of the nearest \
+                     field that has them. The source model is taken from the \
+                     main column given by `--model-column`.",
+                ),
         )
         .arg(
             Arg::new("MAIN-TABLE")
@@ -82,192 +766,3168 @@ pub fn make_command() -> Command {
         )
         .arg(
             Arg::new("WORK-TABLE")
-                .help("The path of the data set containing the source model and calibration gains")
+                .help(
+                    "The path of the data set containing the source model and calibration \
+                     gains; may be repeated to peel several sources at once",
+                )
                 .value_parser(value_parser!(PathBuf))
-                .required(true)
+                .num_args(1..)
+                .required_unless_present_any(["caltable", "restore"])
                 .index(2),
         )
 }
 
-pub fn do_cli(matches: &ArgMatches, nbe: &mut dyn NotificationBackend) -> Result<i32> {
-    let mainpath = matches.get_one::<PathBuf>("MAIN-TABLE").unwrap();
-    let workpath = matches.get_one::<PathBuf>("WORK-TABLE").unwrap();
-    let incremental = *matches.get_one::<bool>("incremental").unwrap();
-
-    // Open up tables and do some checking.
+/// The settings of a peel, gathered from the command line.
+struct PeelSettings<'a> {
+    mainpath: &'a Path,
+    workpaths: Vec<&'a Path>,
+    caltable_paths: Vec<&'a PathBuf>,
+    incremental: bool,
+    subtract: bool,
+    skip_unmatched: bool,
+    autocorrelations: &'a str,
+    interpolate: bool,
+    model_columns: Vec<&'a str>,
+    keep_flags: bool,
+    update_weights: bool,
+    dry_run: bool,
+    backup_name: Option<&'a str>,
+    resume: bool,
+    chunk_rows: usize,
+    report_path: Option<&'a PathBuf>,
+    report_format: OutputFormat,
+    limits: GainLimits,
+    flag_column: &'a str,
+    data_column: &'a str,
+    output_column: &'a str,
+    residual_column: &'a str,
+    work_data_column: &'a str,
+    work_model_column: &'a str,
+    work_corrected_column: &'a str,
+    work_flag_column: &'a str,
 
-    fn open_table(base: &Path, readwrite: bool) -> Result<Table> {
-        let p = base.to_owned();
+    /// Whether the main and work data columns were left at their defaults,
+    /// in which case FLOAT_DATA is used if DATA is missing.
+    default_data_columns: (bool, bool),
+}
 
-        let mode = if readwrite {
-            TableOpenMode::ReadWrite
-        } else {
-            TableOpenMode::Read
+impl<'a> PeelSettings<'a> {
+    /// Gather the settings from the command line, checking that they make
+    /// sense together.
+    fn new(matches: &'a ArgMatches) -> Result<Self> {
+        let settings = PeelSettings {
+            mainpath: matches.get_one::<PathBuf>("MAIN-TABLE").unwrap(),
+            workpaths: matches
+                .get_many::<PathBuf>("WORK-TABLE")
+                .unwrap_or_default()
+                .map(|p| p.as_path())
+                .collect(),
+            caltable_paths: matches
+                .get_many::<PathBuf>("caltable")
+                .unwrap_or_default()
+                .collect(),
+            incremental: *matches.get_one::<bool>("incremental").unwrap(),
+            subtract: *matches.get_one::<bool>("subtract").unwrap(),
+            skip_unmatched: *matches.get_one::<bool>("skip_unmatched").unwrap(),
+            autocorrelations: matches
+                .get_one::<String>("autocorrelations")
+                .unwrap()
+                .as_str(),
+            interpolate: *matches.get_one::<bool>("interpolate").unwrap(),
+            model_columns: matches
+                .get_many::<String>("model_column")
+                .unwrap_or_default()
+                .map(|s| s.as_str())
+                .collect(),
+            keep_flags: *matches.get_one::<bool>("keep_flags").unwrap(),
+            update_weights: *matches.get_one::<bool>("update_weights").unwrap(),
+            dry_run: *matches.get_one::<bool>("dry_run").unwrap(),
+            backup_name: matches.get_one::<String>("backup").map(|s| s.as_str()),
+            resume: *matches.get_one::<bool>("resume").unwrap(),
+            chunk_rows: *matches.get_one::<usize>("chunk_rows").unwrap(),
+            report_path: matches.get_one::<PathBuf>("report"),
+            report_format: matches
+                .get_one::<String>("report_format")
+                .unwrap()
+                .parse()?,
+            limits: GainLimits {
+                min_amp: matches.get_one::<f32>("min_gain_amp").copied(),
+                max_amp: matches.get_one::<f32>("max_gain_amp").copied(),
+                max_sigma: matches.get_one::<f32>("max_gain_sigma").copied(),
+                clip: *matches.get_one::<bool>("clip_gains").unwrap(),
+            },
+            flag_column: matches.get_one::<String>("flag_column").unwrap().as_str(),
+            data_column: matches.get_one::<String>("data_column").unwrap().as_str(),
+            output_column: matches.get_one::<String>("output_column").unwrap().as_str(),
+            residual_column: matches
+                .get_one::<String>("residual_column")
+                .unwrap()
+                .as_str(),
+            work_data_column: matches
+                .get_one::<String>("work_data_column")
+                .unwrap()
+                .as_str(),
+            work_model_column: matches
+                .get_one::<String>("work_model_column")
+                .unwrap()
+                .as_str(),
+            work_corrected_column: matches
+                .get_one::<String>("work_corrected_column")
+                .unwrap()
+                .as_str(),
+            work_flag_column: matches
+                .get_one::<String>("work_flag_column")
+                .unwrap()
+                .as_str(),
+            default_data_columns: (
+                matches.value_source("data_column") == Some(ValueSource::DefaultValue),
+                matches.value_source("work_data_column") == Some(ValueSource::DefaultValue),
+            ),
         };
 
-        Ok(ctry!(Table::open(&p, mode);
-                 "failed to open table \"{}\"", p.display()))
-    }
+        if settings.incremental && settings.model_columns.contains(&settings.output_column) {
+            return err_msg!(
+                "the source model can't be read from the output column {} in incremental mode",
+                settings.output_column
+            );
+        }
+
+        if settings.subtract
+            && (settings.residual_column == settings.data_column
+                || settings.residual_column == settings.output_column)
+        {
+            return err_msg!(
+                "the residual column {} must differ from the data and output columns",
+                settings.residual_column
+            );
+        }
 
-    let mut main_table = open_table(&mainpath, true)?;
-    let mut work_table = open_table(&workpath, false)?;
+        if settings.interpolate {
+            if settings.model_columns.len() != settings.workpaths.len() {
+                return err_msg!(
+                    "`--model-column` must be given once for each of the {} work data sets",
+                    settings.workpaths.len()
+                );
+            }
+        } else if !settings.caltable_paths.is_empty() {
+            if settings.model_columns.len() != 1 {
+                return err_msg!("`--model-column` must be given exactly once with `--caltable`");
+            }
+        } else if !settings.model_columns.is_empty() {
+            return err_msg!("`--model-column` is only used with `--interpolate` or `--caltable`");
+        }
 
-    let n_rows = main_table.n_rows();
+        if settings.chunk_rows == 0 {
+            return err_msg!("`--chunk-rows` must be positive");
+        }
 
-    if work_table.n_rows() != n_rows {
-        rn_fatal!(
-            nbe,
-            "main table \"{}\" has {} rows, but work \
-             table \"{}\" has {} rows",
-            mainpath.display(),
-            n_rows,
-            workpath.display(),
-            work_table.n_rows()
-        );
-        return Ok(1);
+        Ok(settings)
     }
+}
 
-    fn check_cols(table: &mut Table, path: &Path, wanted_col_names: &[&str]) -> Result<()> {
-        let observed_col_names = ctry!(
-            table.column_names();
-            "failed to get names of columns in \"{}\"", path.display()
-        );
+pub fn do_cli(matches: &ArgMatches, nbe: &mut dyn NotificationBackend) -> Result<i32> {
+    if let Some(name) = matches.get_one::<String>("restore") {
+        let mainpath = matches.get_one::<PathBuf>("MAIN-TABLE").unwrap();
+        let flag_column = matches.get_one::<String>("flag_column").unwrap();
+        let keep_backup = *matches.get_one::<bool>("keep_backup").unwrap();
+        return do_restore(mainpath, name, flag_column, keep_backup, nbe);
+    }
 
-        // Not highly efficient, but N is small here ...
+    let settings = PeelSettings::new(matches)?;
+    let mainpath = settings.mainpath;
+    let dry_run = settings.dry_run;
 
-        for wanted_col in wanted_col_names {
-            let mut seen_col = false;
+    // If a previous peel of this data set was interrupted, we must either
+    // resume it or leave it alone.
 
-            for n in &observed_col_names {
-                if n == wanted_col {
-                    seen_col = true;
-                    break;
-                }
-            }
+    let (checkpoint_path, journal_path) = Checkpoint::paths(mainpath);
+    let summary = settings_summary(matches);
 
-            if !seen_col {
+    let resumed = if dry_run {
+        None
+    } else {
+        match (settings.resume, Checkpoint::load(&checkpoint_path)?) {
+            (false, None) => None,
+
+            (true, None) => {
                 return err_msg!(
-                    "{} column in table in \"{}\" \
-                    is required but missing",
-                    wanted_col,
-                    path.display()
+                    "no checkpoint of an interrupted peel was found at \"{}\"",
+                    checkpoint_path.display()
                 );
             }
-        }
 
-        Ok(())
-    }
+            (false, Some(_)) => {
+                rn_fatal!(
+                    nbe,
+                    "found the checkpoint \"{}\" of an interrupted peel; use `--resume` to \
+                     continue it, or delete it to start over",
+                    checkpoint_path.display()
+                );
+                return Ok(1);
+            }
 
-    check_cols(&mut main_table, &mainpath, &["FLAG", "MODEL_DATA"])?;
-    check_cols(
-        &mut work_table,
-        &workpath,
-        &["FLAG", "DATA", "MODEL_DATA", "CORRECTED_DATA"],
-    )?;
+            (true, Some(c)) => {
+                if c.settings != summary {
+                    rn_fatal!(
+                        nbe,
+                        "the interrupted peel recorded in \"{}\" used different settings; \
+                         resume it with the same arguments",
+                        checkpoint_path.display()
+                    );
+                    return Ok(1);
+                }
 
-    // Do the operation.
+                Some(c)
+            }
+        }
+    };
 
-    let mut main_row = main_table.get_row_writer()?;
-    let mut work_row = work_table.get_row_reader()?;
+    let (mut main_table, main_cols) =
+        open_main_table(&settings, resumed.as_ref(), &journal_path, nbe)?;
 
-    let mut pb = pbr::ProgressBar::new(n_rows);
-    pb.set_max_refresh_rate(Some(std::time::Duration::from_millis(500)));
+    // We need to know the spectral windows and polarizations of the main
+    // data to apply calibration tables, report, and match up polarization
+    // products with the work data sets.
 
-    /// Helper to provide error context if a `get_cell` call fails.
-    ///
-    /// This could almost work as a closure, but the type parametricity would be
-    /// a hassle.
-    #[inline(always)]
-    fn getcell_context<T: CasaDataType>(
-        row: &mut TableRow,
-        col_name: &str,
-        rownum: u64,
-        path: &Path,
-    ) -> Result<T> {
-        Ok(ctry!(
-            row.get_cell(col_name);
-            "failed to read column \"{}\" of row #{} of file \"{}\"", col_name, rownum, path.display()
-        ))
-    }
+    let mut caltables = Vec::with_capacity(settings.caltable_paths.len());
 
-    /// Helper to provide error context if a `put_call` call fails.
-    #[inline(always)]
-    fn putcell_context<T: CasaDataType>(
-        table: &mut Table,
-        col_name: &str,
-        row: u64,
-        value: &T,
-        path: &Path,
-    ) -> Result<()> {
-        Ok(ctry!(
-            table.put_cell(col_name, row, value);
-            "failed to write column \"{}\" of row #{} of file \"{}\"", col_name, row, path.display()
-        ))
+    if !settings.caltable_paths.is_empty() {
+        let field_dirs = load_field_directions(mainpath)?;
+
+        for p in &settings.caltable_paths {
+            caltables.push(CalTable::load(p, &field_dirs, nbe)?);
+        }
     }
 
-    for row in 0..n_rows {
-        ctry!(
-            main_table.read_row(&mut main_row, row);
-            "failed to read row #{} from \"{}\"", row, mainpath.display()
-        );
-        ctry!(
-            work_table.read_row(&mut work_row, row);
-            "failed to read row #{} from \"{}\"", row, workpath.display()
-        );
+    let ddinfo = load_data_desc_info(mainpath)?;
 
-        let main_flag: Array<bool, Ix2> = getcell_context(&mut main_row, "FLAG", row, &mainpath)?;
-        let work_data: Array<Complex<f32>, Ix2> =
-            getcell_context(&mut work_row, "DATA", row, &workpath)?;
-        let work_flag: Array<bool, Ix2> = getcell_context(&mut work_row, "FLAG", row, &workpath)?;
-        let work_model: Array<Complex<f32>, Ix2> =
-            getcell_context(&mut work_row, "MODEL_DATA", row, &workpath)?;
-        let mut work_corr: Array<Complex<f32>, Ix2> =
-            getcell_context(&mut work_row, "CORRECTED_DATA", row, &workpath)?;
+    let (works, row_matches) = match match_rows(&settings, &mut main_table, &ddinfo, nbe)? {
+        Some(m) => m,
+        None => return Ok(1),
+    };
 
-        let mut peel_flag = main_flag | work_flag;
+    // If we're making a backup, make sure that there's nothing in its way
+    // before we create anything, so that a clash doesn't leave new columns or
+    // a partial backup behind. When resuming, the original run has already
+    // started the backup.
 
-        // Avoid div-by-zero.
-        Zip::from(&mut work_corr)
-            .and(&mut peel_flag)
-            .for_each(|c, f| {
-                if c.norm() == 0. {
-                    *f = true;
-                }
+    if let (Some(name), None) = (settings.backup_name, &resumed) {
+        check_backup_clear(&settings, &mut main_table, name)?;
+    }
 
-                if *f {
-                    *c = Complex::from(1.0);
-                }
-            });
+    // Make sure that we have somewhere to put the outputs, creating columns
+    // modeled on the template column if needed. If we have to create a
+    // column, there's nothing to work with incrementally. If we're resuming,
+    // the columns were created by the original run.
 
-        let mut peel_model = work_data * work_model / work_corr;
+    let mut created_output = ensure_column(
+        &mut main_table,
+        mainpath,
+        settings.output_column,
+        main_cols.template,
+        dry_run,
+    )?;
 
-        // Not strictly necessary, maybe, but I think this is nice.
-        Zip::from(&mut peel_model)
-            .and(&mut peel_flag)
-            .for_each(|c, f| {
-                if !c.is_finite() {
-                    *f = true;
-                }
+    let mut created_residual = if settings.subtract {
+        ensure_column(
+            &mut main_table,
+            mainpath,
+            settings.residual_column,
+            main_cols.template,
+            dry_run,
+        )?
+    } else {
+        false
+    };
 
-                if *f {
-                    *c = Complex::from(0.0);
-                }
-            });
+    if let Some(c) = &resumed {
+        created_output = c.created_output;
+        created_residual = c.created_residual;
+    }
 
-        putcell_context(&mut main_table, "FLAG", row, &peel_flag, &mainpath)?;
+    // Work out what to do with each main row. Autocorrelations that are
+    // passed through are processed with no gain sources, so that their
+    // peeled model is zero. So are unmatched rows when the residuals are
+    // being overwritten, since leaving them untouched would leave residuals
+    // computed from some previous model in place. The rows that are left
+    // untouched still need to be filled in if we just created output
+    // columns, and backed up so that the backup is complete.
 
-        if incremental {
-            let main_model: Array<Complex<f32>, Ix2> =
-                getcell_context(&mut main_row, "MODEL_DATA", row, &mainpath)?;
-            peel_model += &main_model;
-        }
+    let pass_unmatched = settings.subtract && !settings.incremental;
+    let fill_untouched =
+        !dry_run && (created_output || created_residual || settings.backup_name.is_some());
+    let mut tasks = Vec::with_capacity(row_matches.len());
+    let mut n_untouched = 0;
 
-        putcell_context(&mut main_table, "MODEL_DATA", row, &peel_model, &mainpath)?;
-        pb.inc();
-    }
+    for (i, m) in row_matches.into_iter().enumerate() {
+        let task = match m {
+            RowMatch::Matched(sources) => RowTask::Peel(sources),
+            RowMatch::Auto if settings.autocorrelations == "pass" => RowTask::Peel(Vec::new()),
+            RowMatch::Unmatched if pass_unmatched => RowTask::Peel(Vec::new()),
 
-    // All done!
+            _ => {
+                n_untouched += 1;
 
-    pb.finish();
-    Ok(0)
+                if !fill_untouched {
+                    continue;
+                }
+
+                RowTask::Fill
+            }
+        };
+
+        tasks.push((i as u64, task));
+    }
+
+    if let Some(c) = &resumed {
+        if c.rows_total != tasks.len() {
+            return err_msg!(
+                "the interrupted peel recorded in \"{}\" was processing {} rows, but now there are {}",
+                checkpoint_path.display(),
+                c.rows_total,
+                tasks.len()
+            );
+        }
+    }
+
+    let mut backup = match settings.backup_name {
+        None => None,
+
+        Some(name) => Some(prepare_backup(
+            &settings,
+            &mut main_table,
+            name,
+            &main_cols,
+            (created_output, created_residual),
+            resumed.is_some(),
+        )?),
+    };
+
+    let mut model_cols = Vec::with_capacity(settings.model_columns.len());
+
+    for col in &settings.model_columns {
+        model_cols.push(VisColumn::new(&mut main_table, mainpath, col)?);
+    }
+
+    let report = settings
+        .report_path
+        .map(|_| GainReport::new(mainpath, time_reference(&mut main_table)));
+
+    let mut peeler = RowPeeler {
+        mainpath,
+        flag_column: settings.flag_column,
+        ratio_caches: vec![HashMap::new(); works.len()],
+        ddinfo,
+        works,
+        limits: settings.limits,
+        caltables,
+        caltable_paths: settings.caltable_paths.clone(),
+        model_cols,
+        report,
+        update_weights: settings.update_weights,
+        weight_spectrum: main_cols.has_weight_spectrum,
+        keep_flags: settings.keep_flags,
+        subtract: settings.subtract,
+        created_output,
+        created_residual,
+        add_incrementally: settings.incremental && !created_output,
+        subtract_incrementally: settings.incremental && !created_residual,
+        data_col: VisColumn::new(&mut main_table, mainpath, main_cols.data)?,
+        output_col: VisColumn::new(&mut main_table, mainpath, settings.output_column)?,
+        residual_col: VisColumn::new(&mut main_table, mainpath, settings.residual_column)?,
+    };
+
+    // Do the operation.
+
+    let n_peeled = tasks
+        .iter()
+        .filter(|(_, t)| matches!(t, RowTask::Peel(_)))
+        .count();
+
+    let n_newly_flagged = if dry_run {
+        dry_run_rows(&mut peeler, main_table, tasks)?
+    } else {
+        let mut checkpoint = resumed.unwrap_or(Checkpoint {
+            settings: summary,
+            created_output,
+            created_residual,
+            rows_total: tasks.len(),
+            rows_done: 0,
+        });
+        checkpoint.save(&checkpoint_path)?;
+
+        write_rows(
+            &mut peeler,
+            main_table,
+            tasks,
+            &mut checkpoint,
+            (&checkpoint_path, &journal_path),
+            &mut backup,
+            settings.chunk_rows,
+        )?;
+        0
+    };
+
+    // All done! Make sure that everything is written out before we delete
+    // the checkpoint.
+
+    drop(backup);
+
+    if !dry_run {
+        ctry!(
+            std::fs::remove_file(&checkpoint_path);
+            "failed to delete \"{}\"", checkpoint_path.display()
+        );
+    }
+
+    if let (Some(report), Some(path)) = (peeler.report, settings.report_path) {
+        report.finish().emit(settings.report_format, Some(path))?;
+    }
+
+    if dry_run {
+        rn_note!(
+            nbe,
+            "dry run: {} rows of \"{}\" would be modified, and {} left untouched",
+            n_peeled,
+            mainpath.display(),
+            n_untouched
+        );
+
+        if !settings.keep_flags {
+            rn_note!(
+                nbe,
+                "dry run: {} samples would become newly flagged",
+                n_newly_flagged
+            );
+        }
+
+        for (created, col) in [
+            (created_output, settings.output_column),
+            (created_residual, settings.residual_column),
+        ] {
+            if created {
+                rn_note!(nbe, "dry run: column \"{}\" would be created", col);
+            }
+        }
+    } else if let Some(name) = settings.backup_name {
+        rn_note!(
+            nbe,
+            "saved backup \"{}\"; use `--restore {}` to undo this peel",
+            name,
+            name
+        );
+    }
+
+    Ok(0)
+}
+
+/// The roles of some of the columns of the main table.
+struct MainColumns<'a> {
+    /// The column holding the observed data. It need not exist unless we're
+    /// subtracting.
+    data: &'a str,
+
+    /// The column on which any new columns are modeled.
+    template: &'a str,
+
+    /// Whether WEIGHT_SPECTRUM is to be updated along with WEIGHT and SIGMA.
+    has_weight_spectrum: bool,
+}
+
+/// Open the main table and check that it has what we need. If a previous
+/// peel was interrupted while writing a chunk of rows, the chunk is rolled
+/// back.
+fn open_main_table<'a>(
+    settings: &PeelSettings<'a>,
+    resumed: Option<&Checkpoint>,
+    journal_path: &Path,
+    nbe: &mut dyn NotificationBackend,
+) -> Result<(Table, MainColumns<'a>)> {
+    let mainpath = settings.mainpath;
+    let (_, mut main_table) = open_table(mainpath, "", settings.dry_run)?;
+
+    if let (Some(c), true) = (resumed, journal_path.exists()) {
+        let cols = (
+            settings.flag_column,
+            VisColumn::new(&mut main_table, mainpath, settings.output_column)?,
+            VisColumn::new(&mut main_table, mainpath, settings.residual_column)?,
+        );
+        let n = replay_journal(journal_path, c.rows_done, &mut main_table, mainpath, cols)?;
+
+        // rubbl_casatables can't flush a table, but closing one writes it
+        // out, so reopen it before the journal goes away.
+        drop(main_table);
+        main_table = open_table(mainpath, "", false)?.1;
+
+        ctry!(
+            std::fs::remove_file(journal_path);
+            "failed to delete \"{}\"", journal_path.display()
+        );
+
+        if n > 0 {
+            rn_note!(
+                nbe,
+                "rolled back {} rows that were being written when the peel was interrupted",
+                n
+            );
+        }
+    }
+
+    let data_column = find_data_column(
+        &mut main_table,
+        mainpath,
+        settings.data_column,
+        settings.default_data_columns.0,
+    )?;
+
+    check_cols(&mut main_table, mainpath, &[settings.flag_column])?;
+    check_cols(&mut main_table, mainpath, &settings.model_columns)?;
+
+    // The data column is only needed to compute residuals, but if it exists,
+    // it's the template for any columns that we create.
+
+    if settings.subtract {
+        check_cols(&mut main_table, mainpath, &[data_column])?;
+    }
+
+    let template_column = match settings.model_columns.first() {
+        Some(m) if !has_column(&mut main_table, mainpath, data_column)? => *m,
+        _ => data_column,
+    };
+
+    let has_weight_spectrum = if settings.update_weights {
+        check_cols(&mut main_table, mainpath, &["WEIGHT", "SIGMA"])?;
+        has_column(&mut main_table, mainpath, "WEIGHT_SPECTRUM")?
+    } else {
+        false
+    };
+
+    Ok((
+        main_table,
+        MainColumns {
+            data: data_column,
+            template: template_column,
+            has_weight_spectrum,
+        },
+    ))
+}
+
+/// How a main row matches up with the work data sets.
+enum RowMatch {
+    /// The row has counterparts in every work data set, from which the gains
+    /// of each peeled source come.
+    Matched(Vec<GainSource>),
+
+    /// The row is an autocorrelation that isn't being peeled.
+    Auto,
+
+    /// The row has no counterpart in at least one work data set.
+    Unmatched,
+}
+
+/// What to do with a main row.
+enum RowTask {
+    /// Peel the row, taking the gains of each peeled source from these.
+    Peel(Vec<GainSource>),
+
+    /// Leave the row untouched, apart from filling in any columns created by
+    /// the peel with a zero model and backing it up.
+    Fill,
+}
+
+/// Match up the rows of the main data set with those of the work data sets,
+/// opening the latter. The work data sets may have been created with a
+/// `split` that selected or reordered the data, so we can't just rely on row
+/// numbers. If they have been averaged, we match each main row to the work
+/// rows of the same baseline, etc., that bracket it in time. A main row must
+/// be matched in every work data set to be processed, unless it's an
+/// autocorrelation that we're not peeling. Returns None if a fatal problem
+/// was reported.
+fn match_rows<'a>(
+    settings: &PeelSettings<'a>,
+    main_table: &mut Table,
+    ddinfo: &[DataDescInfo],
+    nbe: &mut dyn NotificationBackend,
+) -> Result<Option<(Vec<WorkSet<'a>>, Vec<RowMatch>)>> {
+    let mainpath = settings.mainpath;
+    let n_main_rows = main_table.n_rows() as usize;
+
+    let is_auto = if settings.autocorrelations == "peel" {
+        vec![false; n_main_rows]
+    } else {
+        let ant1 = ctry!(
+            main_table.get_col_as_vec::<i32>("ANTENNA1");
+            "failed to read column \"ANTENNA1\" of \"{}\"", mainpath.display()
+        );
+        let ant2 = ctry!(
+            main_table.get_col_as_vec::<i32>("ANTENNA2");
+            "failed to read column \"ANTENNA2\" of \"{}\"", mainpath.display()
+        );
+        ant1.into_iter()
+            .zip(ant2)
+            .map(|(a1, a2)| a1 == a2)
+            .collect()
+    };
+
+    let n_works = settings.workpaths.len();
+    let mut works = Vec::with_capacity(n_works);
+    let mut sources = vec![Vec::with_capacity(n_works.max(1)); n_main_rows];
+    let mut is_unmatched = vec![false; n_main_rows];
+
+    if settings.workpaths.is_empty() {
+        for s in &mut sources {
+            s.push(GainSource::CalTables);
+        }
+    } else {
+        let main_idents = ctry!(
+            VisRecordIdentity::load_all(main_table);
+            "failed to read the row identities of \"{}\"", mainpath.display()
+        );
+        let pass_unmatched = settings.subtract && !settings.incremental;
+
+        for &workpath in &settings.workpaths {
+            let mut work = open_work_set(settings, workpath, ddinfo, nbe)?;
+
+            let index = match index_work_rows(&mut work, settings, nbe)? {
+                Some(i) => i,
+                None => return Ok(None),
+            };
+
+            let mut first_unmatched = None;
+            let mut n_unmatched = 0;
+
+            for (i, ident) in main_idents.iter().enumerate() {
+                if is_auto[i] {
+                    continue;
+                }
+
+                match index.find(ident) {
+                    Some(m) => sources[i].push(m),
+                    None => {
+                        first_unmatched.get_or_insert(i);
+                        n_unmatched += 1;
+                        is_unmatched[i] = true;
+                    }
+                }
+            }
+
+            if let Some(first) = first_unmatched {
+                if !settings.skip_unmatched {
+                    rn_fatal!(
+                        nbe,
+                        "{} rows of main table \"{}\", starting with row #{}, have no \
+                         counterpart in work table \"{}\"; use `--skip-unmatched` to leave them untouched",
+                        n_unmatched,
+                        mainpath.display(),
+                        first,
+                        workpath.display()
+                    );
+                    return Ok(None);
+                }
+
+                rn_warning!(
+                    nbe,
+                    "{} rows of main table \"{}\" have no counterpart in work table \"{}\"; \
+                     {}",
+                    n_unmatched,
+                    mainpath.display(),
+                    workpath.display(),
+                    if pass_unmatched {
+                        "setting their peeled model to zero"
+                    } else {
+                        "leaving them untouched"
+                    }
+                );
+            }
+
+            works.push(work);
+        }
+    }
+
+    let rows = sources
+        .into_iter()
+        .zip(is_auto)
+        .zip(is_unmatched)
+        .map(|((src, auto), unmatched)| {
+            if auto {
+                RowMatch::Auto
+            } else if unmatched {
+                RowMatch::Unmatched
+            } else {
+                RowMatch::Matched(src)
+            }
+        })
+        .collect();
+
+    Ok(Some((works, rows)))
+}
+
+/// Open a work data set and check that it has what we need.
+fn open_work_set<'a>(
+    settings: &PeelSettings<'a>,
+    workpath: &'a Path,
+    ddinfo: &[DataDescInfo],
+    nbe: &mut dyn NotificationBackend,
+) -> Result<WorkSet<'a>> {
+    let (_, mut work_table) = open_table(workpath, "", true)?;
+    let work_data_column = find_data_column(
+        &mut work_table,
+        workpath,
+        settings.work_data_column,
+        settings.default_data_columns.1,
+    )?;
+
+    if settings.interpolate {
+        check_cols(
+            &mut work_table,
+            workpath,
+            &[
+                settings.work_flag_column,
+                work_data_column,
+                settings.work_corrected_column,
+            ],
+        )?;
+    } else {
+        check_cols(
+            &mut work_table,
+            workpath,
+            &[
+                settings.work_flag_column,
+                work_data_column,
+                settings.work_model_column,
+                settings.work_corrected_column,
+            ],
+        )?;
+    }
+
+    // The work data set may have been split to select only some
+    // polarization products. The ones that it lacks aren't peeled.
+
+    let work_ddinfo = load_data_desc_info(workpath)?;
+    let mut pol_maps = Vec::with_capacity(ddinfo.len());
+
+    for (ddid, dd) in ddinfo.iter().enumerate() {
+        let map = work_ddinfo
+            .get(ddid)
+            .and_then(|w| pol_map(&dd.corr_types, &w.corr_types));
+
+        if let Some(m) = &map {
+            if m.iter().any(|i| i.is_none()) {
+                rn_warning!(
+                    nbe,
+                    "work table \"{}\" lacks some of the polarization products of \
+                     DATA_DESC_ID {} of main table \"{}\"; they will not be peeled",
+                    workpath.display(),
+                    ddid,
+                    settings.mainpath.display()
+                );
+            }
+        }
+
+        pol_maps.push(map);
+    }
+
+    let weight_column = if !settings.update_weights {
+        None
+    } else if has_column(&mut work_table, workpath, "WEIGHT_SPECTRUM")? {
+        Some("WEIGHT_SPECTRUM")
+    } else {
+        check_cols(&mut work_table, workpath, &["WEIGHT"])?;
+        Some("WEIGHT")
+    };
+
+    let data = VisColumn::new(&mut work_table, workpath, work_data_column)?;
+    let model = VisColumn::new(&mut work_table, workpath, settings.work_model_column)?;
+    let corrected = VisColumn::new(&mut work_table, workpath, settings.work_corrected_column)?;
+    let row = work_table.get_row_reader()?;
+
+    Ok(WorkSet {
+        table: work_table,
+        row,
+        path: workpath,
+        data,
+        model,
+        corrected,
+        flag: settings.work_flag_column,
+        weight_column,
+        pol_maps,
+        gain_stats: HashMap::new(),
+    })
+}
+
+/// How to find the work rows that match a main row.
+enum WorkIndex {
+    /// The work row with each identity.
+    Exact(HashMap<VisRecordIdentity<i32>, u64>),
+
+    /// The work rows with each identity apart from time, as time-sorted lists
+    /// of (time, row number).
+    Interpolated(HashMap<VisRecordIdentity<i32>, Vec<(f64, u64)>>),
+}
+
+impl WorkIndex {
+    /// Find where the gains of the main row with the specified identity come
+    /// from.
+    fn find(&self, ident: &VisRecordIdentity<i32>) -> Option<GainSource> {
+        match self {
+            WorkIndex::Exact(rows) => rows.get(ident).map(|w| GainSource::Exact(*w)),
+            WorkIndex::Interpolated(series) => series
+                .get(&ident.without_time())
+                .map(|s| GainSource::bracketing(s, ident.time())),
+        }
+    }
+}
+
+/// Index the rows of a work data set by their identities. If we're checking
+/// the gain ratios against their typical values, the statistics are gathered
+/// in the same pass. Returns None if a fatal problem was reported.
+fn index_work_rows(
+    work: &mut WorkSet,
+    settings: &PeelSettings,
+    nbe: &mut dyn NotificationBackend,
+) -> Result<Option<WorkIndex>> {
+    let work_idents = ctry!(
+        VisRecordIdentity::load_all(&mut work.table);
+        "failed to read the row identities of \"{}\"", work.path.display()
+    );
+    let mut rows = HashMap::with_capacity(work_idents.len());
+    let mut series: HashMap<_, Vec<(f64, u64)>> = HashMap::new();
+    let mut stats = settings
+        .limits
+        .max_sigma
+        .map(|_| GainStatsBuilder::default());
+
+    for (i, ident) in work_idents.into_iter().enumerate() {
+        if let Some(s) = &mut stats {
+            s.add(work, i as u64, &ident)?;
+        }
+
+        if settings.interpolate {
+            series
+                .entry(ident.without_time())
+                .or_default()
+                .push((ident.time(), i as u64));
+        } else if rows.insert(ident, i as u64).is_some() {
+            rn_fatal!(
+                nbe,
+                "row #{} of work table \"{}\" duplicates the identity of an earlier row",
+                i,
+                work.path.display()
+            );
+            return Ok(None);
+        }
+    }
+
+    if let Some(s) = stats {
+        work.gain_stats = s.finish();
+    }
+
+    if !settings.interpolate {
+        return Ok(Some(WorkIndex::Exact(rows)));
+    }
+
+    for s in series.values_mut() {
+        s.sort_by(|a, b| a.0.total_cmp(&b.0));
+    }
+
+    Ok(Some(WorkIndex::Interpolated(series)))
+}
+
+/// Gathers the amplitudes of the gain ratios of a work data set for each
+/// baseline, DATA_DESC_ID, and polarization product, to compute the
+/// statistics used by `--max-gain-sigma`.
+#[derive(Default)]
+struct GainStatsBuilder {
+    amps: HashMap<(i32, i32, i32), Vec<Vec<f32>>>,
+}
+
+impl GainStatsBuilder {
+    /// Add the unflagged gain ratios of a work row.
+    fn add(&mut self, work: &mut WorkSet, row: u64, ident: &VisRecordIdentity<i32>) -> Result<()> {
+        let ((ratio, flag), _) = gain_ratio(work, row, false)?;
+        let (ant1, ant2) = ident.antennas();
+        let pol_amps = self
+            .amps
+            .entry((ant1, ant2, *ident.discriminant()))
+            .or_default();
+        pol_amps.resize_with(ratio.dim().1.max(pol_amps.len()), Vec::new);
+
+        Zip::indexed(&ratio)
+            .and(&flag)
+            .for_each(|(_, i_pol), r, f| {
+                if !f {
+                    pol_amps[i_pol].push(r.norm());
+                }
+            });
+
+        Ok(())
+    }
+
+    /// Compute the median and robust standard deviation of each set of
+    /// amplitudes.
+    fn finish(self) -> GainStats {
+        self.amps
+            .into_iter()
+            .map(|(key, pol_amps)| {
+                let stats = pol_amps
+                    .into_iter()
+                    .map(|mut a| {
+                        (!a.is_empty()).then(|| {
+                            let (med, sigma) = robust_stats(&mut a);
+                            (med as f32, sigma as f32)
+                        })
+                    })
+                    .collect();
+                (key, stats)
+            })
+            .collect()
+    }
+}
+
+/// Make sure that there's nothing in the way of a new backup named `name`.
+fn check_backup_clear(settings: &PeelSettings, main_table: &mut Table, name: &str) -> Result<()> {
+    let mainpath = settings.mainpath;
+    let version_path = flag_versions_dir(mainpath).join(format!("flags.{}", name));
+
+    if version_path.exists() {
+        return err_msg!("flag version \"{}\" already exists", version_path.display());
+    }
+
+    if let Some((_, c)) = Backup::find_columns(main_table, mainpath, name)?.first() {
+        return err_msg!(
+            "backup column \"{}\" already exists in \"{}\"",
+            c,
+            mainpath.display()
+        );
+    }
+
+    for col in [
+        settings.output_column,
+        settings.residual_column,
+        "WEIGHT",
+        "SIGMA",
+        "WEIGHT_SPECTRUM",
+    ] {
+        let backup_col = Backup::column_name(col, name);
+
+        if has_column(main_table, mainpath, &backup_col)? {
+            return err_msg!(
+                "column \"{}\" already exists in \"{}\"",
+                backup_col,
+                mainpath.display()
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Prepare to save the data that the peel will overwrite in the backup named
+/// `name`, creating its columns and flag version. `created` says whether the
+/// output and residual columns were created by the peel, in which case
+/// there's nothing to save. When resuming, the original run has already
+/// created everything.
+fn prepare_backup(
+    settings: &PeelSettings,
+    main_table: &mut Table,
+    name: &str,
+    main_cols: &MainColumns,
+    created: (bool, bool),
+    resuming: bool,
+) -> Result<Backup> {
+    let mainpath = settings.mainpath;
+    let mut to_save = Vec::new();
+
+    if !created.0 {
+        to_save.push(settings.output_column);
+    }
+
+    if settings.subtract && !created.1 {
+        to_save.push(settings.residual_column);
+    }
+
+    if settings.update_weights {
+        to_save.push("WEIGHT");
+        to_save.push("SIGMA");
+
+        if main_cols.has_weight_spectrum {
+            to_save.push("WEIGHT_SPECTRUM");
+        }
+    }
+
+    let mut columns = Vec::with_capacity(to_save.len());
+
+    for col in to_save {
+        let backup_col = Backup::column_name(col, name);
+        ensure_column(main_table, mainpath, &backup_col, col, false)?;
+        Backup::record_column(main_table, mainpath, col, &backup_col, name)?;
+
+        let is_real = is_real_column(main_table, mainpath, col)?;
+        columns.push((col.to_owned(), backup_col, is_real));
+    }
+
+    let flags = if settings.keep_flags {
+        None
+    } else if resuming {
+        let path = flag_versions_dir(mainpath).join(format!("flags.{}", name));
+        Some(open_table(&path, "", false)?)
+    } else {
+        Some(create_flag_version(
+            mainpath,
+            main_table,
+            settings.flag_column,
+            name,
+            "flags before peeling",
+        )?)
+    };
+
+    Ok(Backup {
+        columns,
+        flag_column: settings.flag_column.to_owned(),
+        flags,
+        have_flag_row: has_column(main_table, mainpath, "FLAG_ROW")?,
+    })
+}
+
+/// Work out the peel without writing anything. Returns the number of samples
+/// that would become newly flagged.
+fn dry_run_rows(
+    peeler: &mut RowPeeler,
+    mut main_table: Table,
+    tasks: Vec<(u64, RowTask)>,
+) -> Result<usize> {
+    let mainpath = peeler.mainpath;
+    let mut main_row = main_table.get_row_reader()?;
+    let mut pb = pbr::ProgressBar::new(tasks.len() as u64);
+    pb.set_max_refresh_rate(Some(std::time::Duration::from_millis(500)));
+    let mut n_newly_flagged = 0;
+
+    for (row, task) in tasks {
+        if let RowTask::Peel(sources) = task {
+            ctry!(
+                main_table.read_row(&mut main_row, row);
+                "failed to read row #{} from \"{}\"", row, mainpath.display()
+            );
+
+            let peeled = peeler.peel(&mut main_row, row, sources)?;
+
+            if !peeler.keep_flags {
+                n_newly_flagged += Zip::from(&peeled.flag)
+                    .and(&peeled.main_flag)
+                    .fold(0, |acc, p, m| acc + (*p && !*m) as usize);
+            }
+        }
+
+        pb.inc();
+    }
+
+    pb.finish();
+    Ok(n_newly_flagged)
+}
+
+/// Peel the main rows and write out the results in chunks, committing each
+/// chunk before moving on to the next and recording our progress in
+/// `checkpoint`. `paths` are those of the checkpoint and journal files.
+fn write_rows(
+    peeler: &mut RowPeeler,
+    mut main_table: Table,
+    tasks: Vec<(u64, RowTask)>,
+    checkpoint: &mut Checkpoint,
+    paths: (&Path, &Path),
+    backup: &mut Option<Backup>,
+    chunk_rows: usize,
+) -> Result<()> {
+    let mainpath = peeler.mainpath;
+    let (checkpoint_path, journal_path) = paths;
+    let cols = (peeler.flag_column, peeler.output_col, peeler.residual_col);
+    let mut main_row = main_table.get_row_writer()?;
+
+    let mut pb = pbr::ProgressBar::new(tasks.len() as u64);
+    pb.set_max_refresh_rate(Some(std::time::Duration::from_millis(500)));
+    pb.set(checkpoint.rows_done as u64);
+
+    for chunk in &tasks
+        .into_iter()
+        .skip(checkpoint.rows_done)
+        .chunks(chunk_rows)
+    {
+        let mut updates = Vec::with_capacity(chunk_rows);
+
+        for (row, task) in chunk {
+            ctry!(
+                main_table.read_row(&mut main_row, row);
+                "failed to read row #{} from \"{}\"", row, mainpath.display()
+            );
+
+            if let Some(b) = backup {
+                b.save_row(&mut main_table, &mut main_row, row, mainpath)?;
+            }
+
+            let (original, update) = match task {
+                RowTask::Peel(sources) => {
+                    let peeled = peeler.peel(&mut main_row, row, sources)?;
+                    peeler.row_cells(&mut main_row, row, peeled)?
+                }
+
+                RowTask::Fill => peeler.fill_cells(&mut main_row, row)?,
+            };
+
+            updates.push((row, original, update));
+            pb.inc();
+        }
+
+        // Commit the chunk. We first save the current contents of its rows in
+        // the journal, so that they can be restored if we're interrupted while
+        // writing. Then we make sure that everything is on disk before
+        // recording our progress. rubbl_casatables can't flush a table, but
+        // closing one writes it out, so we close and reopen the tables.
+
+        save_journal(journal_path, checkpoint.rows_done, &updates)?;
+
+        for (row, _, update) in &updates {
+            update.write(&mut main_table, *row, mainpath, cols)?;
+        }
+
+        drop(main_row);
+        drop(main_table);
+        main_table = open_table(mainpath, "", false)?.1;
+        main_row = main_table.get_row_writer()?;
+
+        if let Some(b) = backup {
+            b.commit()?;
+        }
+
+        checkpoint.rows_done += updates.len();
+        checkpoint.save(checkpoint_path)?;
+
+        ctry!(
+            std::fs::remove_file(journal_path);
+            "failed to delete \"{}\"", journal_path.display()
+        );
+    }
+
+    pb.finish();
+    Ok(())
+}
+
+/// Helper to provide error context if a `get_cell` call fails.
+///
+/// This could almost work as a closure, but the type parametricity would be
+/// a hassle.
+#[inline(always)]
+fn getcell_context<T: CasaDataType>(
+    row: &mut TableRow,
+    col_name: &str,
+    rownum: u64,
+    path: &Path,
+) -> Result<T> {
+    Ok(ctry!(
+        row.get_cell(col_name);
+        "failed to read column \"{}\" of row #{} of file \"{}\"", col_name, rownum, path.display()
+    ))
+}
+
+/// Compute the variance of the gain ratio DATA/CORRECTED_DATA of a work
+/// row that has been read into `work_row`, attributing it all to the noise
+/// in DATA. The weights are read from `weight_col`, which is either
+/// WEIGHT_SPECTRUM or WEIGHT, which is broadcast over channels. Samples
+/// with zero weight or CORRECTED_DATA have infinite variance.
+fn ratio_variance(
+    work_row: &mut TableRow,
+    work_rownum: u64,
+    workpath: &Path,
+    weight_col: &str,
+    corr: &Array<Complex<f32>, Ix2>,
+) -> Result<Array<f32, Ix2>> {
+    let weight: Array<f32, Ix2> = if weight_col == "WEIGHT_SPECTRUM" {
+        getcell_context(work_row, weight_col, work_rownum, workpath)?
+    } else {
+        let w: Array<f32, Ix1> = getcell_context(work_row, weight_col, work_rownum, workpath)?;
+
+        match w.broadcast(corr.dim()) {
+            Some(w) => w.to_owned(),
+            None => Array::zeros((0, 0)),
+        }
+    };
+
+    if weight.dim() != corr.dim() {
+        return err_msg!(
+            "row #{} of \"{}\" has data shape {:?}, but its weights have shape {:?}",
+            work_rownum,
+            workpath.display(),
+            corr.shape(),
+            weight.shape()
+        );
+    }
+
+    Ok(Zip::from(&weight)
+        .and(corr)
+        .map_collect(|w, c| 1. / (w * c.norm_sqr())))
+}
+
+/// Compute the gain ratio DATA/CORRECTED_DATA of a work row, along with
+/// its flags. If `with_variance` is true and the work data set has a
+/// weight column, the variance of the ratio is computed too.
+fn gain_ratio(work: &mut WorkSet, work_rownum: u64, with_variance: bool) -> Result<GainRatio> {
+    let workpath = work.path;
+    let work_row = &mut work.row;
+    ctry!(
+        work.table.read_row(work_row, work_rownum);
+        "failed to read row #{} from \"{}\"", work_rownum, workpath.display()
+    );
+
+    let mut ratio = work.data.get(work_row, work_rownum, workpath)?;
+    let mut flag: Array<bool, Ix2> = getcell_context(work_row, work.flag, work_rownum, workpath)?;
+    let corr = work.corrected.get(work_row, work_rownum, workpath)?;
+
+    let mut variance = work
+        .weight_column
+        .filter(|_| with_variance)
+        .map(|c| ratio_variance(work_row, work_rownum, workpath, c, &corr))
+        .transpose()?;
+
+    if let Some(v) = &variance {
+        Zip::from(v).and(&mut flag).for_each(|v, f| {
+            if !v.is_finite() {
+                *f = true;
+            }
+        });
+    }
+
+    Zip::from(&mut ratio)
+        .and(&mut flag)
+        .and(&corr)
+        .for_each(|r, f, c| {
+            *r /= *c;
+
+            if !r.is_finite() {
+                *f = true;
+            }
+
+            if *f {
+                *r = Complex::from(0.0);
+            }
+        });
+
+    if let Some(v) = &mut variance {
+        Zip::from(v).and(&flag).for_each(|v, f| {
+            if *f {
+                *v = 0.;
+            }
+        });
+    }
+
+    Ok(((ratio, flag), variance))
+}
+
+/// Compute the weights and sigmas of a main row that has been read into
+/// `main_row` after including the variance of its peeled model, storing
+/// the current and new values into `original` and `update`. WEIGHT and
+/// SIGMA have one value per polarization, so they get the mean variance of
+/// the unflagged channels.
+#[allow(clippy::too_many_arguments)]
+fn add_model_variance(
+    main_row: &mut TableRow,
+    row: u64,
+    mainpath: &Path,
+    variance: &Array<f32, Ix2>,
+    flag: &Array<bool, Ix2>,
+    weight_spectrum: bool,
+    original: &mut RowCells,
+    update: &mut RowCells,
+) -> Result<()> {
+    let mut weight: Array<f32, Ix1> = getcell_context(main_row, "WEIGHT", row, mainpath)?;
+    let mut sigma: Array<f32, Ix1> = getcell_context(main_row, "SIGMA", row, mainpath)?;
+    original.weight = Some(weight.clone());
+    original.sigma = Some(sigma.clone());
+    let n_pol = variance.dim().1;
+
+    if weight.len() != n_pol || sigma.len() != n_pol {
+        return err_msg!(
+            "row #{} of \"{}\" has {} polarizations, but its WEIGHT or SIGMA has a different number",
+            row,
+            mainpath.display(),
+            n_pol
+        );
+    }
+
+    for i_pol in 0..n_pol {
+        let (sum, n) = variance
+            .column(i_pol)
+            .iter()
+            .zip(flag.column(i_pol))
+            .filter(|(_, f)| !**f)
+            .fold((0., 0), |(sum, n), (v, _)| (sum + v, n + 1));
+
+        if n > 0 {
+            let mean = sum / n as f32;
+            weight[i_pol] /= 1. + weight[i_pol] * mean;
+            sigma[i_pol] = (sigma[i_pol].powi(2) + mean).sqrt();
+        }
+    }
+
+    update.weight = Some(weight);
+    update.sigma = Some(sigma);
+
+    if weight_spectrum {
+        let mut weight: Array<f32, Ix2> =
+            getcell_context(main_row, "WEIGHT_SPECTRUM", row, mainpath)?;
+        original.weight_spectrum = Some(weight.clone());
+
+        if weight.dim() != variance.dim() {
+            return err_msg!(
+                "row #{} of \"{}\" has data shape {:?}, but its WEIGHT_SPECTRUM has shape {:?}",
+                row,
+                mainpath.display(),
+                variance.shape(),
+                weight.shape()
+            );
+        }
+
+        Zip::from(&mut weight)
+            .and(variance)
+            .for_each(|w, v| *w /= 1. + *w * v);
+
+        update.weight_spectrum = Some(weight);
+    }
+
+    Ok(())
+}
+
+/// The peeled model of a main row, as computed by `RowPeeler::peel`. The
+/// arrays have shape (n_chan, n_pol).
+struct PeeledRow {
+    /// The current flags of the row.
+    main_flag: Array<bool, Ix2>,
+
+    /// The flags after peeling.
+    flag: Array<bool, Ix2>,
+
+    /// The total model of the peeled sources.
+    model: Array<Complex<f32>, Ix2>,
+
+    /// The variance of the model, if the weights are being updated.
+    variance: Option<Array<f32, Ix2>>,
+}
+
+/// Everything needed to work out the peeled model of each main row and the
+/// new contents of its cells.
+struct RowPeeler<'a> {
+    mainpath: &'a Path,
+    flag_column: &'a str,
+    ddinfo: Vec<DataDescInfo>,
+    works: Vec<WorkSet<'a>>,
+
+    /// When interpolating, each work row is generally used for many main
+    /// rows, so we cache the gain ratios, along with the latest main-row time
+    /// at which they're needed. Main rows are usually sorted by time, so
+    /// entries are dropped once the main rows have moved past that time. If
+    /// they aren't sorted, evicted entries are just recomputed as needed.
+    ratio_caches: Vec<HashMap<u64, (GainRatio, f64)>>,
+
+    limits: GainLimits,
+    caltables: Vec<CalTable>,
+    caltable_paths: Vec<&'a PathBuf>,
+    model_cols: Vec<VisColumn<'a>>,
+    report: Option<GainReport>,
+    update_weights: bool,
+    weight_spectrum: bool,
+    keep_flags: bool,
+    subtract: bool,
+    created_output: bool,
+    created_residual: bool,
+    add_incrementally: bool,
+    subtract_incrementally: bool,
+    data_col: VisColumn<'a>,
+    output_col: VisColumn<'a>,
+    residual_col: VisColumn<'a>,
+}
+
+impl<'a> RowPeeler<'a> {
+    /// Compute the peeled model of a main row that has been read into
+    /// `main_row`, given the sources of the gains for each peeled source.
+    fn peel(
+        &mut self,
+        main_row: &mut TableRow,
+        row: u64,
+        sources: Vec<GainSource>,
+    ) -> Result<PeeledRow> {
+        let limits = self.limits;
+
+        let main_flag: Array<bool, Ix2> =
+            getcell_context(main_row, self.flag_column, row, self.mainpath)?;
+        let ddid: i32 = getcell_context(main_row, "DATA_DESC_ID", row, self.mainpath)?;
+
+        let baseline = if limits.max_sigma.is_none() {
+            None
+        } else {
+            Some((
+                getcell_context::<i32>(main_row, "ANTENNA1", row, self.mainpath)?,
+                getcell_context::<i32>(main_row, "ANTENNA2", row, self.mainpath)?,
+            ))
+        };
+
+        let report_key = if self.report.is_some() {
+            let spw_id = match self.ddinfo.get(ddid as usize) {
+                Some(d) => d.spw_id,
+                None => {
+                    return err_msg!(
+                        "row #{} of \"{}\" refers to nonexistent DATA_DESC_ID {}",
+                        row,
+                        self.mainpath.display(),
+                        ddid
+                    );
+                }
+            };
+
+            Some((
+                getcell_context::<f64>(main_row, "TIME", row, self.mainpath)?,
+                getcell_context::<i32>(main_row, "ANTENNA1", row, self.mainpath)?,
+                getcell_context::<i32>(main_row, "ANTENNA2", row, self.mainpath)?,
+                spw_id,
+            ))
+        } else {
+            None
+        };
+
+        let mut peel_flag = main_flag.clone();
+        let mut peel_model = Array::zeros(main_flag.dim());
+        let mut peel_variance = self
+            .update_weights
+            .then(|| Array::<f32, Ix2>::zeros(main_flag.dim()));
+
+        for (i_src, m) in sources.into_iter().enumerate() {
+            let stats = match (baseline, self.works.get(i_src)) {
+                (Some((ant1, ant2)), Some(w)) => w.gain_stats(ant1, ant2, ddid),
+                _ => Vec::new(),
+            };
+            let pol_stats = |i_pol: usize| stats.get(i_pol).copied().flatten();
+            let pol_map = self
+                .works
+                .get(i_src)
+                .and_then(|w| w.pol_maps.get(ddid as usize))
+                .cloned()
+                .flatten();
+
+            let (mut src_flag, mut src_model, src_ratio, mut src_variance) = match m {
+                GainSource::Exact(work_rownum) => {
+                    let work = &mut self.works[i_src];
+                    let workpath = work.path;
+                    let work_row = &mut work.row;
+                    ctry!(
+                        work.table.read_row(work_row, work_rownum);
+                        "failed to read row #{} from \"{}\"", work_rownum, workpath.display()
+                    );
+
+                    let mut work_data = work.data.get(work_row, work_rownum, workpath)?;
+                    let mut work_flag: Array<bool, Ix2> =
+                        getcell_context(work_row, work.flag, work_rownum, workpath)?;
+                    let mut work_model = work.model.get(work_row, work_rownum, workpath)?;
+                    let mut work_corr = work.corrected.get(work_row, work_rownum, workpath)?;
+
+                    let mut variance = work
+                        .weight_column
+                        .map(|c| ratio_variance(work_row, work_rownum, workpath, c, &work_corr))
+                        .transpose()?;
+
+                    if let Some(map) = &pol_map {
+                        work_data = map_pols(&work_data, map, Complex::from(0.0));
+                        work_flag = map_pols(&work_flag, map, true);
+                        work_model = map_pols(&work_model, map, Complex::from(0.0));
+                        work_corr = map_pols(&work_corr, map, Complex::from(1.0));
+                        variance = variance.map(|v| map_pols(&v, map, 0.));
+                    }
+
+                    if work_data.shape() != main_flag.shape() {
+                        return err_msg!(
+                            "row #{} of \"{}\" has data shape {:?}, but matching row #{} of \"{}\" has {:?}",
+                            row,
+                            self.mainpath.display(),
+                            main_flag.shape(),
+                            work_rownum,
+                            workpath.display(),
+                            work_data.shape()
+                        );
+                    }
+
+                    let mut src_flag = &main_flag | &work_flag;
+
+                    if let Some(v) = &variance {
+                        Zip::from(v).and(&mut src_flag).for_each(|v, f| {
+                            if !v.is_finite() {
+                                *f = true;
+                            }
+                        });
+                    }
+
+                    let src_variance = variance.map(|v| v * work_model.mapv(|m| m.norm_sqr()));
+
+                    // Avoid div-by-zero.
+                    Zip::from(&mut work_corr)
+                        .and(&mut src_flag)
+                        .for_each(|c, f| {
+                            if c.norm() == 0. {
+                                *f = true;
+                            }
+
+                            if *f {
+                                *c = Complex::from(1.0);
+                            }
+                        });
+
+                    if limits.is_active() {
+                        let mut ratio = work_data / work_corr;
+
+                        Zip::indexed(&mut ratio)
+                            .and(&mut src_flag)
+                            .for_each(|(_, i_pol), r, f| limits.apply(r, f, pol_stats(i_pol)));
+
+                        let src_model = &ratio * &work_model;
+                        (src_flag, src_model, Some(ratio), src_variance)
+                    } else {
+                        let ratio = self.report.as_ref().map(|_| &work_data / &work_corr);
+                        let src_model = work_data * work_model / work_corr;
+                        (src_flag, src_model, ratio, src_variance)
+                    }
+                }
+
+                GainSource::Interpolated {
+                    before,
+                    after,
+                    frac,
+                    until,
+                } => {
+                    let work = &mut self.works[i_src];
+                    let ratio_cache = &mut self.ratio_caches[i_src];
+                    let time: f64 = getcell_context(main_row, "TIME", row, self.mainpath)?;
+
+                    for w in [before, after] {
+                        match ratio_cache.entry(w) {
+                            Entry::Occupied(mut e) => {
+                                let needed_until = &mut e.get_mut().1;
+                                *needed_until = needed_until.max(until);
+                            }
+
+                            Entry::Vacant(e) => {
+                                let ((mut r, mut f), mut v) = gain_ratio(work, w, true)?;
+
+                                if let Some(map) = &pol_map {
+                                    r = map_pols(&r, map, Complex::from(0.0));
+                                    f = map_pols(&f, map, true);
+                                    v = v.map(|v| map_pols(&v, map, 0.));
+                                }
+
+                                e.insert((((r, f), v), until));
+                            }
+                        }
+                    }
+
+                    ratio_cache.retain(|_, (_, needed_until)| *needed_until >= time);
+
+                    let workpath = work.path;
+                    let (((r0, f0), v0), _) = &ratio_cache[&before];
+                    let (((r1, f1), v1), _) = &ratio_cache[&after];
+                    let model = self.model_cols[i_src].get(main_row, row, self.mainpath)?;
+
+                    // The FLAG arrays have shape (n_chan, n_pol). Work channels
+                    // must evenly divide the main ones.
+
+                    let (n_chan, n_pol) = main_flag.dim();
+                    let (n_work_chan, n_work_pol) = r0.dim();
+
+                    if n_work_pol != n_pol || n_work_chan == 0 || n_chan % n_work_chan != 0 {
+                        return err_msg!(
+                            "row #{} of \"{}\" has data shape {:?}, which is incompatible with \
+                             the shape {:?} of its counterpart(s) in \"{}\"",
+                            row,
+                            self.mainpath.display(),
+                            main_flag.shape(),
+                            r0.shape(),
+                            workpath.display()
+                        );
+                    }
+
+                    let chan_factor = n_chan / n_work_chan;
+                    let frac = frac as f32;
+
+                    // The variance of the interpolated ratio, scaled by the
+                    // model. Fully flagged samples are dealt with below.
+
+                    let src_variance = v0.as_ref().zip(v1.as_ref()).map(|(v0, v1)| {
+                        Zip::indexed(&model).map_collect(|(i_chan, i_pol), m| {
+                            let wc = (i_chan / chan_factor, i_pol);
+
+                            let v = match (f0[wc], f1[wc]) {
+                                (false, false) => {
+                                    v0[wc] * (1. - frac).powi(2) + v1[wc] * frac.powi(2)
+                                }
+                                (false, true) => v0[wc],
+                                (true, false) => v1[wc],
+                                (true, true) => 0.,
+                            };
+
+                            v * m.norm_sqr()
+                        })
+                    });
+
+                    let mut src_flag = main_flag.clone();
+                    let mut src_model = model;
+                    let mut src_ratio = Array::zeros((n_chan, n_pol));
+
+                    Zip::indexed(&mut src_model)
+                        .and(&mut src_flag)
+                        .and(&mut src_ratio)
+                        .for_each(|(i_chan, i_pol), m, f, r| {
+                            let wc = (i_chan / chan_factor, i_pol);
+
+                            let mut ratio = match (f0[wc], f1[wc]) {
+                                (false, false) => interpolate_gain(r0[wc], r1[wc], frac),
+                                (false, true) => r0[wc],
+                                (true, false) => r1[wc],
+                                (true, true) => {
+                                    *f = true;
+                                    Complex::from(0.0)
+                                }
+                            };
+
+                            limits.apply(&mut ratio, f, pol_stats(i_pol));
+                            *m *= ratio;
+                            *r = ratio;
+                        });
+
+                    (src_flag, src_model, Some(src_ratio), src_variance)
+                }
+
+                GainSource::CalTables => {
+                    let model = self.model_cols[0].get(main_row, row, self.mainpath)?;
+                    let time: f64 = getcell_context(main_row, "TIME", row, self.mainpath)?;
+                    let ant1: i32 = getcell_context(main_row, "ANTENNA1", row, self.mainpath)?;
+                    let ant2: i32 = getcell_context(main_row, "ANTENNA2", row, self.mainpath)?;
+                    let field: i32 = getcell_context(main_row, "FIELD_ID", row, self.mainpath)?;
+
+                    let dd = match self.ddinfo.get(ddid as usize) {
+                        Some(d) => d,
+                        None => {
+                            return err_msg!(
+                                "row #{} of \"{}\" refers to nonexistent DATA_DESC_ID {}",
+                                row,
+                                self.mainpath.display(),
+                                ddid
+                            );
+                        }
+                    };
+
+                    let mut receptors = Vec::with_capacity(dd.corr_types.len());
+
+                    for code in &dd.corr_types {
+                        match corr_receptors(*code) {
+                            Some(r) => receptors.push(r),
+                            None => {
+                                return err_msg!(
+                                    "cannot apply antenna gains to correlation type {} in \"{}\"",
+                                    code,
+                                    self.mainpath.display()
+                                );
+                            }
+                        }
+                    }
+
+                    let (n_chan, n_pol) = main_flag.dim();
+
+                    if n_pol != receptors.len() {
+                        return err_msg!(
+                            "row #{} of \"{}\" has {} polarizations, but its DATA_DESC_ID implies {}",
+                            row,
+                            self.mainpath.display(),
+                            n_pol,
+                            receptors.len()
+                        );
+                    }
+
+                    let mut src_flag = main_flag.clone();
+                    let mut src_model = model;
+                    let mut gains = Array::from_elem((n_chan, n_pol), Complex::new(1.0, 0.0));
+
+                    for (ct, ct_path) in self.caltables.iter().zip(&self.caltable_paths) {
+                        let (gi, fi, gj, fj) = match (
+                            ct.gains_at(ant1, dd.spw_id, field, time),
+                            ct.gains_at(ant2, dd.spw_id, field, time),
+                        ) {
+                            (Some((gi, fi)), Some((gj, fj))) => (gi, fi, gj, fj),
+
+                            _ => {
+                                // No solutions for one of the antennas.
+                                src_flag.fill(true);
+                                continue;
+                            }
+                        };
+
+                        let (n_cal_chan, n_rec_i) = gi.dim();
+                        let n_rec_j = gj.dim().1;
+
+                        if gj.dim().0 != n_cal_chan || n_cal_chan == 0 || n_chan % n_cal_chan != 0 {
+                            return err_msg!(
+                                "the gains in \"{}\" have {} channels, which is incompatible with the {} \
+                                 channels of row #{} of \"{}\"",
+                                ct_path.display(),
+                                n_cal_chan,
+                                n_chan,
+                                row,
+                                self.mainpath.display()
+                            );
+                        }
+
+                        let chan_factor = n_chan / n_cal_chan;
+
+                        // Single-receptor ("T") solutions apply to both receptors.
+                        Zip::indexed(&mut gains).and(&mut src_flag).for_each(
+                            |(i_chan, i_pol), g, f| {
+                                let cc = i_chan / chan_factor;
+                                let (ri, rj) = receptors[i_pol];
+                                let ii = (cc, ri.min(n_rec_i - 1));
+                                let jj = (cc, rj.min(n_rec_j - 1));
+
+                                if fi[ii] || fj[jj] {
+                                    *f = true;
+                                } else {
+                                    *g *= gi[ii] * gj[jj].conj();
+                                }
+                            },
+                        );
+                    }
+
+                    Zip::from(&mut src_model)
+                        .and(&mut src_flag)
+                        .and(&mut gains)
+                        .for_each(|m, f, g| {
+                            limits.apply(g, f, None);
+                            *m *= *g;
+                        });
+
+                    (src_flag, src_model, Some(gains), None)
+                }
+            };
+
+            if let (Some(report), Some(key), Some(ratio)) =
+                (&mut self.report, report_key, &src_ratio)
+            {
+                report.add(i_src, key, ratio, &src_flag);
+            }
+
+            // Polarization products that the work data set lacks aren't
+            // peeled, but that's no reason to flag them.
+
+            if let Some(map) = &pol_map {
+                for (i_pol, _) in map.iter().enumerate().filter(|(_, m)| m.is_none()) {
+                    src_flag.column_mut(i_pol).assign(&main_flag.column(i_pol));
+                    src_model.column_mut(i_pol).fill(Complex::from(0.0));
+
+                    if let Some(v) = &mut src_variance {
+                        v.column_mut(i_pol).fill(0.);
+                    }
+                }
+            }
+
+            if let (Some(pv), Some(sv)) = (&mut peel_variance, &mut src_variance) {
+                Zip::from(&mut *sv).and(&src_flag).for_each(|v, f| {
+                    if *f {
+                        *v = 0.;
+                    }
+                });
+                *pv += &*sv;
+            }
+
+            peel_flag |= &src_flag;
+            peel_model += &src_model;
+        }
+
+        // Not strictly necessary, maybe, but I think this is nice.
+        Zip::from(&mut peel_model)
+            .and(&mut peel_flag)
+            .for_each(|c, f| {
+                if !c.is_finite() {
+                    *f = true;
+                }
+
+                if *f {
+                    *c = Complex::from(0.0);
+                }
+            });
+
+        Ok(PeeledRow {
+            main_flag,
+            flag: peel_flag,
+            model: peel_model,
+            variance: peel_variance,
+        })
+    }
+
+    /// Work out the new contents of the cells of a main row that has been
+    /// read into `main_row`, returning them along with the current ones so
+    /// that the latter can be saved in the journal.
+    fn row_cells(
+        &self,
+        main_row: &mut TableRow,
+        row: u64,
+        peeled: PeeledRow,
+    ) -> Result<(RowCells, RowCells)> {
+        let PeeledRow {
+            main_flag,
+            flag: peel_flag,
+            model: mut peel_model,
+            variance: peel_variance,
+        } = peeled;
+
+        // Work out the new contents of the row, keeping track of the current
+        // ones so that they can be saved in the journal.
+
+        let mut original = RowCells::default();
+        let mut update = RowCells::default();
+
+        if let Some(mut variance) = peel_variance {
+            Zip::from(&mut variance).and(&peel_flag).for_each(|v, f| {
+                if *f {
+                    *v = 0.;
+                }
+            });
+
+            add_model_variance(
+                main_row,
+                row,
+                self.mainpath,
+                &variance,
+                &peel_flag,
+                self.weight_spectrum,
+                &mut original,
+                &mut update,
+            )?;
+        }
+
+        if !self.keep_flags {
+            original.flag = Some(main_flag);
+            update.flag = Some(peel_flag);
+        }
+
+        if self.subtract && !self.created_residual {
+            original.residual = Some(self.residual_col.get(main_row, row, self.mainpath)?);
+        }
+
+        if self.subtract && self.subtract_incrementally {
+            update.residual = original.residual.as_ref().map(|r| r - &peel_model);
+        }
+
+        if !self.created_output {
+            let main_model = self.output_col.get(main_row, row, self.mainpath)?;
+
+            if self.add_incrementally {
+                peel_model += &main_model;
+            }
+
+            original.model = Some(main_model);
+        }
+
+        if self.subtract && !self.subtract_incrementally {
+            let main_data = self.data_col.get(main_row, row, self.mainpath)?;
+            update.residual = Some(main_data - &peel_model);
+        }
+
+        update.model = Some(peel_model);
+        Ok((original, update))
+    }
+
+    /// Work out the new contents of the cells of a main row that isn't being
+    /// peeled, but whose output or residual column was created by the peel
+    /// and so must be filled in. Its peeled model is zero. The created
+    /// columns had no contents, so there's nothing to save in the journal.
+    fn fill_cells(&self, main_row: &mut TableRow, row: u64) -> Result<(RowCells, RowCells)> {
+        let mut update = RowCells::default();
+
+        if !(self.created_output || self.created_residual) {
+            return Ok((RowCells::default(), update));
+        }
+
+        // The data column might not exist if we're not subtracting, so get
+        // the shape of the row from its flags.
+        let main_flag: Array<bool, Ix2> =
+            getcell_context(main_row, self.flag_column, row, self.mainpath)?;
+
+        let main_model = if self.created_output {
+            Array::zeros(main_flag.dim())
+        } else {
+            self.output_col.get(main_row, row, self.mainpath)?
+        };
+
+        if self.created_residual {
+            update.residual = Some(self.data_col.get(main_row, row, self.mainpath)? - &main_model);
+        }
+
+        if self.created_output {
+            update.model = Some(main_model);
+        }
+
+        Ok((RowCells::default(), update))
+    }
+}
+
+/// Undo a peel by restoring the main columns and flags saved with
+/// `--backup`.
+fn do_restore(
+    mainpath: &Path,
+    name: &str,
+    flag_column: &str,
+    keep_backup: bool,
+    nbe: &mut dyn NotificationBackend,
+) -> Result<i32> {
+    let mut main_table = ctry!(
+        Table::open(mainpath, TableOpenMode::ReadWrite);
+        "failed to open table \"{}\"", mainpath.display()
+    );
+    let col_names = ctry!(
+        main_table.column_names();
+        "failed to get names of columns in \"{}\"", mainpath.display()
+    );
+    let have_flag_row = col_names.iter().any(|n| n == "FLAG_ROW");
+
+    let mut columns = Vec::new();
+
+    for (col, backup_col) in Backup::find_columns(&mut main_table, mainpath, name)? {
+        if !col_names.contains(&col) {
+            return err_msg!(
+                "column \"{}\" of \"{}\" is a backup of column \"{}\", which no longer exists",
+                backup_col,
+                mainpath.display(),
+                col
+            );
+        }
+
+        let is_real = is_real_column(&mut main_table, mainpath, &backup_col)?;
+        columns.push((col, backup_col, is_real));
+    }
+
+    let flag_path = flag_versions_dir(mainpath).join(format!("flags.{}", name));
+
+    let mut flags = if flag_path.exists() {
+        let mut table = ctry!(
+            Table::open(&flag_path, TableOpenMode::Read);
+            "failed to open table \"{}\"", flag_path.display()
+        );
+
+        if table.n_rows() != main_table.n_rows() {
+            return err_msg!(
+                "flag version table \"{}\" has {} rows, but the main table has {}",
+                flag_path.display(),
+                table.n_rows(),
+                main_table.n_rows()
+            );
+        }
+
+        let row = table.get_row_reader()?;
+        Some((table, row))
+    } else {
+        None
+    };
+
+    if columns.is_empty() && flags.is_none() {
+        rn_fatal!(
+            nbe,
+            "no backup named \"{}\" was found for \"{}\"",
+            name,
+            mainpath.display()
+        );
+        return Ok(1);
+    }
+
+    let n_rows = main_table.n_rows();
+    let mut main_row = main_table.get_row_reader()?;
+    let mut pb = pbr::ProgressBar::new(n_rows);
+    pb.set_max_refresh_rate(Some(std::time::Duration::from_millis(500)));
+
+    for row in 0..n_rows {
+        if !columns.is_empty() {
+            ctry!(
+                main_table.read_row(&mut main_row, row);
+                "failed to read row #{} from \"{}\"", row, mainpath.display()
+            );
+        }
+
+        for (col, backup_col, is_real) in &columns {
+            Backup::copy_cell(
+                &mut main_table,
+                &mut main_row,
+                (col, *is_real),
+                (backup_col, col),
+                row,
+                mainpath,
+            )?;
+        }
+
+        if let Some((flag_table, flag_row)) = &mut flags {
+            ctry!(
+                flag_table.read_row(flag_row, row);
+                "failed to read row #{} from \"{}\"", row, flag_path.display()
+            );
+            let flag: Array<bool, Ix2> = ctry!(
+                flag_row.get_cell("FLAG");
+                "failed to read column \"FLAG\" of row #{} of file \"{}\"", row, flag_path.display()
+            );
+            ctry!(
+                main_table.put_cell(flag_column, row, &flag);
+                "failed to write column \"{}\" of row #{} of file \"{}\"", flag_column, row, mainpath.display()
+            );
+
+            if have_flag_row {
+                let flag_row_value: bool = ctry!(
+                    flag_row.get_cell("FLAG_ROW");
+                    "failed to read column \"FLAG_ROW\" of row #{} of file \"{}\"", row, flag_path.display()
+                );
+                ctry!(
+                    main_table.put_cell("FLAG_ROW", row, &flag_row_value);
+                    "failed to write column \"FLAG_ROW\" of row #{} of file \"{}\"", row, mainpath.display()
+                );
+            }
+        }
+
+        pb.inc();
+    }
+
+    pb.finish();
+    drop(main_row);
+
+    for (col, backup_col, _) in &columns {
+        rn_note!(nbe, "restored column \"{}\" from \"{}\"", col, backup_col);
+    }
+
+    if flags.is_some() {
+        rn_note!(
+            nbe,
+            "restored the flags from flag version \"{}\"",
+            flag_path.display()
+        );
+    }
+
+    // The flag version is left in place, like any other, for CASA's
+    // `flagmanager` to deal with.
+
+    if !keep_backup {
+        for (_, backup_col, _) in &columns {
+            ctry!(
+                main_table.remove_column(backup_col);
+                "failed to remove column \"{}\" from \"{}\"", backup_col, mainpath.display()
+            );
+            rn_note!(nbe, "removed backup column \"{}\"", backup_col);
+        }
+    }
+
+    Ok(0)
+}
+
+/// Copies of the main data that are saved before they are overwritten, so
+/// that a peel can be undone with `--restore`.
+struct Backup {
+    /// Tuples of (column, backup column, whether the column is real).
+    columns: Vec<(String, String, bool)>,
+
+    /// The main column holding the flags.
+    flag_column: String,
+
+    /// The flag version table into which the flags and FLAG_ROW are saved.
+    flags: Option<(PathBuf, Table)>,
+
+    /// Whether the main table has a FLAG_ROW column.
+    have_flag_row: bool,
+}
+
+impl Backup {
+    fn column_name(col_name: &str, backup_name: &str) -> String {
+        format!("{}_BACKUP_{}", col_name, backup_name)
+    }
+
+    /// Record in the keywords of a backup column which backup it belongs to
+    /// and which column it saves, so that `--restore` doesn't have to guess
+    /// from its name.
+    fn record_column(
+        table: &mut Table,
+        path: &Path,
+        col_name: &str,
+        backup_col_name: &str,
+        backup_name: &str,
+    ) -> Result<()> {
+        for (kw, value) in [("PEEL_BACKUP", backup_name), ("PEEL_BACKUP_OF", col_name)] {
+            ctry!(
+                table.put_column_keyword(backup_col_name, kw, &value.to_owned());
+                "failed to set keyword {} of column \"{}\" in \"{}\"", kw, backup_col_name, path.display()
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Find the columns of a table that belong to the named backup, as
+    /// recorded by `record_column`. Returns tuples of (column, backup column).
+    fn find_columns(
+        table: &mut Table,
+        path: &Path,
+        backup_name: &str,
+    ) -> Result<Vec<(String, String)>> {
+        let col_names = ctry!(
+            table.column_names();
+            "failed to get names of columns in \"{}\"", path.display()
+        );
+        let mut columns = Vec::new();
+
+        for n in col_names {
+            let mut kws = ctry!(
+                table.get_column_keyword_record(&n);
+                "failed to read the keywords of column \"{}\" in \"{}\"", n, path.display()
+            );
+
+            if kws.get_field::<String>("PEEL_BACKUP").ok().as_deref() != Some(backup_name) {
+                continue;
+            }
+
+            let col = ctry!(
+                kws.get_field::<String>("PEEL_BACKUP_OF");
+                "backup column \"{}\" in \"{}\" lacks the PEEL_BACKUP_OF keyword", n, path.display()
+            );
+            columns.push((col, n));
+        }
+
+        Ok(columns)
+    }
+
+    /// Copy a cell of a row that has been read into `row_data` between two
+    /// columns, `cols` = (from, to), whose type is that of the column
+    /// `col`, given as (name, whether it is real): WEIGHT and SIGMA are
+    /// vectors, and the other columns that we back up are visibility-like.
+    fn copy_cell(
+        table: &mut Table,
+        row_data: &mut TableRow,
+        col: (&str, bool),
+        cols: (&str, &str),
+        row: u64,
+        path: &Path,
+    ) -> Result<()> {
+        fn copy<T: CasaDataType>(
+            table: &mut Table,
+            row_data: &mut TableRow,
+            cols: (&str, &str),
+            row: u64,
+            path: &Path,
+        ) -> Result<()> {
+            let value: T = ctry!(
+                row_data.get_cell(cols.0);
+                "failed to read column \"{}\" of row #{} of file \"{}\"", cols.0, row, path.display()
+            );
+            ctry!(
+                table.put_cell(cols.1, row, &value);
+                "failed to write column \"{}\" of row #{} of file \"{}\"", cols.1, row, path.display()
+            );
+            Ok(())
+        }
+
+        match col {
+            ("WEIGHT" | "SIGMA", _) => copy::<Array<f32, Ix1>>(table, row_data, cols, row, path),
+            (_, true) => copy::<Array<f32, Ix2>>(table, row_data, cols, row, path),
+            (_, false) => copy::<Array<Complex<f32>, Ix2>>(table, row_data, cols, row, path),
+        }
+    }
+
+    /// Make sure that everything saved so far is written to disk.
+    fn commit(&mut self) -> Result<()> {
+        if let Some((path, table)) = self.flags.take() {
+            drop(table);
+
+            let table = ctry!(
+                Table::open(&path, TableOpenMode::ReadWrite);
+                "failed to open table \"{}\"", path.display()
+            );
+
+            self.flags = Some((path, table));
+        }
+
+        Ok(())
+    }
+
+    /// Save the current contents of a main row, which must have been read
+    /// into `main_row`.
+    fn save_row(
+        &mut self,
+        main_table: &mut Table,
+        main_row: &mut TableRow,
+        row: u64,
+        mainpath: &Path,
+    ) -> Result<()> {
+        for (col, backup_col, is_real) in &self.columns {
+            Self::copy_cell(
+                main_table,
+                main_row,
+                (col, *is_real),
+                (col, backup_col),
+                row,
+                mainpath,
+            )?;
+        }
+
+        let flag_column = &self.flag_column;
+
+        if let Some((flag_path, flag_table)) = &mut self.flags {
+            let flag: Array<bool, Ix2> = ctry!(
+                main_row.get_cell(flag_column);
+                "failed to read column \"{}\" of row #{} of file \"{}\"", flag_column, row, mainpath.display()
+            );
+
+            let flag_row = if self.have_flag_row {
+                ctry!(
+                    main_row.get_cell::<bool>("FLAG_ROW");
+                    "failed to read column \"FLAG_ROW\" of row #{} of file \"{}\"", row, mainpath.display()
+                )
+            } else {
+                false
+            };
+
+            ctry!(
+                flag_table.put_cell("FLAG", row, &flag);
+                "failed to write column \"FLAG\" of row #{} of file \"{}\"", row, flag_path.display()
+            );
+            ctry!(
+                flag_table.put_cell("FLAG_ROW", row, &flag_row);
+                "failed to write column \"FLAG_ROW\" of row #{} of file \"{}\"", row, flag_path.display()
+            );
+        }
+
+        Ok(())
+    }
+}
+
+/// The record of the progress of a peel, saved alongside the main table so
+/// that an interrupted peel can be resumed.
+struct Checkpoint {
+    /// A summary of the command-line settings of the peel.
+    settings: String,
+
+    /// Whether the output and residual columns were created by the peel.
+    created_output: bool,
+    created_residual: bool,
+
+    /// The number of main rows to be processed, and the number that have
+    /// been committed.
+    rows_total: usize,
+    rows_done: usize,
+}
+
+impl Checkpoint {
+    /// Get the paths of the checkpoint and journal files for the data set at
+    /// `mainpath`.
+    fn paths(mainpath: &Path) -> (PathBuf, PathBuf) {
+        let with_suffix = |suffix: &str| {
+            let mut name = mainpath.file_name().unwrap_or_default().to_owned();
+            name.push(suffix);
+            mainpath.with_file_name(name)
+        };
+
+        (
+            with_suffix(".peel-checkpoint"),
+            with_suffix(".peel-journal"),
+        )
+    }
+
+    fn load(path: &Path) -> Result<Option<Self>> {
+        let text = match std::fs::read_to_string(path) {
+            Ok(t) => t,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => {
+                return Err(anyhow::Error::new(e)
+                    .context(format!("failed to read checkpoint \"{}\"", path.display())));
+            }
+        };
+
+        let mut fields = HashMap::new();
+
+        for line in text.lines() {
+            if let Some((key, value)) = line.split_once(' ') {
+                fields.insert(key, value);
+            }
+        }
+
+        let parsed = (|| {
+            Some(Checkpoint {
+                settings: fields.get("settings")?.to_string(),
+                created_output: fields.get("created_output")?.parse().ok()?,
+                created_residual: fields.get("created_residual")?.parse().ok()?,
+                rows_total: fields.get("rows_total")?.parse().ok()?,
+                rows_done: fields.get("rows_done")?.parse().ok()?,
+            })
+        })();
+
+        match parsed {
+            Some(c) => Ok(Some(c)),
+            None => err_msg!("checkpoint file \"{}\" is malformed", path.display()),
+        }
+    }
+
+    /// Save the checkpoint, replacing the file atomically. The new file and
+    /// the rename are on disk when this returns, so that a journal for the
+    /// rows that it records as done can safely be deleted.
+    fn save(&self, path: &Path) -> Result<()> {
+        let mut temp_path = path.as_os_str().to_owned();
+        temp_path.push(".tmp");
+        let temp_path = PathBuf::from(temp_path);
+
+        let text = format!(
+            "settings {}\ncreated_output {}\ncreated_residual {}\nrows_total {}\nrows_done {}\n",
+            self.settings,
+            self.created_output,
+            self.created_residual,
+            self.rows_total,
+            self.rows_done
+        );
+
+        let result = File::create(&temp_path).and_then(|mut f| {
+            f.write_all(text.as_bytes())?;
+            f.sync_all()
+        });
+        ctry!(result; "failed to write checkpoint \"{}\"", temp_path.display());
+        ctry!(
+            std::fs::rename(&temp_path, path);
+            "failed to write checkpoint \"{}\"", path.display()
+        );
+
+        let dir = match path.parent() {
+            Some(p) if !p.as_os_str().is_empty() => p,
+            _ => Path::new("."),
+        };
+        let result = File::open(dir).and_then(|d| d.sync_all());
+        ctry!(result; "failed to sync directory \"{}\"", dir.display());
+        Ok(())
+    }
+}
+
+/// Summarize the command-line settings of a peel, so that we can check that
+/// an interrupted peel is resumed with the same ones.
+fn settings_summary(matches: &ArgMatches) -> String {
+    matches
+        .ids()
+        .map(|id| id.as_str())
+        .filter(|id| !["resume", "chunk_rows"].contains(id))
+        .sorted()
+        .map(|id| {
+            let values = matches
+                .get_raw(id)
+                .map(|v| v.collect::<Vec<_>>())
+                .unwrap_or_default();
+            format!("{}={:?}", id, values)
+        })
+        .join(" ")
+}
+
+/// The contents of the cells of a main row that are overwritten by peeling.
+/// Those that aren't being overwritten are None.
+#[derive(Default)]
+struct RowCells {
+    flag: Option<Array<bool, Ix2>>,
+    model: Option<Array<Complex<f32>, Ix2>>,
+    residual: Option<Array<Complex<f32>, Ix2>>,
+    weight: Option<Array<f32, Ix1>>,
+    sigma: Option<Array<f32, Ix1>>,
+    weight_spectrum: Option<Array<f32, Ix2>>,
+}
+
+impl RowCells {
+    /// Write the cells into a row of the main table. The flag, output, and
+    /// residual columns are given in `cols`.
+    fn write(
+        &self,
+        table: &mut Table,
+        row: u64,
+        path: &Path,
+        cols: (&str, VisColumn, VisColumn),
+    ) -> Result<()> {
+        fn put<T: CasaDataType>(
+            table: &mut Table,
+            col_name: &str,
+            row: u64,
+            value: &Option<T>,
+            path: &Path,
+        ) -> Result<()> {
+            if let Some(v) = value {
+                ctry!(
+                    table.put_cell(col_name, row, v);
+                    "failed to write column \"{}\" of row #{} of file \"{}\"", col_name, row, path.display()
+                );
+            }
+
+            Ok(())
+        }
+
+        put(table, cols.0, row, &self.flag, path)?;
+        if let Some(model) = &self.model {
+            cols.1.put(table, row, model, path)?;
+        }
+
+        if let Some(residual) = &self.residual {
+            cols.2.put(table, row, residual, path)?;
+        }
+
+        put(table, "WEIGHT", row, &self.weight, path)?;
+        put(table, "SIGMA", row, &self.sigma, path)?;
+        put(table, "WEIGHT_SPECTRUM", row, &self.weight_spectrum, path)?;
+        Ok(())
+    }
+
+    fn save<W: Write>(&self, stream: &mut W) -> io::Result<()> {
+        fn write_complex<W: Write>(stream: &mut W, c: &Complex<f32>) -> io::Result<()> {
+            stream.write_f32::<LittleEndian>(c.re)?;
+            stream.write_f32::<LittleEndian>(c.im)
+        }
+
+        save_cell(stream, &self.flag, |s, f| s.write_u8(*f as u8))?;
+        save_cell(stream, &self.model, write_complex)?;
+        save_cell(stream, &self.residual, write_complex)?;
+        save_cell(stream, &self.weight, |s, w| s.write_f32::<LittleEndian>(*w))?;
+        save_cell(stream, &self.sigma, |s, w| s.write_f32::<LittleEndian>(*w))?;
+        save_cell(stream, &self.weight_spectrum, |s, w| {
+            s.write_f32::<LittleEndian>(*w)
+        })
+    }
+
+    fn load<R: Read>(stream: &mut R) -> Result<Self> {
+        fn read_complex<R: Read>(stream: &mut R) -> io::Result<Complex<f32>> {
+            let re = stream.read_f32::<LittleEndian>()?;
+            let im = stream.read_f32::<LittleEndian>()?;
+            Ok(Complex::new(re, im))
+        }
+
+        Ok(RowCells {
+            flag: load_cell(stream, |s| s.read_u8().map(|f| f != 0))?,
+            model: load_cell(stream, read_complex)?,
+            residual: load_cell(stream, read_complex)?,
+            weight: load_cell(stream, |s| s.read_f32::<LittleEndian>())?,
+            sigma: load_cell(stream, |s| s.read_f32::<LittleEndian>())?,
+            weight_spectrum: load_cell(stream, |s| s.read_f32::<LittleEndian>())?,
+        })
+    }
+}
+
+/// Serialize an optional array as its number of dimensions (zero if it is
+/// None), its shape, and its elements.
+fn save_cell<W: Write, T, D: Dimension>(
+    stream: &mut W,
+    cell: &Option<Array<T, D>>,
+    write_elem: impl Fn(&mut W, &T) -> io::Result<()>,
+) -> io::Result<()> {
+    match cell {
+        None => stream.write_u8(0),
+
+        Some(a) => {
+            stream.write_u8(a.ndim() as u8)?;
+
+            for n in a.shape() {
+                stream.write_u64::<LittleEndian>(*n as u64)?;
+            }
+
+            for x in a.iter() {
+                write_elem(stream, x)?;
+            }
+
+            Ok(())
+        }
+    }
+}
+
+/// Deserialize an optional array saved by `save_cell`.
+fn load_cell<R: Read, T, D: Dimension>(
+    stream: &mut R,
+    read_elem: impl Fn(&mut R) -> io::Result<T>,
+) -> Result<Option<Array<T, D>>> {
+    let ndim = stream.read_u8()?;
+
+    if ndim == 0 {
+        return Ok(None);
+    }
+
+    let mut shape = Vec::with_capacity(ndim as usize);
+
+    for _ in 0..ndim {
+        shape.push(stream.read_u64::<LittleEndian>()? as usize);
+    }
+
+    let n = shape.iter().product::<usize>();
+    let mut data = Vec::with_capacity(n);
+
+    for _ in 0..n {
+        data.push(read_elem(stream)?);
+    }
+
+    Ok(Some(
+        Array::from_shape_vec(IxDyn(&shape), data)?.into_dimensionality::<D>()?,
+    ))
+}
+
+/// Save the current contents of a chunk of main rows that are about to be
+/// overwritten, making sure that they're on disk before we continue. The
+/// chunk starts after the first `rows_done` rows to be processed. Each entry
+/// of `updates` gives a row number and its current and new contents.
+fn save_journal(
+    path: &Path,
+    rows_done: usize,
+    updates: &[(u64, RowCells, RowCells)],
+) -> Result<()> {
+    let file = ctry!(
+        File::create(path);
+        "failed to create journal \"{}\"", path.display()
+    );
+    let mut stream = BufWriter::new(file);
+
+    let result = (|| {
+        stream.write_u64::<LittleEndian>(rows_done as u64)?;
+
+        for (row, original, _) in updates {
+            stream.write_u64::<LittleEndian>(*row)?;
+            original.save(&mut stream)?;
+        }
+
+        // This marks the journal as complete.
+        stream.write_u64::<LittleEndian>(u64::MAX)?;
+        stream.flush()?;
+        stream.get_ref().sync_all()
+    })();
+
+    ctry!(result; "failed to write journal \"{}\"", path.display());
+    Ok(())
+}
+
+/// Restore the main rows saved in a journal by `save_journal`, returning the
+/// number of rows restored.
+fn replay_journal(
+    path: &Path,
+    rows_done: usize,
+    table: &mut Table,
+    mainpath: &Path,
+    cols: (&str, VisColumn, VisColumn),
+) -> Result<usize> {
+    let entries = read_journal(path, rows_done)?;
+
+    for (row, cells) in &entries {
+        cells.write(table, *row, mainpath, cols)?;
+    }
+
+    Ok(entries.len())
+}
+
+/// Read the main rows saved in a journal by `save_journal`. If the journal is
+/// incomplete, we must have been interrupted before any of its rows were
+/// overwritten, and if it is for a chunk other than the one following the
+/// first `rows_done` rows, its chunk was committed. Either way, there's
+/// nothing to restore.
+fn read_journal(path: &Path, rows_done: usize) -> Result<Vec<(u64, RowCells)>> {
+    let file = ctry!(
+        File::open(path);
+        "failed to open journal \"{}\"", path.display()
+    );
+    let mut stream = BufReader::new(file);
+    let mut entries = Vec::new();
+
+    match stream.read_u64::<LittleEndian>() {
+        Ok(n) if n == rows_done as u64 => {}
+        _ => return Ok(Vec::new()),
+    }
+
+    loop {
+        let row = match stream.read_u64::<LittleEndian>() {
+            Ok(u64::MAX) => break,
+            Ok(r) => r,
+            Err(_) => return Ok(Vec::new()),
+        };
+
+        match RowCells::load(&mut stream) {
+            Ok(cells) => entries.push((row, cells)),
+            Err(_) => return Ok(Vec::new()),
+        }
+    }
+
+    Ok(entries)
+}
+
+/// Statistics of the implied gain ratios, accumulated for a diagnostic
+/// report. Since main rows are usually sorted by time, the samples of each
+/// group are summarized whenever the time changes, to keep memory usage
+/// bounded. If the rows are not sorted, a group may be reported more than
+/// once.
+struct GainReport {
+    current_time: f64,
+
+    /// The samples of the current timeslot, keyed by (source, antenna, spw).
+    pending: HashMap<(usize, i32, i32), GainSamples>,
+
+    table: ResultTable,
+}
+
+#[derive(Default)]
+struct GainSamples {
+    n_flagged: usize,
+    ratios: Vec<Complex<f32>>,
+}
+
+impl GainReport {
+    fn new(mainpath: &Path, time_reference: String) -> Self {
+        let mut table = ResultTable::new(mainpath, "peel-gains", time_reference);
+        let time_description = format!("timeslot, in {}", table.time_reference);
+
+        table.columns = vec![
+            Column::new("source", "index of the work data set"),
+            Column::new("time", time_description),
+            Column::new("antenna", "antenna number"),
+            Column::new("spw", "spectral window number"),
+            Column::new("n_samples", "number of samples"),
+            Column::new("flagged_frac", "fraction of samples flagged"),
+            Column::new("amp_median", "median gain ratio amplitude"),
+            Column::new(
+                "amp_scatter",
+                "robust standard deviation of the gain ratio amplitude",
+            ),
+            Column::new("phase_median", "median gain ratio phase, in radians"),
+            Column::new(
+                "phase_scatter",
+                "robust standard deviation of the gain ratio phase, in radians",
+            ),
+        ];
+
+        GainReport {
+            current_time: f64::NAN,
+            pending: HashMap::new(),
+            table,
+        }
+    }
+
+    /// Add the gain ratios of one baseline. `key` is (time, ant1, ant2, spw).
+    fn add(
+        &mut self,
+        source: usize,
+        key: (f64, i32, i32, i32),
+        ratio: &Array<Complex<f32>, Ix2>,
+        flag: &Array<bool, Ix2>,
+    ) {
+        let (time, ant1, ant2, spw) = key;
+
+        if time.to_bits() != self.current_time.to_bits() {
+            self.flush();
+            self.current_time = time;
+        }
+
+        let ants = if ant1 == ant2 {
+            &[ant1][..]
+        } else {
+            &[ant1, ant2][..]
+        };
+
+        for ant in ants {
+            let samples = self.pending.entry((source, *ant, spw)).or_default();
+
+            for (r, f) in ratio.iter().zip(flag.iter()) {
+                if *f {
+                    samples.n_flagged += 1;
+                } else {
+                    samples.ratios.push(*r);
+                }
+            }
+        }
+    }
+
+    fn flush(&mut self) {
+        let mut keys = self.pending.keys().copied().collect::<Vec<_>>();
+        keys.sort_unstable();
+
+        for key in keys {
+            let samples = self.pending.remove(&key).unwrap();
+            let (source, ant, spw) = key;
+            let n_good = samples.ratios.len();
+            let n_samples = n_good + samples.n_flagged;
+
+            // Phases are measured relative to the direction of the mean
+            // ratio to avoid problems with wrapping.
+
+            let mut amps = samples.ratios.iter().map(|r| r.norm()).collect::<Vec<_>>();
+            let mean_dir = samples.ratios.iter().sum::<Complex<f32>>().arg();
+            let mut phases = samples
+                .ratios
+                .iter()
+                .map(|r| (r * Complex::from_polar(1., -mean_dir)).arg())
+                .collect::<Vec<_>>();
+
+            let (amp_median, amp_scatter) = robust_stats(&mut amps);
+            let (phase_median, phase_scatter) = robust_stats(&mut phases);
+            let phase_median = Complex::from_polar(1., phase_median + mean_dir as f64).arg();
+
+            self.table.rows.push(vec![
+                Value::Int(source as i64),
+                Value::Float(self.current_time),
+                Value::Int(ant as i64),
+                Value::Int(spw as i64),
+                Value::Int(n_samples as i64),
+                Value::Float(samples.n_flagged as f64 / n_samples as f64),
+                Value::Float(amp_median),
+                Value::Float(amp_scatter),
+                Value::Float(phase_median),
+                Value::Float(phase_scatter),
+            ]);
+        }
+    }
+
+    fn finish(mut self) -> ResultTable {
+        self.flush();
+        self.table
+    }
+}
+
+/// Compute the median and robust standard deviation (scaled median absolute
+/// deviation) of a set of values, which are modified. Returns NaNs if there
+/// are no values.
+fn robust_stats(values: &mut [f32]) -> (f64, f64) {
+    fn median(values: &mut [f32]) -> f32 {
+        let mid = values.len() / 2;
+        *values.select_nth_unstable_by(mid, |a, b| a.total_cmp(b)).1
+    }
+
+    if values.is_empty() {
+        return (f64::NAN, f64::NAN);
+    }
+
+    let med = median(values);
+
+    for v in values.iter_mut() {
+        *v = (*v - med).abs();
+    }
+
+    (med as f64, 1.4826 * median(values) as f64)
+}
+
+/// Sanity limits on the amplitudes of the implied direction-dependent gain
+/// ratios.
+#[derive(Clone, Copy, Debug)]
+struct GainLimits {
+    min_amp: Option<f32>,
+    max_amp: Option<f32>,
+
+    /// The maximum deviation from the baseline median, in units of the robust
+    /// standard deviation.
+    max_sigma: Option<f32>,
+
+    /// If true, out-of-range ratios are clipped rather than flagged.
+    clip: bool,
+}
+
+impl GainLimits {
+    fn is_active(&self) -> bool {
+        self.min_amp.is_some() || self.max_amp.is_some() || self.max_sigma.is_some()
+    }
+
+    /// Check a gain ratio against the limits, flagging or clipping it as
+    /// needed. `stats` gives the median and robust standard deviation of the
+    /// ratio amplitudes of the relevant baseline, spectral window and
+    /// polarization, if known. A ratio of zero has no phase, so it can't be
+    /// clipped up to a minimum amplitude and is always flagged instead.
+    fn apply(&self, ratio: &mut Complex<f32>, flag: &mut bool, stats: Option<AmpStats>) {
+        if *flag {
+            return;
+        }
+
+        let mut lo = self.min_amp.unwrap_or(0.);
+        let mut hi = self.max_amp.unwrap_or(f32::INFINITY);
+
+        if let (Some(n), Some((med, sigma))) = (self.max_sigma, stats) {
+            lo = lo.max(med - n * sigma);
+            hi = hi.min(med + n * sigma);
+        }
+
+        let amp = ratio.norm();
+
+        if amp >= lo && amp <= hi {
+            return;
+        }
+
+        if self.clip && amp > 0. && lo <= hi {
+            *ratio *= amp.clamp(lo, hi) / amp;
+        } else {
+            *flag = true;
+        }
+    }
+}
+
+/// The solutions in a CASA calibration table, such as those produced by
+/// `gaincal` or `bandpass`. In CASA's convention, the observed visibilities
+/// are related to the true ones by V_ij = g_i g_j* V_ij,true, and `applycal`
+/// divides these gains out.
+struct CalTable {
+    /// Solutions for each (antenna, spectral window, field), sorted by time.
+    solutions: HashMap<(i32, i32, i32), Vec<CalSolution>>,
+
+    /// For each main FIELD_ID, the field whose solutions are applied to it.
+    field_map: Vec<i32>,
+}
+
+struct CalSolution {
+    time: f64,
+
+    /// The gains and their flags, with shape (n_chan, n_receptor).
+    gains: VisAndFlags,
+}
+
+impl CalTable {
+    /// Load a calibration table. Solutions are applied to main rows with the
+    /// same FIELD_ID. If the table has no solutions for one of the fields
+    /// described by `main_field_dirs`, those of the field nearest to it on
+    /// the sky are used instead, as with CASA's `gainfield="nearest"`.
+    fn load(
+        path: &Path,
+        main_field_dirs: &[(f64, f64)],
+        nbe: &mut dyn NotificationBackend,
+    ) -> Result<Self> {
+        let mut table = ctry!(
+            Table::open(path, TableOpenMode::Read);
+            "failed to open calibration table \"{}\"", path.display()
+        );
+        let mut solutions: HashMap<_, Vec<CalSolution>> = HashMap::new();
+        let mut row_num = 0;
+
+        ctry!(
+            table.for_each_row(|row| {
+                let ant = row.get_cell::<i32>("ANTENNA1")?;
+                let spw = row.get_cell::<i32>("SPECTRAL_WINDOW_ID")?;
+                let field = row.get_cell::<i32>("FIELD_ID")?;
+                let gains: Array<Complex<f32>, Ix2> = row.get_cell("CPARAM")?;
+                let flags: Array<bool, Ix2> = row.get_cell("FLAG")?;
+
+                if gains.is_empty() {
+                    return err_msg!("row #{} has an empty CPARAM", row_num);
+                }
+
+                if flags.shape() != gains.shape() {
+                    return err_msg!(
+                        "row #{} has CPARAM shape {:?} but FLAG shape {:?}",
+                        row_num,
+                        gains.shape(),
+                        flags.shape()
+                    );
+                }
+
+                let time: f64 = row.get_cell("TIME")?;
+
+                if !time.is_finite() {
+                    return err_msg!("row #{} has an invalid TIME {}", row_num, time);
+                }
+
+                solutions
+                    .entry((ant, spw, field))
+                    .or_default()
+                    .push(CalSolution {
+                        time,
+                        gains: (gains, flags),
+                    });
+                row_num += 1;
+                Ok(())
+            });
+            "failed to read calibration table \"{}\"", path.display()
+        );
+
+        for series in solutions.values_mut() {
+            series.sort_by(|a, b| a.time.total_cmp(&b.time));
+        }
+
+        // Work out which solutions to apply to each main field. The FIELD
+        // table of the calibration table is only needed if some main fields
+        // lack solutions.
+
+        let cal_fields = solutions.keys().map(|k| k.2).collect::<BTreeSet<_>>();
+        let mut cal_field_dirs = None;
+        let mut field_map = Vec::with_capacity(main_field_dirs.len());
+
+        for (field, dir) in main_field_dirs.iter().enumerate() {
+            let field = field as i32;
+
+            if cal_fields.is_empty() || cal_fields.contains(&field) {
+                field_map.push(field);
+                continue;
+            }
+
+            if cal_field_dirs.is_none() {
+                cal_field_dirs = Some(load_field_directions(path)?);
+            }
+
+            let cal_dirs = cal_field_dirs.as_ref().unwrap();
+            let nearest = cal_fields
+                .iter()
+                .filter_map(|f| {
+                    cal_dirs
+                        .get(*f as usize)
+                        .map(|d| (angular_separation(*dir, *d), *f))
+                })
+                .min_by(|a, b| a.0.total_cmp(&b.0));
+
+            match nearest {
+                Some((sep, f)) => {
+                    rn_note!(
+                        nbe,
+                        "calibration table \"{}\" has no solutions for field {}; using those \
+                         of field {}, {:.3} deg away",
+                        path.display(),
+                        field,
+                        f,
+                        sep.to_degrees()
+                    );
+                    field_map.push(f);
+                }
+
+                None => {
+                    return err_msg!(
+                        "calibration table \"{}\" has no solutions for field {}, and its FIELD \
+                         table doesn't describe the fields that it does have",
+                        path.display(),
+                        field
+                    );
+                }
+            }
+        }
+
+        Ok(CalTable {
+            solutions,
+            field_map,
+        })
+    }
+
+    /// Get the gains of an antenna in a spectral window at the specified
+    /// time, for a main row of the specified field. Gains are interpolated
+    /// between solutions in amplitude and phase separately. If only one of
+    /// the bracketing solutions is unflagged, it is used as-is. Returns None
+    /// if the table has no solutions for the antenna, window, and field.
+    fn gains_at(&self, ant: i32, spw: i32, field: i32, time: f64) -> Option<VisAndFlags> {
+        let field = *self.field_map.get(field as usize)?;
+        let series = self.solutions.get(&(ant, spw, field))?;
+        let idx = series.partition_point(|s| s.time <= time);
+
+        if idx == 0 {
+            return Some(series[0].gains.clone());
+        }
+
+        if idx == series.len() {
+            return Some(series[idx - 1].gains.clone());
+        }
+
+        let (s0, s1) = (&series[idx - 1], &series[idx]);
+        let (g0, f0) = &s0.gains;
+        let (g1, f1) = &s1.gains;
+
+        if g0.shape() != g1.shape() {
+            return Some(s0.gains.clone());
+        }
+
+        let frac = ((time - s0.time) / (s1.time - s0.time)) as f32;
+        let mut g = g0.clone();
+        let mut f = f0.clone();
+
+        Zip::from(&mut g)
+            .and(&mut f)
+            .and(g1)
+            .and(f1)
+            .for_each(|g, f, g1, f1| match (*f, *f1) {
+                (false, false) => *g = interpolate_gain(*g, *g1, frac),
+                (true, false) => {
+                    *g = *g1;
+                    *f = false;
+                }
+                _ => {}
+            });
+
+        Some((g, f))
+    }
+}
+
+/// The angular separation of two (longitude, latitude) directions, in
+/// radians.
+fn angular_separation((lon1, lat1): (f64, f64), (lon2, lat2): (f64, f64)) -> f64 {
+    // The haversine formula, which behaves well at small separations.
+    let h = ((lat2 - lat1) / 2.).sin().powi(2)
+        + lat1.cos() * lat2.cos() * ((lon2 - lon1) / 2.).sin().powi(2);
+    2. * h.sqrt().min(1.).asin()
+}
+
+/// Map a CASA correlation type code to the indices of the receptors of the
+/// two antennas that it involves.
+fn corr_receptors(code: i32) -> Option<(usize, usize)> {
+    match code {
+        5 | 9 => Some((0, 0)),  // RR, XX
+        6 | 10 => Some((0, 1)), // RL, XY
+        7 | 11 => Some((1, 0)), // LR, YX
+        8 | 12 => Some((1, 1)), // LL, YY
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: Complex<f32>, b: Complex<f32>) -> bool {
+        (a - b).norm() < 1e-5
+    }
+
+    #[test]
+    fn interpolate_gain_amplitude_and_phase() {
+        let g0 = Complex::from_polar(1., 0.);
+        let g1 = Complex::from_polar(3., 0.5 * PI);
+
+        assert!(close(interpolate_gain(g0, g1, 0.), g0));
+        assert!(close(interpolate_gain(g0, g1, 1.), g1));
+        assert!(close(
+            interpolate_gain(g0, g1, 0.5),
+            Complex::from_polar(2., 0.25 * PI)
+        ));
+    }
+
+    #[test]
+    fn interpolate_gain_unwraps_phase() {
+        // Going from +170 to -170 degrees should pass through 180, not 0.
+        let g0 = Complex::from_polar(1., 170f32.to_radians());
+        let g1 = Complex::from_polar(1., -170f32.to_radians());
+        assert!(close(interpolate_gain(g0, g1, 0.5), Complex::from(-1.)));
+    }
+
+    #[test]
+    fn robust_stats_ignore_outliers() {
+        let (med, sigma) = robust_stats(&mut [5., 1., 3., 2., 4.]);
+        assert_eq!(med, 3.);
+        assert!((sigma - 1.4826).abs() < 1e-6);
+
+        let (med, sigma) = robust_stats(&mut [1., 2., 3., 4., 100.]);
+        assert_eq!(med, 3.);
+        assert!((sigma - 1.4826).abs() < 1e-6);
+    }
+
+    #[test]
+    fn robust_stats_of_nothing() {
+        let (med, sigma) = robust_stats(&mut []);
+        assert!(med.is_nan());
+        assert!(sigma.is_nan());
+    }
+
+    #[test]
+    fn pol_map_matches_corr_types() {
+        // RR, RL, LR, LL vs. RR, LL, and the same set reordered.
+        assert_eq!(pol_map(&[5, 6, 7, 8], &[5, 6, 7, 8]), None);
+        assert_eq!(
+            pol_map(&[5, 6, 7, 8], &[5, 8]),
+            Some(vec![Some(0), None, None, Some(1)])
+        );
+        assert_eq!(
+            pol_map(&[5, 8], &[8, 7, 6, 5]),
+            Some(vec![Some(3), Some(0)])
+        );
+    }
+
+    #[test]
+    fn map_pols_fills_missing_products() {
+        let work = Array::from_shape_vec((2, 2), vec![1, 2, 3, 4]).unwrap();
+        let map = vec![Some(0), None, None, Some(1)];
+        let expected = Array::from_shape_vec((2, 4), vec![1, 0, 0, 2, 3, 0, 0, 4]).unwrap();
+        assert_eq!(map_pols(&work, &map, 0), expected);
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("rxpackage-test-{}-{}", std::process::id(), name))
+    }
+
+    fn sample_cells(scale: f32) -> RowCells {
+        RowCells {
+            flag: Some(Array::from_shape_fn((3, 2), |(i, j)| (i + j) % 2 == 0)),
+            model: Some(Array::from_shape_fn((3, 2), |(i, j)| {
+                Complex::new(scale * i as f32, -(j as f32))
+            })),
+            residual: None,
+            weight: Some(Array::from_vec(vec![scale, 2. * scale])),
+            sigma: None,
+            weight_spectrum: Some(Array::from_elem((3, 2), scale)),
+        }
+    }
+
+    #[test]
+    fn journal_round_trip() {
+        let path = temp_path("journal-round-trip");
+        let updates = vec![
+            (4, sample_cells(1.), RowCells::default()),
+            (9, sample_cells(2.5), RowCells::default()),
+        ];
+
+        save_journal(&path, 100, &updates).unwrap();
+        let entries = read_journal(&path, 100).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(entries.len(), updates.len());
+
+        for ((row, cells), (orig_row, orig, _)) in entries.iter().zip(&updates) {
+            assert_eq!(row, orig_row);
+            assert_eq!(cells.flag, orig.flag);
+            assert_eq!(cells.model, orig.model);
+            assert_eq!(cells.residual, orig.residual);
+            assert_eq!(cells.weight, orig.weight);
+            assert_eq!(cells.sigma, orig.sigma);
+            assert_eq!(cells.weight_spectrum, orig.weight_spectrum);
+        }
+    }
+
+    #[test]
+    fn journal_for_other_chunk_is_ignored() {
+        let path = temp_path("journal-other-chunk");
+        let updates = vec![(4, sample_cells(1.), RowCells::default())];
+
+        save_journal(&path, 100, &updates).unwrap();
+        let entries = read_journal(&path, 50).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(entries.is_empty());
+    }
+
+    #[test]
+    fn incomplete_journal_is_ignored() {
+        let path = temp_path("journal-incomplete");
+        let updates = vec![
+            (4, sample_cells(1.), RowCells::default()),
+            (9, sample_cells(2.5), RowCells::default()),
+        ];
+
+        save_journal(&path, 100, &updates).unwrap();
+
+        // Chop off the end-of-journal marker, as if we were interrupted.
+        let len = std::fs::metadata(&path).unwrap().len();
+        let file = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len(len - 8).unwrap();
+        drop(file);
+
+        let entries = read_journal(&path, 100).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(entries.is_empty());
+    }
+
+    #[test]
+    fn checkpoint_round_trip() {
+        let path = temp_path("checkpoint");
+        assert!(Checkpoint::load(&path).unwrap().is_none());
+
+        let checkpoint = Checkpoint {
+            settings: "subtract=[\"true\"] chunk_rows=[\"10\"]".to_owned(),
+            created_output: true,
+            created_residual: false,
+            rows_total: 1234,
+            rows_done: 560,
+        };
+
+        checkpoint.save(&path).unwrap();
+        let loaded = Checkpoint::load(&path).unwrap().unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded.settings, checkpoint.settings);
+        assert_eq!(loaded.created_output, checkpoint.created_output);
+        assert_eq!(loaded.created_residual, checkpoint.created_residual);
+        assert_eq!(loaded.rows_total, checkpoint.rows_total);
+        assert_eq!(loaded.rows_done, checkpoint.rows_done);
+    }
 }
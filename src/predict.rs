@@ -0,0 +1,751 @@
+// Copyright 2017-2022 Peter Williams <peter@newton.cx> and collaborators
+// Licensed under the MIT License.
+
+//! Predict the visibilities of simple source models.
+//!
+//! This fills a column of a data set (MODEL_DATA by default) with the
+//! visibilities of a list of point and Gaussian components, computed with a
+//! direct Fourier transform from the UVW coordinates, the phase center of each
+//! row's FIELD, and the CHAN_FREQ of its spectral window. It can replace the
+//! CASA component-list and `ft` steps of the peeling workflow described in
+//! the `peel` module.
+//!
+//! The components are read from a text file with one component per line.
+//! Blank lines and anything following a `#` are ignored. Each line gives the
+//! RA, the declination, and the flux density in Jy, separated by whitespace,
+//! followed by optional `KEY=VALUE` settings:
+//!
+//! ```text
+//! # RA          Dec          Flux   Options
+//! 12:30:49.42   +12:23:28.0  1.5    alpha=-0.7 freq=1.4e9
+//! 187.70593     12.39112     0.2    major=10 minor=4 pa=30
+//! ```
+//!
+//! The coordinates are in decimal degrees, or sexagesimal hours (RA) and
+//! degrees (declination) if they contain colons. They are assumed to be in the
+//! same frame as the FIELD phase centers, which are taken to be constant in
+//! time. The supported options are:
+//!
+//! - `alpha`: the spectral index, defaulting to zero.
+//! - `freq`: the frequency at which the flux density is given, in Hz. This is
+//!   required if the spectral index is nonzero.
+//! - `major` and `minor`: the FWHM of a Gaussian component along its major and
+//!   minor axes, in arcseconds. If only `major` is given, the Gaussian is
+//!   circular. If neither is given, the component is a point source.
+//! - `pa`: the position angle of the major axis of a Gaussian component, in
+//!   degrees east of north.
+//!
+//! The component list may also be given as a JSON file, if its name ends in
+//! `.json`. It must contain an array of objects, each with `ra`, `dec`, and
+//! `flux` fields and the options above as numeric fields. The coordinates may
+//! be numbers, in decimal degrees, or strings in either of the notations
+//! described above:
+//!
+//! ```json
+//! [
+//!   {"ra": "12:30:49.42", "dec": "+12:23:28.0", "flux": 1.5, "alpha": -0.7, "freq": 1.4e9},
+//!   {"ra": 187.70593, "dec": 12.39112, "flux": 0.2, "major": 10, "minor": 4, "pa": 30}
+//! ]
+//! ```
+//!
+//! The components are unpolarized, so the parallel-hand correlations (and
+//! Stokes I) get the model, and the cross-hand correlations are zero.
+
+use clap::{Arg, ArgAction, ArgMatches, Command};
+use ndarray::Ix2;
+use rubbl_casatables::GlueDataType;
+use rubbl_core::{
+    anyhow::{self, Result},
+    ctry,
+    notify::NotificationBackend,
+    rn_note, Array, Complex,
+};
+use std::{
+    self,
+    f64::consts::{LN_2, PI},
+    path::{Path, PathBuf},
+};
+
+use crate::msutil::{load_data_desc_info, load_field_directions, open_table};
+
+/// The speed of light, in m/s.
+const SPEED_OF_LIGHT: f64 = 299_792_458.0;
+
+/// A component of a source model.
+#[derive(Clone, Debug)]
+struct Component {
+    /// The RA, in radians.
+    ra: f64,
+
+    /// The declination, in radians.
+    dec: f64,
+
+    /// The flux density at `ref_freq`, in Jy.
+    flux: f64,
+
+    /// The reference frequency of the spectral model, in Hz.
+    ref_freq: f64,
+
+    spectral_index: f64,
+
+    /// The FWHM major and minor axes and the position angle of a Gaussian
+    /// component, all in radians. None for point sources.
+    shape: Option<(f64, f64, f64)>,
+}
+
+impl Component {
+    /// The flux density at the frequency `freq`, in Jy.
+    fn flux_at(&self, freq: f64) -> f64 {
+        if self.spectral_index == 0. {
+            self.flux
+        } else {
+            self.flux * (freq / self.ref_freq).powf(self.spectral_index)
+        }
+    }
+
+    /// The fractional visibility amplitude of the component's shape at the
+    /// given baseline coordinates, in wavelengths.
+    fn shape_factor(&self, u: f64, v: f64) -> f64 {
+        match self.shape {
+            None => 1.,
+
+            Some((major, minor, pa)) => {
+                // Project the baseline onto the axes of the Gaussian. The
+                // position angle is measured from north (+v) through east
+                // (+u).
+                let (sin_pa, cos_pa) = pa.sin_cos();
+                let u_major = u * sin_pa + v * cos_pa;
+                let u_minor = u * cos_pa - v * sin_pa;
+                let scale = PI * PI / (4. * LN_2);
+                (-scale * ((major * u_major).powi(2) + (minor * u_minor).powi(2))).exp()
+            }
+        }
+    }
+
+    /// The direction cosines (l, m, n - 1) of the component relative to a
+    /// phase center.
+    fn direction_cosines(&self, center_ra: f64, center_dec: f64) -> (f64, f64, f64) {
+        let (sin_dra, cos_dra) = (self.ra - center_ra).sin_cos();
+        let (sin_dec, cos_dec) = self.dec.sin_cos();
+        let (sin_dec0, cos_dec0) = center_dec.sin_cos();
+
+        let l = cos_dec * sin_dra;
+        let m = sin_dec * cos_dec0 - cos_dec * sin_dec0 * cos_dra;
+        let n = sin_dec * sin_dec0 + cos_dec * cos_dec0 * cos_dra;
+        (l, m, n - 1.)
+    }
+}
+
+/// Parse an angle given in decimal degrees or, if it contains colons, in
+/// sexagesimal notation whose first field is in units of `unit` degrees (15
+/// for hours of RA). Returns the angle in radians.
+fn parse_angle(text: &str, unit: f64) -> Option<f64> {
+    if !text.contains(':') {
+        return text
+            .parse::<f64>()
+            .ok()
+            .filter(|d| d.is_finite())
+            .map(|d| d.to_radians());
+    }
+
+    let (negative, body) = match text.strip_prefix('-') {
+        Some(b) => (true, b),
+        None => (false, text.strip_prefix('+').unwrap_or(text)),
+    };
+
+    let mut value = 0.;
+    let mut scale = unit;
+
+    for (i, piece) in body.split(':').enumerate() {
+        let v = piece.parse::<f64>().ok().filter(|v| *v >= 0. && i < 3)?;
+        value += v * scale;
+        scale /= 60.;
+    }
+
+    if negative {
+        value = -value;
+    }
+
+    Some(value.to_radians())
+}
+
+/// Check the description of a component and assemble it. The coordinates are
+/// in radians, and `settings` are the optional `KEY=VALUE` settings described
+/// in the module documentation. `location` says where the component came
+/// from, for error messages.
+fn make_component(
+    ra: f64,
+    dec: f64,
+    flux: f64,
+    settings: &[(&str, f64)],
+    location: &str,
+) -> Result<Component> {
+    if dec.abs() > PI / 2. {
+        return err_msg!("the declination is out of range in {}", location);
+    }
+
+    if !flux.is_finite() {
+        return err_msg!("the flux density is invalid in {}", location);
+    }
+
+    let mut ref_freq = None;
+    let mut spectral_index = 0.;
+    let mut major = None;
+    let mut minor = None;
+    let mut pa = 0.;
+
+    for &(key, v) in settings {
+        if !v.is_finite() {
+            return err_msg!("invalid setting \"{}={}\" in {}", key, v, location);
+        }
+
+        match key {
+            "alpha" => spectral_index = v,
+            "freq" if v > 0. => ref_freq = Some(v),
+            "major" if v >= 0. => major = Some((v / 3600.).to_radians()),
+            "minor" if v >= 0. => minor = Some((v / 3600.).to_radians()),
+            "pa" => pa = v.to_radians(),
+            _ => {
+                return err_msg!(
+                    "unrecognized or invalid setting \"{}={}\" in {}",
+                    key,
+                    v,
+                    location
+                );
+            }
+        }
+    }
+
+    if ref_freq.is_none() && spectral_index != 0. {
+        return err_msg!(
+            "a reference frequency (`freq`) must be given with the spectral index in {}",
+            location
+        );
+    }
+
+    let shape = match (major, minor) {
+        (None, None) => None,
+        (Some(maj), None) => Some((maj, maj, pa)),
+        (Some(maj), Some(min)) => Some((maj, min, pa)),
+        (None, Some(_)) => {
+            return err_msg!(
+                "a Gaussian's minor axis is given without its major axis in {}",
+                location
+            );
+        }
+    };
+
+    Ok(Component {
+        ra,
+        dec,
+        flux,
+        ref_freq: ref_freq.unwrap_or(1.),
+        spectral_index,
+        shape,
+    })
+}
+
+/// Load a component list in one of the formats described in the module
+/// documentation. Files whose names end in `.json` are parsed as JSON.
+fn load_components(path: &Path) -> Result<Vec<Component>> {
+    let text = ctry!(
+        std::fs::read_to_string(path);
+        "failed to read component list \"{}\"", path.display()
+    );
+
+    let is_json = path
+        .extension()
+        .is_some_and(|e| e.eq_ignore_ascii_case("json"));
+
+    if is_json {
+        parse_json_components(&text, path)
+    } else {
+        parse_text_components(&text, path)
+    }
+}
+
+/// Parse a component list in the text format.
+fn parse_text_components(text: &str, path: &Path) -> Result<Vec<Component>> {
+    let mut components = Vec::new();
+
+    for (i, line) in text.lines().enumerate() {
+        let location = format!("line {} of \"{}\"", i + 1, path.display());
+        let line = line.split('#').next().unwrap_or_default();
+        let mut pieces = line.split_whitespace();
+
+        let ra_text = match pieces.next() {
+            Some(t) => t,
+            None => continue,
+        };
+
+        let (dec_text, flux_text) = match (pieces.next(), pieces.next()) {
+            (Some(d), Some(f)) => (d, f),
+            _ => {
+                return err_msg!(
+                    "{} should have at least an RA, a declination, and a flux density",
+                    location
+                );
+            }
+        };
+
+        let ra = match parse_angle(ra_text, 15.) {
+            Some(a) => a,
+            None => {
+                return err_msg!("can't parse RA \"{}\" on {}", ra_text, location);
+            }
+        };
+
+        let dec = match parse_angle(dec_text, 1.) {
+            Some(a) => a,
+            None => {
+                return err_msg!("can't parse declination \"{}\" on {}", dec_text, location);
+            }
+        };
+
+        let flux = match flux_text.parse::<f64>() {
+            Ok(f) => f,
+            Err(_) => {
+                return err_msg!("can't parse flux density \"{}\" on {}", flux_text, location);
+            }
+        };
+
+        let mut settings = Vec::new();
+
+        for setting in pieces {
+            match setting
+                .split_once('=')
+                .and_then(|(k, v)| v.parse::<f64>().ok().map(|v| (k, v)))
+            {
+                Some(kv) => settings.push(kv),
+                None => {
+                    return err_msg!("can't parse setting \"{}\" on {}", setting, location);
+                }
+            }
+        }
+
+        components.push(make_component(ra, dec, flux, &settings, &location)?);
+    }
+
+    Ok(components)
+}
+
+/// Parse a component list in the JSON format.
+fn parse_json_components(text: &str, path: &Path) -> Result<Vec<Component>> {
+    let value: serde_json::Value = ctry!(
+        serde_json::from_str(text);
+        "failed to parse component list \"{}\" as JSON", path.display()
+    );
+
+    let items = match value.as_array() {
+        Some(a) => a,
+        None => {
+            return err_msg!(
+                "component list \"{}\" should contain a JSON array",
+                path.display()
+            );
+        }
+    };
+
+    let mut components = Vec::with_capacity(items.len());
+
+    for (i, item) in items.iter().enumerate() {
+        let location = format!("component {} of \"{}\"", i + 1, path.display());
+
+        let obj = match item.as_object() {
+            Some(o) => o,
+            None => {
+                return err_msg!("{} should be a JSON object", location);
+            }
+        };
+
+        // Coordinates may be given in decimal degrees or as sexagesimal text.
+        let angle = |key: &str, unit: f64| match obj.get(key) {
+            Some(serde_json::Value::Number(n)) => n.as_f64().map(f64::to_radians),
+            Some(serde_json::Value::String(t)) => parse_angle(t, unit),
+            _ => None,
+        };
+
+        let ra = match angle("ra", 15.) {
+            Some(a) => a,
+            None => {
+                return err_msg!("{} should have a valid \"ra\"", location);
+            }
+        };
+
+        let dec = match angle("dec", 1.) {
+            Some(a) => a,
+            None => {
+                return err_msg!("{} should have a valid \"dec\"", location);
+            }
+        };
+
+        let flux = match obj.get("flux").and_then(serde_json::Value::as_f64) {
+            Some(f) => f,
+            None => {
+                return err_msg!("{} should have a numeric \"flux\"", location);
+            }
+        };
+
+        let mut settings = Vec::new();
+
+        for (key, v) in obj {
+            if ["ra", "dec", "flux"].contains(&key.as_str()) {
+                continue;
+            }
+
+            match v.as_f64() {
+                Some(v) => settings.push((key.as_str(), v)),
+                None => {
+                    return err_msg!("setting \"{}\" should be a number in {}", key, location);
+                }
+            }
+        }
+
+        components.push(make_component(ra, dec, flux, &settings, &location)?);
+    }
+
+    Ok(components)
+}
+
+/// Whether a correlation, identified by its CORR_TYPE code, measures the
+/// total intensity of an unpolarized source: Stokes I and the parallel hands
+/// RR, LL, XX, and YY.
+fn is_total_intensity(code: i32) -> bool {
+    matches!(code, 1 | 5 | 8 | 9 | 12)
+}
+
+pub fn make_command() -> Command {
+    Command::new("predict")
+        .bin_name("rubbl rxpackage predict")
+        .about("Compute the visibilities of point and Gaussian components")
+        .arg(
+            Arg::new("column")
+                .long("column")
+                .value_name("COLUMN")
+                .default_value("MODEL_DATA")
+                .help("The column in which to store the model; created if missing"),
+        )
+        .arg(Arg::new("add").long("add").action(ArgAction::SetTrue).help(
+            "Add the model to the existing contents of the column, rather than replacing them",
+        ))
+        .arg(
+            Arg::new("COMPONENTS")
+                .value_parser(clap::value_parser!(PathBuf))
+                .help("The path of the component list, in text or (if named *.json) JSON format")
+                .required(true)
+                .index(1),
+        )
+        .arg(
+            Arg::new("TABLE")
+                .value_parser(clap::value_parser!(PathBuf))
+                .help("The path of the data set in which to store the model")
+                .required(true)
+                .index(2),
+        )
+}
+
+pub fn do_cli(matches: &ArgMatches, nbe: &mut dyn NotificationBackend) -> Result<i32> {
+    let components_path = matches.get_one::<PathBuf>("COMPONENTS").unwrap();
+    let inpath = matches.get_one::<PathBuf>("TABLE").unwrap();
+    let column = matches.get_one::<String>("column").unwrap().as_str();
+    let add = *matches.get_one::<bool>("add").unwrap();
+
+    let components = load_components(components_path)?;
+
+    // Load the ancillary information: phase centers, frequencies, and
+    // correlation types.
+
+    let ddinfo = load_data_desc_info(inpath)?;
+
+    let (spw_path, mut spw_table) = open_table(inpath, "SPECTRAL_WINDOW", true)?;
+    let mut spw_freqs = Vec::with_capacity(spw_table.n_rows() as usize);
+
+    for i in 0..spw_table.n_rows() {
+        spw_freqs.push(ctry!(
+            spw_table.get_cell_as_vec::<f64>("CHAN_FREQ", i);
+            "failed to read CHAN_FREQ of spectral window {} from \"{}\"", i, spw_path.display()
+        ));
+    }
+
+    let field_lmn = load_field_directions(inpath)?
+        .into_iter()
+        .map(|(lon, lat)| {
+            components
+                .iter()
+                .map(|c| c.direction_cosines(lon, lat))
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+
+    // Get the main table ready.
+
+    let (_, mut main_table) = open_table(inpath, "", false)?;
+
+    let col_names = ctry!(
+        main_table.column_names();
+        "failed to get names of columns in \"{}\"", inpath.display()
+    );
+    let created = !col_names.iter().any(|n| n == column);
+
+    // New columns are modeled on the data column, which is FLOAT_DATA in
+    // single-dish data sets. Models are always complex, though.
+
+    if created {
+        let template = match ["DATA", "FLOAT_DATA"]
+            .iter()
+            .find(|t| col_names.iter().any(|n| n == *t))
+        {
+            Some(t) => *t,
+            None => {
+                return err_msg!(
+                    "cannot create column \"{}\" in \"{}\": it has neither a DATA nor a FLOAT_DATA column",
+                    column,
+                    inpath.display()
+                );
+            }
+        };
+
+        let desc = ctry!(
+            main_table.get_col_desc(template);
+            "failed to get the description of column \"{}\" in \"{}\"", template, inpath.display()
+        );
+
+        ctry!(
+            main_table.add_array_column(
+                GlueDataType::TpComplex,
+                column,
+                None,
+                desc.shape(),
+                desc.is_fixed_shape(),
+                false
+            );
+            "failed to create column \"{}\" in \"{}\"", column, inpath.display()
+        );
+    }
+
+    let add = add && !created;
+
+    let ddids = ctry!(
+        main_table.get_col_as_vec::<i32>("DATA_DESC_ID");
+        "failed to read column \"DATA_DESC_ID\" of \"{}\"", inpath.display()
+    );
+    let field_ids = ctry!(
+        main_table.get_col_as_vec::<i32>("FIELD_ID");
+        "failed to read column \"FIELD_ID\" of \"{}\"", inpath.display()
+    );
+
+    // Do the predictions.
+
+    let mut pb = pbr::ProgressBar::new(main_table.n_rows());
+    pb.set_max_refresh_rate(Some(std::time::Duration::from_millis(500)));
+    let mut chan_vis = Vec::new();
+
+    for (row, (ddid, field_id)) in ddids.into_iter().zip(field_ids).enumerate() {
+        let row = row as u64;
+
+        let (dd, lmns) = match (ddinfo.get(ddid as usize), field_lmn.get(field_id as usize)) {
+            (Some(dd), Some(lmns)) => (dd, lmns),
+            _ => {
+                return err_msg!(
+                    "row #{} of \"{}\" refers to nonexistent DATA_DESC_ID {} or FIELD_ID {}",
+                    row,
+                    inpath.display(),
+                    ddid,
+                    field_id
+                );
+            }
+        };
+
+        let freqs = match spw_freqs.get(dd.spw_id as usize) {
+            Some(f) => f,
+            None => {
+                return err_msg!(
+                    "DATA_DESC_ID {} of \"{}\" refers to nonexistent spectral window {}",
+                    ddid,
+                    inpath.display(),
+                    dd.spw_id
+                );
+            }
+        };
+
+        let uvw = ctry!(
+            main_table.get_cell_as_vec::<f64>("UVW", row);
+            "failed to read column \"UVW\" of row #{} of file \"{}\"", row, inpath.display()
+        );
+
+        if uvw.len() != 3 {
+            return err_msg!(
+                "row #{} of \"{}\" has a malformed UVW",
+                row,
+                inpath.display()
+            );
+        }
+
+        // Sum up the components in each channel.
+
+        chan_vis.clear();
+        chan_vis.resize(freqs.len(), Complex::new(0f64, 0.));
+
+        for (comp, (l, m, n1)) in components.iter().zip(lmns) {
+            // The geometric delay, in seconds.
+            let delay = (uvw[0] * l + uvw[1] * m + uvw[2] * n1) / SPEED_OF_LIGHT;
+
+            for (vis, freq) in chan_vis.iter_mut().zip(freqs) {
+                let inv_wavelength = freq / SPEED_OF_LIGHT;
+                let amp = comp.flux_at(*freq)
+                    * comp.shape_factor(uvw[0] * inv_wavelength, uvw[1] * inv_wavelength);
+                *vis += Complex::from_polar(amp, -2. * PI * delay * freq);
+            }
+        }
+
+        let n_pol = dd.corr_types.len();
+
+        let mut model: Array<Complex<f32>, Ix2> = if add {
+            let existing = ctry!(
+                main_table.get_cell_as_vec::<Complex<f32>>(column, row);
+                "failed to read column \"{}\" of row #{} of file \"{}\"", column, row, inpath.display()
+            );
+
+            match Array::from_shape_vec((freqs.len(), n_pol), existing) {
+                Ok(a) => a,
+                Err(_) => {
+                    return err_msg!(
+                        "row #{} of \"{}\" does not have the {}x{} shape implied by its DATA_DESC_ID",
+                        row,
+                        inpath.display(),
+                        freqs.len(),
+                        n_pol
+                    );
+                }
+            }
+        } else {
+            Array::zeros((freqs.len(), n_pol))
+        };
+
+        for (i_pol, code) in dd.corr_types.iter().enumerate() {
+            if is_total_intensity(*code) {
+                for (m, vis) in model.column_mut(i_pol).iter_mut().zip(&chan_vis) {
+                    *m += Complex::new(vis.re as f32, vis.im as f32);
+                }
+            }
+        }
+
+        ctry!(
+            main_table.put_cell(column, row, &model);
+            "failed to write column \"{}\" of row #{} of file \"{}\"", column, row, inpath.display()
+        );
+
+        pb.inc();
+    }
+
+    pb.finish();
+
+    rn_note!(
+        nbe,
+        "predicted {} component(s) into column \"{}\" of \"{}\"",
+        components.len(),
+        column,
+        inpath.display()
+    );
+
+    Ok(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-12
+    }
+
+    #[test]
+    fn parse_angle_decimal() {
+        assert!(close(
+            parse_angle("12.5", 15.).unwrap(),
+            12.5f64.to_radians()
+        ));
+        assert!(close(parse_angle("-30", 1.).unwrap(), -PI / 6.));
+        assert!(parse_angle("nan", 1.).is_none());
+        assert!(parse_angle("twelve", 1.).is_none());
+    }
+
+    #[test]
+    fn parse_angle_sexagesimal() {
+        let ra: f64 = 15. * (12. + 30. / 60. + 49.42 / 3600.);
+        assert!(close(
+            parse_angle("12:30:49.42", 15.).unwrap(),
+            ra.to_radians()
+        ));
+
+        let dec: f64 = 12. + 23. / 60. + 28. / 3600.;
+        assert!(close(
+            parse_angle("+12:23:28", 1.).unwrap(),
+            dec.to_radians()
+        ));
+        assert!(close(
+            parse_angle("-12:23:28", 1.).unwrap(),
+            -dec.to_radians()
+        ));
+
+        // A negative declination whose degrees field is zero.
+        assert!(close(
+            parse_angle("-00:30", 1.).unwrap(),
+            -0.5f64.to_radians()
+        ));
+    }
+
+    #[test]
+    fn parse_angle_rejects_bad_sexagesimal() {
+        assert!(parse_angle("12:-30:00", 15.).is_none());
+        assert!(parse_angle("12:30:00:00", 15.).is_none());
+        assert!(parse_angle("12::00", 15.).is_none());
+    }
+
+    #[test]
+    fn text_and_json_components_agree() {
+        let path = Path::new("test");
+        let text = parse_text_components(
+            "12:30:49.42 +12:23:28.0 1.5 alpha=-0.7 freq=1.4e9 # comment\n\
+             \n\
+             187.70593 12.39112 0.2 major=10 minor=4 pa=30\n",
+            path,
+        )
+        .unwrap();
+        let json = parse_json_components(
+            r#"[
+                {"ra": "12:30:49.42", "dec": "+12:23:28.0", "flux": 1.5, "alpha": -0.7, "freq": 1.4e9},
+                {"ra": 187.70593, "dec": 12.39112, "flux": 0.2, "major": 10, "minor": 4, "pa": 30}
+            ]"#,
+            path,
+        )
+        .unwrap();
+
+        assert_eq!(text.len(), 2);
+        assert_eq!(json.len(), 2);
+
+        for (t, j) in text.iter().zip(&json) {
+            assert!(close(t.ra, j.ra));
+            assert!(close(t.dec, j.dec));
+            assert!(close(t.flux, j.flux));
+            assert!(close(t.ref_freq, j.ref_freq));
+            assert!(close(t.spectral_index, j.spectral_index));
+            assert_eq!(t.shape, j.shape);
+        }
+    }
+
+    #[test]
+    fn invalid_components_are_rejected() {
+        let path = Path::new("test");
+        assert!(parse_text_components("10 95 1", path).is_err());
+        assert!(parse_text_components("10 20 1 alpha=-0.7", path).is_err());
+        assert!(parse_text_components("10 20 1 minor=3", path).is_err());
+        assert!(parse_text_components("10 20 1 size=3", path).is_err());
+        assert!(parse_json_components(r#"{"ra": 10, "dec": 20, "flux": 1}"#, path).is_err());
+        assert!(parse_json_components(r#"[{"ra": 10, "dec": 20}]"#, path).is_err());
+        assert!(
+            parse_json_components(r#"[{"ra": 10, "dec": 20, "flux": 1, "pa": "x"}]"#, path)
+                .is_err()
+        );
+    }
+}
@@ -4,13 +4,12 @@
 use clap::{value_parser, Arg, ArgAction, ArgMatches, Command};
 use ndarray::{s, Ix1, Ix2};
 use num_traits::{Float, One, Signed, Zero};
-use pbr;
 use rubbl_casatables::{CasaDataType, CasaScalarData, Table, TableError, TableOpenMode, TableRow};
 use rubbl_core::{
     anyhow::{self, Error, Result},
     ctry,
     notify::NotificationBackend,
-    rn_severe, Array, Complex,
+    rn_severe, rn_warning, Array, Complex,
 };
 use std::{
     self,
@@ -20,271 +19,12 @@ use std::{
     fs::File,
     hash::Hash,
     marker::PhantomData,
-    mem,
     ops::{AddAssign, BitOrAssign, Range, Sub},
     path::{Path, PathBuf},
     str::FromStr,
 };
 
-// Quick .npy file parsing, stealing work from the `npy` crate version 0.3.2.
-
-mod mini_npy_parser {
-    use super::*;
-    use byteorder::{ByteOrder, LittleEndian, ReadBytesExt};
-    use ndarray::{Array, Dimension};
-    use nom::{
-        branch::alt,
-        bytes::complete::tag,
-        character::complete::{char, i64, multispace0, satisfy},
-        combinator::{map, opt},
-        multi::{many1, separated_list0},
-        sequence::{delimited, preceded, separated_pair},
-        IResult, Parser,
-    };
-    use rubbl_core::num::DimFromShapeSlice;
-    use std::collections::HashMap;
-    use std::io::Read;
-
-    #[derive(PartialEq, Eq, Debug)]
-    enum LimitedPyLiteral {
-        String(String),
-        Integer(i64),
-        Bool(bool),
-        List(Vec<LimitedPyLiteral>),
-        Map(HashMap<String, LimitedPyLiteral>),
-    }
-
-    pub fn npy_stream_to_ndarray<R: Read, D: Dimension + DimFromShapeSlice<u64>>(
-        stream: &mut R,
-    ) -> Result<Array<f64, D>> {
-        let mut preamble = [0u8; 10];
-
-        stream.read_exact(&mut preamble)?;
-
-        if &preamble[..8] != b"\x93NUMPY\x01\x00" {
-            return err_msg!("stream does not appear to be NPY-format save data");
-        }
-
-        // Because header_len is only a u16, it can't be absurdly large even
-        // in a maliciously-constructed file.
-        //
-        // The Python header is padded such that the data start at a multiple
-        // of 16 bytes into the file. If the un-padded header length is a
-        // total of X bytes, we can calculate the padded length as ((X + 15) /
-        // 16) * 16 with standard truncating integer division. We've already
-        // read 10 bytes though, so we need to account for those in the total
-        // length as well as for the length that remains to be read.
-        let header_len = LittleEndian::read_u16(&preamble[8..]);
-        let aligned_len = ((header_len as usize + 10 + 15) / 16) * 16 - 10;
-
-        let mut header = vec![0; aligned_len];
-        stream.read_exact(&mut header[..])?;
-
-        let endpos = header
-            .iter()
-            .position(|&c| c == b'\0')
-            .unwrap_or(aligned_len);
-
-        let header = match std::str::from_utf8(&header[..endpos]) {
-            Ok(h) => h,
-
-            Err(e) => {
-                return err_msg!("failed to convert NPY Python header into text: {}", e);
-            }
-        };
-
-        let pyinfo = match limited_py_literal(&header) {
-            Ok((_, info)) => info,
-
-            Err(e) => {
-                return err_msg!("failed to parse NPY Python header: {}", e);
-            }
-        };
-
-        let pyinfo = match pyinfo {
-            LimitedPyLiteral::Map(m) => m,
-            other => {
-                return err_msg!(
-                    "bad NPY Python header: expected toplevel map but got {:?}",
-                    other
-                );
-            }
-        };
-
-        let descr = match pyinfo.get("descr") {
-            Some(&LimitedPyLiteral::String(ref s)) => s,
-            other => {
-                return err_msg!(
-                    "bad NPY Python header: expected string item \"descr\" but got {:?}",
-                    other
-                );
-            }
-        };
-
-        let fortran_order = match pyinfo.get("fortran_order") {
-            Some(&LimitedPyLiteral::Bool(b)) => b,
-            other => {
-                return err_msg!(
-                    "bad NPY Python header: expected bool item \"fortran_order\" but got {:?}",
-                    other
-                );
-            }
-        };
-
-        let py_shape = match pyinfo.get("shape") {
-            Some(&LimitedPyLiteral::List(ref ell)) => ell,
-            other => {
-                return err_msg!(
-                    "bad NPY Python header: expected list item \"shape\" but got {:?}",
-                    other
-                );
-            }
-        };
-
-        // We could support more choices here ...
-        if descr != "<f8" {
-            return err_msg!(
-                "unsupported NPY file: data type must be little-endian \
-                 f64 (\"<f8\") but got \"{}\"",
-                descr
-            );
-        }
-
-        // ... and here.
-        if fortran_order {
-            return err_msg!("unsupported NPY file: data ordering must be C, but got Fortran");
-        }
-
-        let mut shape = Vec::new();
-
-        for py_shape_item in py_shape {
-            match py_shape_item {
-                &LimitedPyLiteral::Integer(i) => {
-                    shape.push(i as u64);
-                }
-                other => {
-                    return err_msg!(
-                        "bad NPY Python header: expected \"shape\" to be all integers but got {:?}",
-                        other
-                    );
-                }
-            }
-        }
-
-        let mut arr = Array::uninit(D::from_shape_slice(&shape)?);
-
-        // Note: we "should" probably use a BufReader here, but the
-        // performance of this bit is totally insignificant in the grand
-        // scheme of things.
-
-        for item in arr.iter_mut() {
-            *item = std::mem::MaybeUninit::new(stream.read_f64::<LittleEndian>()?);
-        }
-
-        Ok(unsafe { arr.assume_init() })
-    }
-
-    fn limited_py_literal(input: &str) -> IResult<&str, LimitedPyLiteral> {
-        alt((
-            integer_literal,
-            boolean_literal,
-            braindead_string_literal,
-            listlike_literal,
-            braindead_map_literal,
-        ))
-        .parse(input)
-    }
-
-    fn integer_literal(input: &str) -> IResult<&str, LimitedPyLiteral> {
-        map(delimited(multispace0, i64, multispace0), |x: i64| {
-            LimitedPyLiteral::Integer(x)
-        })
-        .parse(input)
-    }
-
-    fn boolean_literal(input: &str) -> IResult<&str, LimitedPyLiteral> {
-        delimited(
-            multispace0,
-            alt((
-                map(tag("True"), |_| LimitedPyLiteral::Bool(true)),
-                map(tag("False"), |_| LimitedPyLiteral::Bool(false)),
-            )),
-            multispace0,
-        )
-        .parse(input)
-    }
-
-    /// This is "braindead" because we don't handle escapes at all, nor many
-    /// other facets of real Python string syntax. This is all we need for .npy
-    /// files, though.
-    fn braindead_string_text(input: &str) -> IResult<&str, String> {
-        // This is wildly inefficient since we're buffering up invididual
-        // characters in Vec rather than just using the input slice, but for
-        // these purposes I can't be bothered to do better.
-        map(
-            delimited(
-                multispace0,
-                alt((
-                    delimited(char('\"'), many1(satisfy(|c| c != '\"')), char('\"')),
-                    delimited(char('\''), many1(satisfy(|c| c != '\'')), char('\'')),
-                )),
-                multispace0,
-            ),
-            |chars| chars.iter().collect(),
-        )
-        .parse(input)
-    }
-
-    fn braindead_string_literal(input: &str) -> IResult<&str, LimitedPyLiteral> {
-        map(braindead_string_text, |s| LimitedPyLiteral::String(s)).parse(input)
-    }
-
-    /// Note that we do not distinguish between tuples and lists.
-    fn listlike_literal(input: &str) -> IResult<&str, LimitedPyLiteral> {
-        map(
-            delimited(
-                multispace0,
-                alt((
-                    delimited(
-                        char('['),
-                        separated_list0(char(','), limited_py_literal),
-                        (opt(char(',')), multispace0, char(']')),
-                    ),
-                    delimited(
-                        char('('),
-                        separated_list0(char(','), limited_py_literal),
-                        (opt(char(',')), multispace0, char(')')),
-                    ),
-                )),
-                multispace0,
-            ),
-            |items| LimitedPyLiteral::List(items),
-        )
-        .parse(input)
-    }
-
-    /// Note that we only allow string keys.
-    fn braindead_map_literal(input: &str) -> IResult<&str, LimitedPyLiteral> {
-        map(
-            delimited(
-                multispace0,
-                delimited(
-                    char('{'),
-                    separated_list0(
-                        char(','),
-                        separated_pair(braindead_string_text, char(':'), limited_py_literal),
-                    ),
-                    (opt(char(',')), multispace0, char('}')),
-                ),
-                multispace0,
-            ),
-            |items| LimitedPyLiteral::Map(items.into_iter().collect()),
-        )
-        .parse(input)
-    }
-}
-
-use self::mini_npy_parser::npy_stream_to_ndarray;
+use crate::npy::npy_stream_to_ndarray;
 
 /// Code for combining spw-associated quantities. We have to implement these
 /// as discrete types so that we can leverage Rust's generics. It's a bit of a
@@ -939,7 +679,7 @@ mod main_table {
                 return Ok(());
             }
 
-            if let &Some(ref arr) = vis_factor {
+            if let Some(arr) = vis_factor {
                 self.buf *= &arr.view().to_shape((arr.len(), 1)).unwrap();
             }
 
@@ -994,7 +734,7 @@ mod main_table {
                 _ => col_name,
             };
 
-            if let &Some(ref arr) = vis_factor {
+            if let Some(arr) = vis_factor {
                 self.buf *= &arr.view().to_shape((arr.len(), 1)).unwrap();
             }
 
@@ -1088,12 +828,12 @@ mod main_table {
                 {
                     let col_name = self.col_name();
 
-                    Ok(match self {
+                    match self {
                         $(
                             &mut VisDataColumn::$variant_name(ref mut s) =>
                                 s.process(col_name, data_mapping, in_spw, out_spw, row),
                         )+
-                    }?)
+                    }
                 }
 
                 fn emit(&mut self, data_mapping: DataMappingKind, vis_factor: &MaybeVisFactor,
@@ -1101,12 +841,12 @@ mod main_table {
                 {
                     let col_name = self.col_name();
 
-                    Ok(match self {
+                    match self {
                         $(
                             &mut VisDataColumn::$variant_name(ref mut s) =>
                                 s.emit(col_name, data_mapping, vis_factor, table, row),
                         )+
-                    }?)
+                    }
                 }
 
                 fn reset(&mut self) {
@@ -1207,13 +947,12 @@ mod main_table {
 
 use self::main_table::WrappedVisDataColumn as VisDataColumn;
 
-// DATA_DESC_ID is the one that we ignore because that encodes the SPW
-// information. TODO: POLARIZATION_ID is hidden in DATA_DESC_ID and we
-// could/should multiplex on that, but we currently hardcode a limitation to
-// just one POLARIZATION_ID anyway.
+// DATA_DESC_ID is the one that we ignore because that encodes the SPW and
+// polarization information. When gluing, the discriminant is the output
+// DATA_DESC_ID, which multiplexes on both the output spw and POLARIZATION_ID.
 
 #[derive(Clone, Debug, Eq, Hash, PartialEq)]
-struct VisRecordIdentity<T: Clone + Debug + Eq + Hash> {
+pub struct VisRecordIdentity<T: Clone + Debug + Eq + Hash> {
     discriminant: T,
 
     antenna1: i32,
@@ -1254,11 +993,75 @@ impl<T: Clone + Debug + Eq + Hash> VisRecordIdentity<T> {
             processor_id: row.get_cell("PROCESSOR_ID")?,
             scan_number: row.get_cell("SCAN_NUMBER")?,
             state_id: row.get_cell("STATE_ID")?,
-            recast_time: unsafe { mem::transmute(time) },
+            recast_time: time.to_bits(),
         })
     }
 }
 
+impl<T: Clone + Debug + Eq + Hash> VisRecordIdentity<T> {
+    /// Get the timestamp of the record.
+    pub fn time(&self) -> f64 {
+        f64::from_bits(self.recast_time)
+    }
+
+    /// Get the discriminant of the record, such as its DATA_DESC_ID.
+    pub fn discriminant(&self) -> &T {
+        &self.discriminant
+    }
+
+    /// Get the antenna numbers of the record.
+    pub fn antennas(&self) -> (i32, i32) {
+        (self.antenna1, self.antenna2)
+    }
+
+    /// Get a copy of this identity with the time zeroed out, which identifies
+    /// all of the records of a given baseline, spw, etc.
+    pub fn without_time(&self) -> Self {
+        Self {
+            recast_time: 0,
+            ..self.clone()
+        }
+    }
+}
+
+impl VisRecordIdentity<i32> {
+    /// Get the identities of all of the rows of a table, using DATA_DESC_ID
+    /// as the discriminant. The relevant columns are read in bulk, which is
+    /// much faster than going row-by-row in data sets with large DATA
+    /// columns. No time fuzzing is done here.
+    pub fn load_all(table: &mut Table) -> Result<Vec<Self>, TableError> {
+        let time = table.get_col_as_vec::<f64>("TIME")?;
+        let ddid = table.get_col_as_vec::<i32>("DATA_DESC_ID")?;
+        let antenna1 = table.get_col_as_vec::<i32>("ANTENNA1")?;
+        let antenna2 = table.get_col_as_vec::<i32>("ANTENNA2")?;
+        let array_id = table.get_col_as_vec::<i32>("ARRAY_ID")?;
+        let feed1 = table.get_col_as_vec::<i32>("FEED1")?;
+        let feed2 = table.get_col_as_vec::<i32>("FEED2")?;
+        let field_id = table.get_col_as_vec::<i32>("FIELD_ID")?;
+        let observation_id = table.get_col_as_vec::<i32>("OBSERVATION_ID")?;
+        let processor_id = table.get_col_as_vec::<i32>("PROCESSOR_ID")?;
+        let scan_number = table.get_col_as_vec::<i32>("SCAN_NUMBER")?;
+        let state_id = table.get_col_as_vec::<i32>("STATE_ID")?;
+
+        Ok((0..time.len())
+            .map(|i| Self {
+                discriminant: ddid[i],
+                antenna1: antenna1[i],
+                antenna2: antenna2[i],
+                array_id: array_id[i],
+                feed1: feed1[i],
+                feed2: feed2[i],
+                field_id: field_id[i],
+                observation_id: observation_id[i],
+                processor_id: processor_id[i],
+                scan_number: scan_number[i],
+                state_id: state_id[i],
+                recast_time: time[i].to_bits(),
+            })
+            .collect())
+    }
+}
+
 /// Information about an output spectral window.
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct OutputSpwInfo {
@@ -1317,9 +1120,9 @@ impl FromStr for OutputSpwInfo {
     }
 }
 
-/// Information about each *input* spw. We currently hardcode the limitation
-/// that each input spw can only appear in one output spw, which is something
-/// that we could in principle lift.
+/// Information about how an *input* spw maps into an output spw. An input
+/// spw may appear in several output spws, in which case it has one of these
+/// for each of them.
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct InputSpwInfo {
     out_spw: usize,
@@ -1328,10 +1131,7 @@ pub struct InputSpwInfo {
 
 impl InputSpwInfo {
     pub fn new(out_spw: usize, offset: usize) -> Self {
-        InputSpwInfo {
-            out_spw: out_spw,
-            offset: offset,
-        }
+        InputSpwInfo { out_spw, offset }
     }
 
     pub fn out_spw_id(&self) -> usize {
@@ -1354,9 +1154,9 @@ struct OutputRecordState<'a> {
 impl<'a> OutputRecordState<'a> {
     pub fn new(spw_info: &'a OutputSpwInfo, columns: Vec<VisDataColumn>) -> Self {
         Self {
-            spw_info: spw_info,
+            spw_info,
             n_input_spws_seen: 0,
-            columns: columns,
+            columns,
         }
     }
 
@@ -1438,7 +1238,11 @@ pub fn make_command() -> Command {
                 .long_help(
                     "Define a glued spectral window that concatenates \
                      input windows numbers N through M, inclusive. The \
-                     numbers are zero-based.",
+                     numbers are zero-based. Windows may overlap, so that, \
+                     e.g., `-w 0-3 -w 0-1 -w 2-3` produces both a full-band \
+                     window and two sub-band windows. A glued window is \
+                     produced for each polarization setup that all of its \
+                     input windows share.",
                 )
                 .value_name("N-M")
                 .number_of_values(1)
@@ -1546,7 +1350,7 @@ pub fn do_cli(matches: &ArgMatches, nbe: &mut dyn NotificationBackend) -> Result
         idx
     });
 
-    if destinations.len() == 0 {
+    if destinations.is_empty() {
         return err_msg!("must specify at least one destination path with `-f` or `-D`");
     }
 
@@ -1563,7 +1367,7 @@ pub fn do_cli(matches: &ArgMatches, nbe: &mut dyn NotificationBackend) -> Result
     let inv_sq_mean_bp = match matches.get_one::<PathBuf>("meanbp") {
         None => None,
         Some(meanbp_path) => {
-            let mut meanbp = ctry!(File::open(&meanbp_path);
+            let mut meanbp = ctry!(File::open(meanbp_path);
                                    "could not open meanbp file \"{}\"", meanbp_path.to_string_lossy());
             let mut arr: Array<f64, Ix1> = npy_stream_to_ndarray(&mut meanbp)?;
 
@@ -1593,7 +1397,7 @@ pub fn do_cli(matches: &ArgMatches, nbe: &mut dyn NotificationBackend) -> Result
     fn open_table(base: &Path, extension: &str, is_input: bool) -> Result<(PathBuf, Table)> {
         let mut p = base.to_owned();
 
-        if extension.len() > 0 {
+        if !extension.is_empty() {
             p.push(extension);
         }
 
@@ -1606,14 +1410,14 @@ pub fn do_cli(matches: &ArgMatches, nbe: &mut dyn NotificationBackend) -> Result
         let t = ctry!(Table::open(&p, mode);
                       "failed to open {} {}table \"{}\"",
                       if is_input { "input" } else { "output" },
-                      if extension.len() > 0 { "sub-" } else { "" },
+                      if !extension.is_empty() { "sub-" } else { "" },
                       p.display()
         );
 
         Ok((p, t))
     }
 
-    let (_, mut in_main_table) = open_table(&inpath, "", true)?;
+    let (_, mut in_main_table) = open_table(inpath, "", true)?;
 
     let col_names = ctry!(in_main_table.column_names();
                           "failed to get names of columns in \"{}\"", inpath.display());
@@ -1646,39 +1450,15 @@ pub fn do_cli(matches: &ArgMatches, nbe: &mut dyn NotificationBackend) -> Result
               inpath_str, dest.display());
     }
 
-    // Copy POLARIZATION. We currently require that there be only one
-    // polarization type in the input file. This tool *could* work with
-    // multiple pol types, but it would be more of a hassle and my data
-    // don't currently have that structure. But I've separated out the
-    // relevant code here rather than grouped it in with the rest of
-    // the miscellaneous tables Just In Case.
-
-    {
-        let (_, mut in_pol_table) = open_table(&inpath, "POLARIZATION", true)?;
-
-        let n_pol_types = in_pol_table.n_rows();
-
-        if n_pol_types != 1 {
-            return err_msg!(
-                "input data set has {} \"POLARIZATION\" rows; I require exactly 1",
-                n_pol_types
-            );
-        }
-
-        for dest in &destinations {
-            let (_, mut out_pol_table) = open_table(dest, "POLARIZATION", false)?;
-            in_pol_table.copy_rows_to(&mut out_pol_table)?;
-        }
-    };
-
     // Process the SPECTRAL_WINDOW table, building up our database of
     // information about how to map input spectral windows to output
-    // spectral windows.
+    // spectral windows. Windows may overlap, so each input spw may feed
+    // several output spws.
 
-    let mut in_spws: HashMap<usize, InputSpwInfo> = HashMap::new();
+    let mut in_spws: HashMap<usize, Vec<InputSpwInfo>> = HashMap::new();
 
     {
-        let (in_spw_path, mut in_spw_table) = open_table(&inpath, "SPECTRAL_WINDOW", true)?;
+        let (in_spw_path, mut in_spw_table) = open_table(inpath, "SPECTRAL_WINDOW", true)?;
 
         let n_in_spws = in_spw_table.n_rows();
 
@@ -1721,14 +1501,12 @@ pub fn do_cli(matches: &ArgMatches, nbe: &mut dyn NotificationBackend) -> Result
 
             if n == "NUM_CHAN" {
                 // A bit inefficient since we reread the column but whatever.
-                let num_chans = in_spw_table.get_col_as_vec::<i32>(&n)?;
+                let num_chans = in_spw_table.get_col_as_vec::<i32>(n)?;
 
                 for (i, out_spw) in out_spws.iter_mut().enumerate() {
                     for in_spw_num in out_spw.spw_indices() {
                         let ism = out_spw.register_new_input_spw(num_chans[in_spw_num] as usize, i);
-                        if in_spws.insert(in_spw_num, ism).is_some() {
-                            return err_msg!("cannot (currently) include a single input spw in multiple output spws");
-                        }
+                        in_spws.entry(in_spw_num).or_default().push(ism);
                     }
                 }
             }
@@ -1758,218 +1536,203 @@ pub fn do_cli(matches: &ArgMatches, nbe: &mut dyn NotificationBackend) -> Result
         }
     }
 
-    // Now process DATA_DESCRIPTION. By the restriction to one
-    // polarization type, there should be exactly one row per input spw.
-    // If we were being fancy we'd reuse the infrastructure that merges
-    // the various SPECTRAL_WINDOW columns, but this table only has three
+    // Now process DATA_DESCRIPTION. Each input DDID names a (spw,
+    // polarization) pair, and there may be several polarization setups
+    // (e.g., after a CASA `concat` or in mixed-mode observations). We emit
+    // one output DDID for every combination of an output spw and a
+    // polarization setup that all of its input spws were observed with. If
+    // we were being fancy we'd reuse the infrastructure that merges the
+    // various SPECTRAL_WINDOW columns, but this table only has three
     // columns.
 
-    let mut ddid_to_in_spw_id = HashMap::new();
+    let mut ddid_to_in_spw_pol = HashMap::new();
+    let mut out_ddids = HashMap::new();
 
     {
-        let (_, mut in_ddid_table) = open_table(&inpath, "DATA_DESCRIPTION", true)?;
+        let (in_ddid_path, mut in_ddid_table) = open_table(inpath, "DATA_DESCRIPTION", true)?;
+        let (_, mut in_pol_table) = open_table(inpath, "POLARIZATION", true)?;
+        let n_pol_types = in_pol_table.n_rows() as usize;
 
         let flag_row = in_ddid_table.get_col_as_vec::<bool>("FLAG_ROW")?;
         let pol_id = in_ddid_table.get_col_as_vec::<i32>("POLARIZATION_ID")?;
         let spw_id = in_ddid_table.get_col_as_vec::<i32>("SPECTRAL_WINDOW_ID")?;
 
-        // Process everything into first destination.
-
-        let (out_ddid_path, mut out_ddid_table) =
-            open_table(&destinations[0], "DATA_DESCRIPTION", false)?;
-
-        ctry!(out_ddid_table.add_rows(out_spws.len());
-              "failed to add {} rows to \"{}\"", out_spws.len(), out_ddid_path.display());
-
-        for (out_spw_idx, out_spw) in out_spws.iter().enumerate() {
-            let mut spw_idx_iter = out_spw.spw_indices();
-            let first_in_spw = spw_idx_iter.next().unwrap();
+        // Map each (input spw, input pol) pair to the first DDID that
+        // describes it.
 
-            let the_flag_row = flag_row[first_in_spw];
+        let mut spw_pol_to_ddid = HashMap::new();
 
-            if pol_id[first_in_spw] != 0 {
+        for (ddid, (&spw, &pol)) in spw_id.iter().zip(pol_id.iter()).enumerate() {
+            if pol < 0 || pol as usize >= n_pol_types {
                 return err_msg!(
-                    "consistency failure: expected POLARIZATION_ID[{}] = 0; got {}",
-                    first_in_spw,
-                    pol_id[first_in_spw]
+                    "consistency failure: POLARIZATION_ID[{}] = {} in \"{}\", but there are {} \
+                     polarization setups",
+                    ddid,
+                    pol,
+                    in_ddid_path.display(),
+                    n_pol_types
                 );
             }
 
-            if spw_id[first_in_spw] as usize != first_in_spw {
-                return err_msg!(
-                    "consistency failure: expected SPECTRAL_WINDOW_ID[{}] = {}; got {}",
-                    first_in_spw,
-                    first_in_spw,
-                    spw_id[first_in_spw]
-                );
+            if spw < 0 {
+                continue;
             }
 
-            // note: baking in assumption that DDID = SPWID (also done in insert call below)
-            ddid_to_in_spw_id.insert(first_in_spw, first_in_spw);
+            ddid_to_in_spw_pol.insert(ddid, (spw as usize, pol as usize));
+            spw_pol_to_ddid
+                .entry((spw as usize, pol as usize))
+                .or_insert(ddid);
+        }
+
+        // Figure out the output DDIDs. They're ordered by output spw, then
+        // by input polarization ID, so that data sets with only one
+        // polarization setup come out just as they always have.
 
-            for in_spw in spw_idx_iter {
-                if pol_id[in_spw] != 0 {
-                    return err_msg!(
-                        "consistency failure: expected POLARIZATION_ID[{}] = 0; got {}",
-                        in_spw,
-                        pol_id[in_spw]
-                    );
-                }
+        let mut out_dds = Vec::new();
 
-                if spw_id[in_spw] as usize != in_spw {
-                    return err_msg!(
-                        "consistency failure: expected SPECTRAL_WINDOW_ID[{}] = {}; got {}",
-                        in_spw,
-                        in_spw,
-                        spw_id[in_spw]
+        for (out_spw_idx, out_spw) in out_spws.iter().enumerate() {
+            let first_in_spw = out_spw.spw_indices().next().unwrap();
+
+            let mut pols: Vec<usize> = spw_pol_to_ddid
+                .keys()
+                .filter(|(spw, _)| out_spw.spw_indices().contains(spw))
+                .map(|(_, pol)| *pol)
+                .collect();
+            pols.sort_unstable();
+            pols.dedup();
+
+            for pol in pols {
+                if let Some(missing) = out_spw
+                    .spw_indices()
+                    .find(|spw| !spw_pol_to_ddid.contains_key(&(*spw, pol)))
+                {
+                    rn_warning!(
+                        nbe,
+                        "input spw {} has no data with POLARIZATION_ID {}, so output window #{} \
+                         will not include that polarization setup",
+                        missing,
+                        pol,
+                        out_spw_idx
                     );
+                    continue;
                 }
 
-                ddid_to_in_spw_id.insert(in_spw, in_spw);
+                let the_flag_row = flag_row[spw_pol_to_ddid[&(first_in_spw, pol)]];
+                out_ddids.insert((out_spw_idx, pol), out_dds.len());
+                out_dds.push((out_spw_idx, pol, the_flag_row));
             }
-
-            out_ddid_table.put_cell("FLAG_ROW", out_spw_idx as u64, &the_flag_row)?;
-            out_ddid_table.put_cell("POLARIZATION_ID", out_spw_idx as u64, &0i32)?;
-            out_ddid_table.put_cell(
-                "SPECTRAL_WINDOW_ID",
-                out_spw_idx as u64,
-                &(out_spw_idx as i32),
-            )?;
-        }
-
-        // Now propagate into remaining destinations (if any).
-
-        for more_dest in &destinations[1..] {
-            let (_, mut more_ddid_table) = open_table(more_dest, "DATA_DESCRIPTION", false)?;
-            out_ddid_table.copy_rows_to(&mut more_ddid_table)?;
         }
-    }
 
-    // We can copy FIELD verbatim but we need to know how many fields there are for
-    // our lamebrained handling of the SOURCE table.
+        // Only the polarization setups that are actually used are copied
+        // into the output, renumbered but keeping their relative order.
 
-    let n_fields = {
-        let (_, in_field_table) = open_table(&inpath, "FIELD", true)?;
-        in_field_table.n_rows() as usize
-    };
+        let mut used_pols: Vec<usize> = out_dds.iter().map(|(_, pol, _)| *pol).collect();
+        used_pols.sort_unstable();
+        used_pols.dedup();
 
-    // SOURCE table also needs some custom processing. We assume, but
-    // don't verify, that every row corresponding to the same output spw
-    // has the same values in all columns except SPECTRAL_WINDOW_ID. So we
-    // just copy over the rows corresponding to the first input spw of each
-    // output, making sure to renumber the SPECTRAL_WINDOW_ID appropriately.
+        let pol_id_map: HashMap<usize, usize> = used_pols
+            .iter()
+            .enumerate()
+            .map(|(out_pol, in_pol)| (*in_pol, out_pol))
+            .collect();
 
-    {
-        let (_in_src_path, mut in_src_table) = open_table(&inpath, "SOURCE", true)?;
+        // Process everything into first destination.
 
-        // First destination ...
+        let (out_ddid_path, mut out_ddid_table) =
+            open_table(&destinations[0], "DATA_DESCRIPTION", false)?;
 
-        let (out_src_path, mut out_src_table) = open_table(&destinations[0], "SOURCE", false)?;
-        let n_out_sources = n_fields * out_spws.len();
+        ctry!(out_ddid_table.add_rows(out_dds.len());
+              "failed to add {} rows to \"{}\"", out_dds.len(), out_ddid_path.display());
 
-        ctry!(out_src_table.add_rows(n_out_sources);
-              "failed to add {} rows to \"{}\"", n_out_sources, out_src_path.display());
+        for (out_ddid, (out_spw_idx, pol, the_flag_row)) in out_dds.iter().enumerate() {
+            out_ddid_table.put_cell("FLAG_ROW", out_ddid as u64, the_flag_row)?;
+            out_ddid_table.put_cell(
+                "POLARIZATION_ID",
+                out_ddid as u64,
+                &(pol_id_map[pol] as i32),
+            )?;
+            out_ddid_table.put_cell(
+                "SPECTRAL_WINDOW_ID",
+                out_ddid as u64,
+                &(*out_spw_idx as i32),
+            )?;
+        }
 
-        let mut out_row = out_src_table.get_row_writer()?;
-        let mut n_rows_written = 0;
+        let (out_pol_path, mut out_pol_table) =
+            open_table(&destinations[0], "POLARIZATION", false)?;
 
-        in_src_table.for_each_row(|in_row| {
-            let spwid = in_row.get_cell::<i32>("SPECTRAL_WINDOW_ID")?;
+        ctry!(out_pol_table.add_rows(used_pols.len());
+              "failed to add {} rows to \"{}\"", used_pols.len(), out_pol_path.display());
 
-            for (i, out_spw) in out_spws.iter().enumerate() {
-                let mut idx_iter = out_spw.spw_indices();
-                let first_idx = idx_iter.next().unwrap();
+        {
+            let mut out_row = out_pol_table.get_row_writer()?;
+            let mut in_row_num = 0;
 
-                if spwid as usize == first_idx {
-                    in_row.copy_and_put(&mut out_row, n_rows_written)?;
-                    out_src_table.put_cell("SPECTRAL_WINDOW_ID", n_rows_written, &(i as i32))?;
-                    n_rows_written += 1;
+            in_pol_table.for_each_row(|in_row| {
+                if let Some(out_pol) = pol_id_map.get(&in_row_num) {
+                    in_row.copy_and_put(&mut out_row, *out_pol as u64)?;
                 }
-            }
 
-            Ok(())
-        })?;
-
-        // XXX to fix for real: this fails if there are fields that don't include any of
-        // our desired SPWs at all. We could figure this out.
-        //
-        //if n_out_sources as u64 != n_rows_written {
-        //    return err_msg!("consistency failure: expected to reuse {} rows in input \
-        //                     sub-table \"{}\"; found {}",
-        //                    n_out_sources, in_src_path.display(), n_rows_written);
-        //}
+                in_row_num += 1;
+                Ok(())
+            })?;
+        }
 
-        // The rest.
+        // Now propagate into remaining destinations (if any).
 
         for more_dest in &destinations[1..] {
-            let (_, mut more_src_table) = open_table(more_dest, "SOURCE", false)?;
-            out_src_table.copy_rows_to(&mut more_src_table)?;
+            let (_, mut more_ddid_table) = open_table(more_dest, "DATA_DESCRIPTION", false)?;
+            out_ddid_table.copy_rows_to(&mut more_ddid_table)?;
+            let (_, mut more_pol_table) = open_table(more_dest, "POLARIZATION", false)?;
+            out_pol_table.copy_rows_to(&mut more_pol_table)?;
         }
     }
 
-    // FEED is handled pretty much the same way as SOURCE. (I have no idea
-    // why feeds are tied to spectral windows but here we are.)
-
-    {
-        let (_, mut in_feed_table) = open_table(&inpath, "FEED", true)?;
+    // SOURCE, FEED, and CALDEVICE are tied to spectral windows (but not to
+    // polarization setups), so they need some custom processing. We assume,
+    // but don't verify, that every row corresponding to the same output spw
+    // has the same values in all columns except SPECTRAL_WINDOW_ID. So we
+    // just copy over the rows corresponding to the first input spw of each
+    // output, making sure to renumber the SPECTRAL_WINDOW_ID appropriately.
+    // Rows with negative IDs apply to all windows and are copied as-is. (I
+    // have no idea why feeds are tied to spectral windows but here we are.)
+    //
+    // The NOISE_CAL column of CALDEVICE contains values that seem to vary
+    // across spws. I don't know what the CALDEVICE table actually does so I
+    // am just going to ignore that and hope everything will be OK!
+
+    fn copy_spw_rows(
+        inpath: &Path,
+        name: &str,
+        destinations: &[PathBuf],
+        out_spws: &[OutputSpwInfo],
+    ) -> Result<()> {
+        let (_, mut in_table) = open_table(inpath, name, true)?;
 
         // First destination ...
 
-        let (_, mut out_feed_table) = open_table(&destinations[0], "FEED", false)?;
-        let mut out_row = out_feed_table.get_row_writer()?;
+        let (_, mut out_table) = open_table(&destinations[0], name, false)?;
+        let mut out_row = out_table.get_row_writer()?;
         let mut n_rows_written = 0;
 
-        in_feed_table.for_each_row(|in_row| {
+        in_table.for_each_row(|in_row| {
             let spwid = in_row.get_cell::<i32>("SPECTRAL_WINDOW_ID")?;
 
-            for (i, out_spw) in out_spws.iter().enumerate() {
-                let mut idx_iter = out_spw.spw_indices();
-                let first_idx = idx_iter.next().unwrap();
-
-                if spwid as usize == first_idx {
-                    out_feed_table.add_rows(1)?;
-                    in_row.copy_and_put(&mut out_row, n_rows_written)?;
-                    out_feed_table.put_cell("SPECTRAL_WINDOW_ID", n_rows_written, &(i as i32))?;
-                    n_rows_written += 1;
-                }
+            if spwid < 0 {
+                out_table.add_rows(1)?;
+                in_row.copy_and_put(&mut out_row, n_rows_written)?;
+                n_rows_written += 1;
+                return Ok(());
             }
 
-            Ok(())
-        })?;
-
-        // The rest.
-
-        for more_dest in &destinations[1..] {
-            let (_, mut more_feed_table) = open_table(more_dest, "FEED", false)?;
-            out_feed_table.copy_rows_to(&mut more_feed_table)?;
-        }
-    }
-
-    // And CALDEVICE too. The NOISE_CAL column contains values that seem
-    // to vary across spws. I don't know what the CALDEVICE table actually
-    // does so I am just going to ignore that and hope everything will be
-    // OK!
-
-    {
-        let (_, mut in_cd_table) = open_table(&inpath, "CALDEVICE", true)?;
-
-        // First destination ...
-
-        let (_, mut out_cd_table) = open_table(&destinations[0], "CALDEVICE", false)?;
-        let mut out_row = out_cd_table.get_row_writer()?;
-        let mut n_rows_written = 0;
-
-        in_cd_table.for_each_row(|in_row| {
-            let spwid = in_row.get_cell::<i32>("SPECTRAL_WINDOW_ID")?;
-
             for (i, out_spw) in out_spws.iter().enumerate() {
                 let mut idx_iter = out_spw.spw_indices();
                 let first_idx = idx_iter.next().unwrap();
 
                 if spwid as usize == first_idx {
-                    out_cd_table.add_rows(1)?;
+                    out_table.add_rows(1)?;
                     in_row.copy_and_put(&mut out_row, n_rows_written)?;
-                    out_cd_table.put_cell("SPECTRAL_WINDOW_ID", n_rows_written, &(i as i32))?;
+                    out_table.put_cell("SPECTRAL_WINDOW_ID", n_rows_written, &(i as i32))?;
                     n_rows_written += 1;
                 }
             }
@@ -1980,12 +1743,18 @@ pub fn do_cli(matches: &ArgMatches, nbe: &mut dyn NotificationBackend) -> Result
         // The rest.
 
         for more_dest in &destinations[1..] {
-            let (_, mut more_cd_table) = open_table(more_dest, "CALDEVICE", false)?;
-            out_cd_table.copy_rows_to(&mut more_cd_table)?;
+            let (_, mut more_table) = open_table(more_dest, name, false)?;
+            out_table.copy_rows_to(&mut more_table)?;
         }
+
+        Ok(())
+    }
+
+    for name in &["SOURCE", "FEED", "CALDEVICE"] {
+        copy_spw_rows(inpath, name, &destinations, &out_spws)?;
     }
 
-    // Copy over the remaining sub-tables as-is.
+    // Copy over the remaining sub-tables.
 
     let table_kw_names = ctry!(in_main_table.table_keyword_names();
                                "failed to get keyword info in \"{}\"", inpath.display());
@@ -2000,7 +1769,18 @@ pub fn do_cli(matches: &ArgMatches, nbe: &mut dyn NotificationBackend) -> Result
             "SPECTRAL_WINDOW" => {}
             "SYSPOWER" => {} // large and my pipeline pre-applies it!
             n => {
-                let (_, mut in_misc_table) = open_table(&inpath, n, true)?;
+                let (in_misc_path, mut in_misc_table) = open_table(inpath, n, true)?;
+                let misc_col_names = ctry!(in_misc_table.column_names();
+                                           "failed to get names of columns in \"{}\"",
+                                           in_misc_path.display());
+
+                // Other tables tied to spectral windows, such as SYSCAL,
+                // need to be renumbered like SOURCE.
+
+                if misc_col_names.iter().any(|c| c == "SPECTRAL_WINDOW_ID") {
+                    copy_spw_rows(inpath, n, &destinations, &out_spws)?;
+                    continue;
+                }
 
                 for dest in &destinations {
                     let (_, mut out_misc_table) = open_table(dest, n, false)?;
@@ -2041,7 +1821,7 @@ pub fn do_cli(matches: &ArgMatches, nbe: &mut dyn NotificationBackend) -> Result
         for n in &col_names {
             if n == "MODEL_DATA" || n == "CORRECTED_DATA" {
                 for rec in &mut out_tables {
-                    ctry!(rec.table.remove_column(&n);
+                    ctry!(rec.table.remove_column(n);
                           "couldn\'t remove column {} from \"{}\"",
                           n, rec.path.display());
                 }
@@ -2049,61 +1829,77 @@ pub fn do_cli(matches: &ArgMatches, nbe: &mut dyn NotificationBackend) -> Result
         }
     }
 
-    in_main_table.for_each_row(|mut in_row| {
+    in_main_table.for_each_row(|in_row| {
         let ddid = in_row.get_cell::<i32>("DATA_DESC_ID")?;
-        let in_spw_id = match ddid_to_in_spw_id.get(&(ddid as usize)) {
-            Some(i) => i,
+        let (in_spw_id, in_pol_id) = match ddid_to_in_spw_pol.get(&(ddid as usize)) {
+            Some(i) => *i,
+            None => {
+                return Ok(());
+            } // this DDID is invalid
+        };
+
+        let in_spw_infos = match in_spws.get(&in_spw_id) {
+            Some(v) => v,
             None => {
                 return Ok(());
-            } // this DDID is being dropped
+            } // this spw is being dropped
         };
 
         let fieldid = in_row.get_cell::<i32>("FIELD_ID")?;
-        let in_spw_info = in_spws.get(&in_spw_id).unwrap();
-        let out_spw_id = in_spw_info.out_spw_id();
-        let row_ident = VisRecordIdentity::create(out_spw_id, &mut in_row, last_time)?;
-
-        if !records_in_progress.contains_key(&row_ident) {
-            let state = match state_pool.pop() {
-                Some(s) => s.reset(&out_spws[out_spw_id]),
-                None => OutputRecordState::new(&out_spws[out_spw_id], col_state_template.clone()),
-            };
 
-            records_in_progress.insert(row_ident.clone(), state);
-        }
+        // Each input row feeds a record of every output spw that includes
+        // its input spw, unless that output lacks its polarization setup.
 
-        let record_complete = {
-            let state = records_in_progress.get_mut(&row_ident).unwrap();
-            state.process(data_mapping, in_spw_info, &mut in_row)?
-        };
+        for in_spw_info in in_spw_infos {
+            let out_spw_id = in_spw_info.out_spw_id();
+            let out_ddid = match out_ddids.get(&(out_spw_id, in_pol_id)) {
+                Some(i) => *i,
+                None => continue,
+            };
+            let row_ident = VisRecordIdentity::create(out_ddid, in_row, last_time)?;
+
+            if !records_in_progress.contains_key(&row_ident) {
+                let state = match state_pool.pop() {
+                    Some(s) => s.reset(&out_spws[out_spw_id]),
+                    None => {
+                        OutputRecordState::new(&out_spws[out_spw_id], col_state_template.clone())
+                    }
+                };
 
-        if record_complete {
-            let mut state = records_in_progress.remove(&row_ident).unwrap();
+                records_in_progress.insert(row_ident.clone(), state);
+            }
 
-            let maybe_out_rec = if let Some(idx) = field_id_to_dest_index.get_mut(&fieldid) {
-                Some(&mut out_tables[*idx])
-            } else if let Some(ddi) = default_dest_index {
-                Some(&mut out_tables[ddi])
-            } else {
-                None
+            let record_complete = {
+                let state = records_in_progress.get_mut(&row_ident).unwrap();
+                state.process(data_mapping, in_spw_info, in_row)?
             };
 
-            if let Some(out_rec) = maybe_out_rec {
-                out_rec.table.add_rows(1)?;
-                state.emit(
-                    data_mapping,
-                    &inv_sq_mean_bp,
-                    &mut out_rec.table,
-                    out_rec.num_rows,
-                )?;
-                // Rewriting this is kind of lame, but eh.
-                out_rec
-                    .table
-                    .put_cell("DATA_DESC_ID", out_rec.num_rows, &(out_spw_id as i32))?;
-                out_rec.num_rows += 1;
-            }
+            if record_complete {
+                let mut state = records_in_progress.remove(&row_ident).unwrap();
 
-            state_pool.push(state);
+                let maybe_out_rec = if let Some(idx) = field_id_to_dest_index.get_mut(&fieldid) {
+                    Some(&mut out_tables[*idx])
+                } else {
+                    default_dest_index.map(|ddi| &mut out_tables[ddi])
+                };
+
+                if let Some(out_rec) = maybe_out_rec {
+                    out_rec.table.add_rows(1)?;
+                    state.emit(
+                        data_mapping,
+                        &inv_sq_mean_bp,
+                        &mut out_rec.table,
+                        out_rec.num_rows,
+                    )?;
+                    // Rewriting this is kind of lame, but eh.
+                    out_rec
+                        .table
+                        .put_cell("DATA_DESC_ID", out_rec.num_rows, &(out_ddid as i32))?;
+                    out_rec.num_rows += 1;
+                }
+
+                state_pool.push(state);
+            }
         }
 
         last_time = in_row.get_cell("TIME")?;
@@ -2114,7 +1910,7 @@ pub fn do_cli(matches: &ArgMatches, nbe: &mut dyn NotificationBackend) -> Result
 
     // All done!
 
-    if records_in_progress.len() != 0 {
+    if !records_in_progress.is_empty() {
         rn_severe!(
             nbe,
             "there were {} unfinished records left over at the end",
@@ -2125,3 +1921,261 @@ pub fn do_cli(matches: &ArgMatches, nbe: &mut dyn NotificationBackend) -> Result
     pb.finish();
     Ok(0)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rubbl_casatables::{GlueDataType, TableCreateMode, TableDesc, TableDescCreateMode};
+    use rubbl_core::notify::NoopNotificationBackend;
+
+    const ID_COLUMNS: &[&str] = &[
+        "ANTENNA1",
+        "ANTENNA2",
+        "ARRAY_ID",
+        "FEED1",
+        "FEED2",
+        "FIELD_ID",
+        "OBSERVATION_ID",
+        "PROCESSOR_ID",
+        "SCAN_NUMBER",
+        "STATE_ID",
+    ];
+
+    fn new_table(
+        path: &Path,
+        scalars: &[(&str, GlueDataType)],
+        arrays: &[(&str, GlueDataType)],
+        n_rows: usize,
+    ) -> Table {
+        let mut td = TableDesc::new("", TableDescCreateMode::TDM_SCRATCH).unwrap();
+
+        for (name, ty) in scalars {
+            td.add_scalar_column(*ty, name, None, false, false).unwrap();
+        }
+
+        for (name, ty) in arrays {
+            td.add_array_column(*ty, name, None, None, false, false)
+                .unwrap();
+        }
+
+        Table::new(path, td, n_rows, TableCreateMode::New).unwrap()
+    }
+
+    fn spw_ids(path: &Path, name: &str) -> Vec<i32> {
+        let mut t = Table::open(path.join(name), TableOpenMode::Read).unwrap();
+        t.get_col_as_vec("SPECTRAL_WINDOW_ID").unwrap()
+    }
+
+    /// Two spws with two channels each, observed with two polarization
+    /// setups, and a SOURCE table with two sources but only one field.
+    fn make_two_pol_ms(path: &Path) {
+        let pols = [vec![9, 12], vec![5, 6, 7, 8]];
+        let dds = [(0, 0), (1, 0), (0, 1), (1, 1)];
+
+        let mut scalars = vec![
+            ("TIME", GlueDataType::TpDouble),
+            ("DATA_DESC_ID", GlueDataType::TpInt),
+        ];
+        scalars.extend(ID_COLUMNS.iter().map(|c| (*c, GlueDataType::TpInt)));
+        let mut main = new_table(
+            path,
+            &scalars,
+            &[
+                ("DATA", GlueDataType::TpComplex),
+                ("FLAG", GlueDataType::TpBool),
+            ],
+            dds.len(),
+        );
+
+        for (ddid, (spw, pol)) in dds.iter().enumerate() {
+            let row = ddid as u64;
+            main.put_cell("TIME", row, &4.9e9f64).unwrap();
+            main.put_cell("DATA_DESC_ID", row, &(ddid as i32)).unwrap();
+
+            for c in ID_COLUMNS {
+                main.put_cell(c, row, &0i32).unwrap();
+            }
+
+            main.put_cell("ANTENNA2", row, &1i32).unwrap();
+            let shape = (2, pols[*pol].len());
+            let data = Array::from_shape_fn(shape, |(i, _)| Complex::new((2 * spw + i) as f32, 0.));
+            main.put_cell("DATA", row, &data).unwrap();
+            main.put_cell("FLAG", row, &Array::from_elem(shape, false))
+                .unwrap();
+        }
+
+        let mut pol_table = new_table(
+            &path.join("POLARIZATION"),
+            &[("NUM_CORR", GlueDataType::TpInt)],
+            &[("CORR_TYPE", GlueDataType::TpInt)],
+            pols.len(),
+        );
+
+        for (i, corrs) in pols.iter().enumerate() {
+            pol_table
+                .put_cell("NUM_CORR", i as u64, &(corrs.len() as i32))
+                .unwrap();
+            pol_table
+                .put_cell("CORR_TYPE", i as u64, &Array::from(corrs.clone()))
+                .unwrap();
+        }
+
+        let mut dd_table = new_table(
+            &path.join("DATA_DESCRIPTION"),
+            &[
+                ("FLAG_ROW", GlueDataType::TpBool),
+                ("POLARIZATION_ID", GlueDataType::TpInt),
+                ("SPECTRAL_WINDOW_ID", GlueDataType::TpInt),
+            ],
+            &[],
+            dds.len(),
+        );
+
+        for (ddid, (spw, pol)) in dds.iter().enumerate() {
+            let row = ddid as u64;
+            dd_table.put_cell("FLAG_ROW", row, &false).unwrap();
+            dd_table
+                .put_cell("POLARIZATION_ID", row, &(*pol as i32))
+                .unwrap();
+            dd_table
+                .put_cell("SPECTRAL_WINDOW_ID", row, &(*spw as i32))
+                .unwrap();
+        }
+
+        let mut spw_table = new_table(
+            &path.join("SPECTRAL_WINDOW"),
+            &[("NUM_CHAN", GlueDataType::TpInt)],
+            &[("CHAN_FREQ", GlueDataType::TpDouble)],
+            2,
+        );
+
+        for spw in 0..2 {
+            let freqs = Array::from(vec![1e9 + 2e6 * spw as f64, 1.001e9 + 2e6 * spw as f64]);
+            spw_table.put_cell("NUM_CHAN", spw, &2i32).unwrap();
+            spw_table.put_cell("CHAN_FREQ", spw, &freqs).unwrap();
+        }
+
+        let field_table = new_table(
+            &path.join("FIELD"),
+            &[("SOURCE_ID", GlueDataType::TpInt)],
+            &[],
+            1,
+        );
+
+        // SOURCE has a row for each source and spw; FEED has a row for each
+        // spw plus one that applies to all of them.
+
+        let mut src_table = new_table(
+            &path.join("SOURCE"),
+            &[
+                ("SOURCE_ID", GlueDataType::TpInt),
+                ("SPECTRAL_WINDOW_ID", GlueDataType::TpInt),
+            ],
+            &[],
+            4,
+        );
+
+        for row in 0..4 {
+            src_table
+                .put_cell("SOURCE_ID", row, &(row as i32 / 2))
+                .unwrap();
+            src_table
+                .put_cell("SPECTRAL_WINDOW_ID", row, &(row as i32 % 2))
+                .unwrap();
+        }
+
+        let mut feed_table = new_table(
+            &path.join("FEED"),
+            &[("SPECTRAL_WINDOW_ID", GlueDataType::TpInt)],
+            &[],
+            3,
+        );
+
+        for (row, spw) in [0, 1, -1].iter().enumerate() {
+            feed_table
+                .put_cell("SPECTRAL_WINDOW_ID", row as u64, spw)
+                .unwrap();
+        }
+
+        let cd_table = new_table(
+            &path.join("CALDEVICE"),
+            &[("SPECTRAL_WINDOW_ID", GlueDataType::TpInt)],
+            &[],
+            0,
+        );
+
+        main.put_table_keyword("POLARIZATION", pol_table).unwrap();
+        main.put_table_keyword("DATA_DESCRIPTION", dd_table)
+            .unwrap();
+        main.put_table_keyword("SPECTRAL_WINDOW", spw_table)
+            .unwrap();
+        main.put_table_keyword("FIELD", field_table).unwrap();
+        main.put_table_keyword("SOURCE", src_table).unwrap();
+        main.put_table_keyword("FEED", feed_table).unwrap();
+        main.put_table_keyword("CALDEVICE", cd_table).unwrap();
+    }
+
+    #[test]
+    fn glue_two_polarization_setups() {
+        let dir = std::env::temp_dir().join(format!("rxpackage-spwglue-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let inpath = dir.join("in.ms");
+        let outpath = dir.join("out.ms");
+        make_two_pol_ms(&inpath);
+
+        let matches = make_command()
+            .try_get_matches_from([
+                "spwglue".as_ref(),
+                "-w".as_ref(),
+                "0-1".as_ref(),
+                "-D".as_ref(),
+                outpath.as_os_str(),
+                inpath.as_os_str(),
+            ])
+            .unwrap();
+        assert_eq!(
+            do_cli(&matches, &mut NoopNotificationBackend::default()).unwrap(),
+            0
+        );
+
+        // One output DDID for each polarization setup of the glued window.
+
+        let mut dd_table =
+            Table::open(outpath.join("DATA_DESCRIPTION"), TableOpenMode::Read).unwrap();
+        assert_eq!(spw_ids(&outpath, "DATA_DESCRIPTION"), vec![0, 0]);
+        assert_eq!(
+            dd_table.get_col_as_vec::<i32>("POLARIZATION_ID").unwrap(),
+            vec![0, 1]
+        );
+
+        let mut pol_table = Table::open(outpath.join("POLARIZATION"), TableOpenMode::Read).unwrap();
+        assert_eq!(
+            pol_table.get_col_as_vec::<i32>("NUM_CORR").unwrap(),
+            vec![2, 4]
+        );
+
+        let mut main = Table::open(&outpath, TableOpenMode::Read).unwrap();
+        assert_eq!(
+            main.get_col_as_vec::<i32>("DATA_DESC_ID").unwrap(),
+            vec![0, 1]
+        );
+        assert_eq!(
+            main.get_cell_as_vec::<bool>("FLAG", 0).unwrap().len(),
+            4 * 2
+        );
+        assert_eq!(
+            main.get_cell_as_vec::<bool>("FLAG", 1).unwrap().len(),
+            4 * 4
+        );
+
+        // The spw-keyed tables refer to the single output window, however
+        // many rows they have for it.
+
+        assert_eq!(spw_ids(&outpath, "SOURCE"), vec![0, 0]);
+        assert_eq!(spw_ids(&outpath, "FEED"), vec![0, -1]);
+        assert!(spw_ids(&outpath, "CALDEVICE").is_empty());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}